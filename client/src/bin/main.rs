@@ -10,8 +10,17 @@ use core::net::Ipv4Addr;
 use core::str::FromStr;
 
 use blocking_network_stack::Stack;
+use client::codec::{Decoder, Encoding};
+use client::crc::Crc32;
 use client::dev_config::DevConfig;
-use client::epd13in3;
+use client::epd13in3::{self, EPD_HEIGHT, EPD_WIDTH};
+#[cfg(feature = "http")]
+use client::http;
+use client::protocol::{ACK_INTERVAL, FrameHeader};
+#[cfg(not(feature = "http"))]
+use client::protocol::{CHALLENGE_LEN, Hello, NO_CHALLENGE, WINDOW, challenge_response};
+#[cfg(feature = "http")]
+use client::protocol::{CHALLENGE_LEN, NO_CHALLENGE, challenge_response};
 use embedded_io::{Read, Write};
 use esp_hal::clock::CpuClock;
 use esp_hal::gpio::{Input, InputConfig, Level, Output, OutputConfig, Pull};
@@ -38,6 +47,31 @@ extern crate alloc;
 // For more information see: <https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/system/app_image_format.html#application-description>
 esp_bootloader_esp_idf::esp_app_desc!();
 
+/// Decoded bytes each panel expects, two pixels per byte over half the display.
+const PANEL_BYTES: usize = EPD_WIDTH * EPD_HEIGHT / 4;
+/// Transfers tried before giving up and keeping the current image.
+const MAX_ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+/// Name the server is reached by, what a reverse proxy routes on.
+#[cfg(all(feature = "http", feature = "tls"))]
+const HTTP_HOST: &str = env!("SERVER_NAME");
+#[cfg(all(feature = "http", not(feature = "tls")))]
+const HTTP_HOST: &str = concat!(env!("SERVER_ADDRESS"), ":", env!("SERVER_PORT"));
+
+/// Checksum and encoding of the image on the display, kept through deep sleep
+/// so the server can tell when it's still the current one. Zero after power on.
+#[cfg(feature = "http")]
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static mut SHOWN_IMAGE: u32 = 0;
+#[cfg(feature = "http")]
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static mut SHOWN_ENCODING: u8 = 0;
+/// Nonce of the server's last answer, the next request is signed with it.
+/// Zero when there is none.
+#[cfg(feature = "http")]
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static mut NONCE: [u8; CHALLENGE_LEN] = [0; CHALLENGE_LEN];
+
 #[main]
 fn main() -> ! {
     // generator version: 1.0.0
@@ -59,12 +93,16 @@ fn main() -> ! {
 
     let mut socket_set_entries: [SocketStorage; 3] = Default::default();
     let rng = esp_hal::rng::Rng::new();
+    // Wi-Fi alone already feeds the RNG, the ADC adds entropy for TLS keys
+    #[cfg(feature = "tls")]
+    let _trng_source = esp_hal::rng::TrngSource::new(peripherals.RNG, peripherals.ADC1);
 
     let (mut wifi_controller, interfaces) =
         esp_radio::wifi::new(&radio_init, peripherals.WIFI, Default::default())
             .expect("Failed to initialize Wi-Fi controller");
 
     let mut device = interfaces.sta;
+    let device_id = device.mac_address();
     let mut socket_set = SocketSet::new(&mut socket_set_entries[..]);
     let mut dhcp_socket = smoltcp::socket::dhcpv4::Socket::new();
     // we can set a hostname here (or add other DHCP options)
@@ -125,10 +163,11 @@ fn main() -> ! {
         smoltcp::wire::IpAddress::Ipv4(Ipv4Addr::from_str(env!("SERVER_ADDRESS")).unwrap());
     let server_port: u16 = env!("SERVER_PORT").parse().unwrap();
 
-    let response = b"Ok";
-    let number_of_pixels_in_bytes = (epd13in3::EPD_HEIGHT * epd13in3::EPD_WIDTH / 2) as usize;
-
     let mut socket = stack.get_socket(&mut rx_buffer, &mut tx_buffer);
+    let mut transfer = None;
+    #[cfg(feature = "tls")]
+    let mut tls_buffers = client::tls::Buffers::new();
+    let mut attempt = 1;
     loop {
         socket.work();
         info!(
@@ -147,61 +186,35 @@ fn main() -> ! {
         }
         log::info!("Socket connected to server.");
 
-        epd.init();
-
-        let mut buffer_read = 0;
-        let mut buff_reader = [0u8; 1024];
-        epd.select_left_panel();
-        loop {
-            match socket.read(&mut buff_reader) {
-                Ok(0) => {
-                    // EOF
-                    break;
-                }
-                Ok(n) => {
-                    if n + buffer_read < (number_of_pixels_in_bytes / 2)
-                        || buffer_read >= (number_of_pixels_in_bytes / 2)
-                    {
-                        epd.send_data_bytes(&buff_reader[..n]);
-                    } else if n + buffer_read == (number_of_pixels_in_bytes / 2) {
-                        log::info!("Finished reading left panel data.");
-                        epd.send_data_bytes(&buff_reader[..n]);
-                        epd.select_right_panel();
-                    } else {
-                        log::info!(
-                            "Finished reading left panel data. Starting to read right panel data."
-                        );
-                        epd.send_data_bytes(
-                            &buff_reader[..(number_of_pixels_in_bytes / 2 - buffer_read)],
-                        );
-                        epd.select_right_panel();
-                        epd.send_data_bytes(
-                            &buff_reader[(number_of_pixels_in_bytes / 2 - buffer_read)..n],
-                        );
-                    }
-                    // epd.send_data_bytes(&buff_reader[..n]);
-                    buffer_read += n;
-                    if buffer_read == number_of_pixels_in_bytes {
-                        break;
-                    }
-                }
-                Err(e) => {
-                    log::error!("Socket read error: {:?}", e);
-                    break;
-                }
+        #[cfg(feature = "tls")]
+        let result = esp_hal::rng::Trng::try_new()
+            .map_err(|_| "True random number generator isn't running")
+            .and_then(|trng| client::tls::connect(&mut socket, &mut tls_buffers, trng))
+            .and_then(|mut tls| next_frame(&mut tls, &mut epd, device_id, &mut transfer));
+        #[cfg(not(feature = "tls"))]
+        let result = next_frame(&mut socket, &mut epd, device_id, &mut transfer);
+
+        match result {
+            Ok(None) => log::info!("Server has no new image, keeping the current one"),
+            Ok(Some(written)) => {
+                log::info!(
+                    "Finished reading from socket. Total bytes written to the panels: {written}"
+                );
+                epd.turn_on_display();
             }
-
-            if let Err(e) = socket.write(response) {
-                log::error!("Socket write error: {:?}", e);
-                break;
-            }
-            if let Err(e) = socket.flush() {
-                log::error!("Socket flush error: {:?}", e);
-                break;
+            Err(e) if attempt < MAX_ATTEMPTS => {
+                log::error!("Failed to receive frame: {e}, retrying ({attempt}/{MAX_ATTEMPTS})");
+                attempt += 1;
+                socket.disconnect();
+                let retry_start = Instant::now();
+                while retry_start.elapsed() < RETRY_DELAY {
+                    socket.work();
+                }
+                continue;
             }
+            // Refreshing now would show a torn image, keep the current one instead
+            Err(e) => log::error!("Failed to receive frame: {e}, skipping this refresh"),
         }
-        log::info!("Finished reading from socket. Total bytes read: {buffer_read}");
-        epd.turn_on_display();
 
         let delay_start = Instant::now();
         socket.close();
@@ -212,3 +225,312 @@ fn main() -> ! {
 
     // for inspiration have a look at the examples at https://github.com/esp-rs/esp-hal/tree/esp-hal-v1.0.0/examples/src/bin
 }
+
+/// Progress of a frame, kept across connections so a dropped one can resume.
+struct Transfer {
+    header: FrameHeader,
+    decoder: Decoder,
+    /// Encoded bytes received over both panels.
+    received: usize,
+    panel_crc: Crc32,
+    panel_written: usize,
+    image_crc: Crc32,
+    written: usize,
+}
+
+impl Transfer {
+    fn new(header: FrameHeader) -> Self {
+        Transfer {
+            decoder: Decoder::new(header.encoding),
+            header,
+            received: 0,
+            panel_crc: Crc32::new(),
+            panel_written: 0,
+            image_crc: Crc32::new(),
+            written: 0,
+        }
+    }
+
+    /// Checks the panel that just ended and gets ready for the next one.
+    fn finish_panel(&mut self, expected_crc: u32) -> Result<(), &'static str> {
+        if !self.decoder.is_idle() || self.panel_written != PANEL_BYTES {
+            log::error!(
+                "Panel decoded to {} of {PANEL_BYTES} bytes",
+                self.panel_written
+            );
+            return Err("Panel data is incomplete");
+        }
+        if self.panel_crc.finish() != expected_crc {
+            return Err("Panel checksum mismatch");
+        }
+
+        // Each panel is encoded on its own
+        self.decoder.reset();
+        self.panel_crc = Crc32::new();
+        self.written += self.panel_written;
+        self.panel_written = 0;
+        Ok(())
+    }
+}
+
+/// Gets the next frame over the frame protocol.
+///
+/// Returns the bytes written to the panels, or `None` when the image on the
+/// display is still the current one, which the frame protocol never says.
+#[cfg(not(feature = "http"))]
+fn next_frame(
+    socket: &mut (impl Read + Write),
+    epd: &mut epd13in3::EPD13in3e,
+    device: [u8; 6],
+    transfer: &mut Option<Transfer>,
+) -> Result<Option<usize>, &'static str> {
+    receive_frame(socket, epd, device, transfer).map(Some)
+}
+
+/// Gets the next frame over HTTP, returning the same as over the frame protocol.
+#[cfg(feature = "http")]
+fn next_frame(
+    socket: &mut (impl Read + Write),
+    epd: &mut epd13in3::EPD13in3e,
+    device: [u8; 6],
+    transfer: &mut Option<Transfer>,
+) -> Result<Option<usize>, &'static str> {
+    fetch_frame(socket, epd, device, transfer)
+}
+
+/// Receives a frame from the server and streams it into the panels as it arrives.
+///
+/// A `transfer` left over from a dropped connection is resumed where it
+/// stopped, the panels keep what was already written to them. Fails unless
+/// both panels decoded to the expected size and checksums, in which case the
+/// panels hold an incomplete image and must not be refreshed. The transfer is
+/// kept when it failed because of the connection, so the next call resumes it.
+#[cfg(not(feature = "http"))]
+fn receive_frame(
+    socket: &mut (impl Read + Write),
+    epd: &mut epd13in3::EPD13in3e,
+    device: [u8; 6],
+    transfer: &mut Option<Transfer>,
+) -> Result<usize, &'static str> {
+    let (image, offset) = match transfer {
+        Some(transfer) => (transfer.header.image_crc, transfer.received as u32),
+        None => (0, 0),
+    };
+    let hello = Hello {
+        encodings: Encoding::Raw.mask() | Encoding::Lz.mask(),
+        device,
+        image,
+        offset,
+        window: WINDOW,
+    };
+    socket.write_all(&hello.to_bytes()).map_err(|e| {
+        log::error!("Socket write error: {:?}", e);
+        "Failed to send hello"
+    })?;
+    socket.flush().map_err(|e| {
+        log::error!("Socket flush error: {:?}", e);
+        "Failed to send hello"
+    })?;
+
+    let mut challenge = [0u8; CHALLENGE_LEN];
+    socket.read_exact(&mut challenge).map_err(|e| {
+        log::error!("Socket read error: {:?}", e);
+        "Failed to read challenge, is this frame allowed on the server?"
+    })?;
+    if challenge != NO_CHALLENGE {
+        let response = challenge_response(env!("DEVICE_KEY").as_bytes(), &challenge, &device);
+        socket.write_all(&response).map_err(|e| {
+            log::error!("Socket write error: {:?}", e);
+            "Failed to answer challenge"
+        })?;
+        socket.flush().map_err(|e| {
+            log::error!("Socket flush error: {:?}", e);
+            "Failed to answer challenge"
+        })?;
+    }
+
+    let mut header = [0u8; FrameHeader::LEN];
+    socket.read_exact(&mut header).map_err(|e| {
+        log::error!("Socket read error: {:?}", e);
+        "Failed to read frame header, is the device key right?"
+    })?;
+    let header = FrameHeader::parse(&header)?;
+
+    start_transfer(header, epd, transfer)?;
+    receive_panels(socket, epd, transfer, true)
+}
+
+/// Fetches the next frame with an HTTP request, streaming it into the panels
+/// like [`receive_frame`] does.
+///
+/// A `transfer` left over from a dropped connection is resumed with a range
+/// request. Returns `None` when the server answers that the image on the
+/// display is still the current one.
+#[cfg(feature = "http")]
+fn fetch_frame(
+    socket: &mut (impl Read + Write),
+    epd: &mut epd13in3::EPD13in3e,
+    device: [u8; 6],
+    transfer: &mut Option<Transfer>,
+) -> Result<Option<usize>, &'static str> {
+    // SAFETY: the firmware is single threaded and nothing else touches these
+    let (shown_image, shown_encoding, nonce) = unsafe { (SHOWN_IMAGE, SHOWN_ENCODING, NONCE) };
+    let key = env!("DEVICE_KEY");
+    let request = http::Request {
+        host: HTTP_HOST,
+        device,
+        signature: (!key.is_empty() && nonce != NO_CHALLENGE)
+            .then(|| challenge_response(key.as_bytes(), &nonce, &device)),
+        shown: Encoding::from_u8(shown_encoding)
+            .filter(|_| shown_image != 0)
+            .map(|encoding| (shown_image, encoding)),
+        resume: transfer
+            .as_ref()
+            .map(|transfer| (&transfer.header, transfer.received)),
+    };
+    socket.write_all(request.format().as_bytes()).map_err(|e| {
+        log::error!("Socket write error: {:?}", e);
+        "Failed to send HTTP request"
+    })?;
+    socket.flush().map_err(|e| {
+        log::error!("Socket flush error: {:?}", e);
+        "Failed to send HTTP request"
+    })?;
+
+    let mut next_nonce = None;
+    let response = http::read_response(socket, &mut next_nonce);
+    // SAFETY: as above. The nonce just sent is used up either way
+    unsafe {
+        NONCE = next_nonce.unwrap_or(NO_CHALLENGE);
+    }
+    let header = match response? {
+        http::Response::NotModified => {
+            *transfer = None;
+            return Ok(None);
+        }
+        http::Response::Frame(header) => header,
+    };
+    let image = (header.image_crc, header.encoding);
+    start_transfer(header, epd, transfer)?;
+    let written = receive_panels(socket, epd, transfer, false)?;
+
+    // SAFETY: as above
+    unsafe {
+        SHOWN_IMAGE = image.0;
+        SHOWN_ENCODING = image.1 as u8;
+    }
+    Ok(Some(written))
+}
+
+/// Starts writing the frame the header announces, or continues the
+/// `transfer` when the header resumes it.
+fn start_transfer(
+    header: FrameHeader,
+    epd: &mut epd13in3::EPD13in3e,
+    transfer: &mut Option<Transfer>,
+) -> Result<(), &'static str> {
+    let resumes = transfer.as_ref().is_some_and(|transfer| {
+        header.offset as usize == transfer.received && header.image_crc == transfer.header.image_crc
+    });
+    if header.offset == 0 {
+        log::info!(
+            "Receiving {:?} frame, panels of {} and {} bytes",
+            header.encoding,
+            header.left_len,
+            header.right_len
+        );
+        epd.init();
+        epd.select_left_panel();
+        *transfer = Some(Transfer::new(header));
+    } else if resumes {
+        log::info!("Resuming frame at byte {}", header.offset);
+    } else {
+        *transfer = None;
+        return Err("Server resumed a transfer we don't have");
+    }
+    Ok(())
+}
+
+/// Streams the rest of the `transfer` into the panels, acking what arrived
+/// when `ack` is set, and checks it once complete.
+fn receive_panels(
+    socket: &mut (impl Read + Write),
+    epd: &mut epd13in3::EPD13in3e,
+    transfer: &mut Option<Transfer>,
+    ack: bool,
+) -> Result<usize, &'static str> {
+    let state = transfer.as_mut().ok_or("No transfer to receive")?;
+
+    let left_len = state.header.left_len as usize;
+    let total = left_len + state.header.right_len as usize;
+    let mut buff_reader = [0u8; 1024];
+    let mut unacked = 0;
+    while state.received < total {
+        let panel_end = if state.received < left_len {
+            left_len
+        } else {
+            total
+        };
+        let len = (panel_end - state.received).min(buff_reader.len());
+        let n = socket.read(&mut buff_reader[..len]).map_err(|e| {
+            log::error!("Socket read error: {:?}", e);
+            "Failed to read panel data"
+        })?;
+        if n == 0 {
+            return Err("Connection closed in the middle of the frame");
+        }
+
+        let fed = state.decoder.feed(&buff_reader[..n], &mut |bytes: &[u8]| {
+            epd.send_data_bytes(bytes);
+            state.panel_crc.update(bytes);
+            state.image_crc.update(bytes);
+            state.panel_written += bytes.len();
+        });
+        if let Err(e) = fed {
+            *transfer = None;
+            return Err(e);
+        }
+        state.received += n;
+        unacked += n;
+
+        // Done before the ack, a resume must not find a panel half finished
+        if state.received == panel_end {
+            let expected_crc = if panel_end == left_len {
+                state.header.left_crc
+            } else {
+                state.header.right_crc
+            };
+            if let Err(e) = state.finish_panel(expected_crc) {
+                *transfer = None;
+                return Err(e);
+            }
+            if panel_end == left_len {
+                log::info!("Finished reading left panel data.");
+                epd.select_right_panel();
+            }
+        }
+
+        // The server needs the final ack to know the frame arrived
+        if ack && (unacked >= ACK_INTERVAL || state.received == total) {
+            unacked = 0;
+            socket
+                .write_all(&(state.received as u32).to_le_bytes())
+                .map_err(|e| {
+                    log::error!("Socket write error: {:?}", e);
+                    "Failed to acknowledge panel data"
+                })?;
+            socket.flush().map_err(|e| {
+                log::error!("Socket flush error: {:?}", e);
+                "Failed to acknowledge panel data"
+            })?;
+        }
+    }
+
+    let complete = state.image_crc.finish() == state.header.image_crc;
+    let written = state.written;
+    *transfer = None;
+    if !complete {
+        return Err("Image checksum mismatch");
+    }
+    Ok(written)
+}
@@ -0,0 +1,304 @@
+//! Streaming decoder for the compressed panel data the server sends.
+//!
+//! Tokens start with a control byte:
+//!
+//! - `0x00..=0x3F`: literal run, the next `c + 1` bytes are copied as is.
+//! - `0x40..=0x7F`: packed literal run of `(c & 0x3F) + 1` groups, each a
+//!   little endian `u16` holding six pixels as base 6 digits.
+//! - `0x80..=0xBF`: byte run, the next byte repeated `(c & 0x3F) + 3` times.
+//! - `0xC0..=0xFF`: back reference, `(c & 0x3F) + 4` bytes copied from the
+//!   little endian `u16` distance that follows.
+//!
+//! Input can be fed in pieces of any size, the decoder only keeps the last
+//! [`WINDOW`] bytes of output around for back references.
+
+use alloc::{boxed::Box, vec};
+
+/// How far back references may reach.
+pub const WINDOW: usize = 8192;
+
+/// The nibbles the panel uses for its six colours.
+const PIXELS: [u8; 6] = [0x0, 0x1, 0x2, 0x3, 0x5, 0x6];
+const GROUPS_LIMIT: u16 = 6 * 6 * 6 * 6 * 6 * 6;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Encoding {
+    Raw = 0,
+    Lz = 1,
+}
+
+impl Encoding {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Encoding::Raw),
+            1 => Some(Encoding::Lz),
+            _ => None,
+        }
+    }
+
+    /// Bit representing the encoding in the list of supported encodings.
+    pub fn mask(self) -> u8 {
+        1 << self as u8
+    }
+
+    /// Name of the encoding in HTTP headers.
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Raw => "raw",
+            Encoding::Lz => "lz",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "raw" => Some(Encoding::Raw),
+            "lz" => Some(Encoding::Lz),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+enum State {
+    Control,
+    Literals(usize),
+    Packed { groups: usize, low: Option<u8> },
+    Run(usize),
+    Reference { length: usize, low: Option<u8> },
+}
+
+pub struct Decoder {
+    encoding: Encoding,
+    state: State,
+    window: Box<[u8]>,
+    written: usize,
+    out: [u8; 64],
+    out_len: usize,
+}
+
+impl Decoder {
+    pub fn new(encoding: Encoding) -> Self {
+        Decoder {
+            encoding,
+            state: State::Control,
+            window: vec![0u8; WINDOW].into_boxed_slice(),
+            written: 0,
+            out: [0u8; 64],
+            out_len: 0,
+        }
+    }
+
+    /// Starts a new stream, forgetting all earlier output.
+    pub fn reset(&mut self) {
+        self.state = State::Control;
+        self.written = 0;
+        self.out_len = 0;
+    }
+
+    /// True when the input so far ended on a token boundary.
+    pub fn is_idle(&self) -> bool {
+        matches!(self.state, State::Control)
+    }
+
+    /// Decodes the input, handing the output to `sink` in small pieces.
+    pub fn feed(&mut self, input: &[u8], sink: &mut impl FnMut(&[u8])) -> Result<(), &'static str> {
+        if self.encoding == Encoding::Raw {
+            sink(input);
+            return Ok(());
+        }
+
+        for &byte in input {
+            self.state = match self.state {
+                State::Control => match byte {
+                    0x00..=0x3F => State::Literals(byte as usize + 1),
+                    0x40..=0x7F => State::Packed {
+                        groups: (byte & 0x3F) as usize + 1,
+                        low: None,
+                    },
+                    0x80..=0xBF => State::Run((byte & 0x3F) as usize + 3),
+                    0xC0..=0xFF => State::Reference {
+                        length: (byte & 0x3F) as usize + 4,
+                        low: None,
+                    },
+                },
+                State::Literals(remaining) => {
+                    self.push(byte, sink);
+                    if remaining == 1 {
+                        State::Control
+                    } else {
+                        State::Literals(remaining - 1)
+                    }
+                }
+                State::Packed { groups, low: None } => State::Packed {
+                    groups,
+                    low: Some(byte),
+                },
+                State::Packed {
+                    groups,
+                    low: Some(low),
+                } => {
+                    let mut value = u16::from_le_bytes([low, byte]);
+                    if value >= GROUPS_LIMIT {
+                        return Err("Packed group out of range");
+                    }
+                    for _ in 0..3 {
+                        let high = PIXELS[(value % 6) as usize];
+                        value /= 6;
+                        let low = PIXELS[(value % 6) as usize];
+                        value /= 6;
+                        self.push(high << 4 | low, sink);
+                    }
+                    if groups == 1 {
+                        State::Control
+                    } else {
+                        State::Packed {
+                            groups: groups - 1,
+                            low: None,
+                        }
+                    }
+                }
+                State::Run(length) => {
+                    for _ in 0..length {
+                        self.push(byte, sink);
+                    }
+                    State::Control
+                }
+                State::Reference { length, low: None } => State::Reference {
+                    length,
+                    low: Some(byte),
+                },
+                State::Reference {
+                    length,
+                    low: Some(low),
+                } => {
+                    let distance = u16::from_le_bytes([low, byte]) as usize;
+                    if distance == 0 || distance > self.written.min(WINDOW) {
+                        return Err("Back reference outside of the window");
+                    }
+                    for _ in 0..length {
+                        let byte = self.window[(self.written - distance) % WINDOW];
+                        self.push(byte, sink);
+                    }
+                    State::Control
+                }
+            };
+        }
+
+        self.flush(sink);
+        Ok(())
+    }
+
+    fn push(&mut self, byte: u8, sink: &mut impl FnMut(&[u8])) {
+        self.window[self.written % WINDOW] = byte;
+        self.written += 1;
+        self.out[self.out_len] = byte;
+        self.out_len += 1;
+        if self.out_len == self.out.len() {
+            self.flush(sink);
+        }
+    }
+
+    fn flush(&mut self, sink: &mut impl FnMut(&[u8])) {
+        if self.out_len > 0 {
+            sink(&self.out[..self.out_len]);
+            self.out_len = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    /// Output of the server's compressor for a sample with every kind of token,
+    /// including a back reference a whole window back.
+    const RAW: &[u8] = include_bytes!("../tests/fixtures/panel.raw");
+    const LZ: &[u8] = include_bytes!("../tests/fixtures/panel.lz");
+
+    /// Feeds the input in pieces of the given sizes, cycling through them.
+    fn decode(input: &[u8], sizes: &[usize]) -> Result<Vec<u8>, &'static str> {
+        let mut decoder = Decoder::new(Encoding::Lz);
+        let mut out = Vec::new();
+        let mut rest = input;
+        for size in sizes.iter().cycle() {
+            if rest.is_empty() {
+                break;
+            }
+            let (piece, next) = rest.split_at((*size).min(rest.len()));
+            decoder.feed(piece, &mut |bytes| out.extend_from_slice(bytes))?;
+            rest = next;
+        }
+        assert!(decoder.is_idle());
+        Ok(out)
+    }
+
+    #[test]
+    fn decodes_server_output_in_any_pieces() {
+        for sizes in [
+            &[LZ.len()][..],
+            &[1],
+            &[2],
+            &[3],
+            &[64],
+            &[1000],
+            &[1, 2, 3, 5, 8, 13, 21, 34, 55, 89],
+            &[4096, 1, 7],
+        ] {
+            assert_eq!(decode(LZ, sizes).unwrap(), RAW, "{sizes:?}");
+        }
+    }
+
+    #[test]
+    fn decodes_every_token() {
+        let input = [
+            0x01, 0xAA, 0xBB, // Two literals
+            0x40, 0x00, 0x00, // A group of black pixels
+            0x40, 0x3F, 0xB6, // The highest group, all green
+            0x80, 0x12, // Three of a byte
+            0xC1, 0x05, 0x00, // Five back from the end
+        ];
+        assert_eq!(
+            decode(&input, &[1]).unwrap(),
+            [
+                0xAA, 0xBB, 0x00, 0x00, 0x00, 0x66, 0x66, 0x66, 0x12, 0x12, 0x12, 0x66, 0x66, 0x12,
+                0x12, 0x12
+            ]
+        );
+    }
+
+    #[test]
+    fn reaches_back_exactly_one_window() {
+        // One literal, then runs for the rest of the window
+        let mut input = Vec::from([0x00, b'a']);
+        for _ in 0..(WINDOW - 1) / 66 {
+            input.extend_from_slice(&[0xBF, b'x']);
+        }
+        input.extend_from_slice(&[0x80 | ((WINDOW - 1) % 66 - 3) as u8, b'x']);
+
+        let mut reference = input.clone();
+        reference.extend_from_slice(&[0xC0, 0x00, 0x20]);
+        let out = decode(&reference, &[5]).unwrap();
+        assert_eq!(out.len(), WINDOW + 4);
+        assert_eq!(&out[WINDOW..], b"axxx");
+
+        // Still out of reach once more output was written
+        input.extend_from_slice(&[0x00, b'y', 0xC0, 0x01, 0x20]);
+        assert!(decode(&input, &[5]).is_err());
+    }
+
+    #[test]
+    fn rejects_references_before_the_start() {
+        assert!(decode(&[0x01, 1, 2, 0xC0, 0x03, 0x00], &[1]).is_err());
+        assert!(decode(&[0x01, 1, 2, 0xC0, 0x00, 0x00], &[1]).is_err());
+        assert!(decode(&[0xC0, 0x01, 0x00], &[1]).is_err());
+    }
+
+    #[test]
+    fn rejects_groups_out_of_range() {
+        assert!(decode(&[0x40, 0x40, 0xB6], &[1]).is_err());
+        assert!(decode(&[0x40, 0xFF, 0xFF], &[3]).is_err());
+    }
+}
@@ -0,0 +1,305 @@
+//! Fetching frames over HTTP/1.1, see the server's `http` module.
+
+use alloc::{format, string::String};
+use core::fmt::Write;
+
+use embedded_io::Read;
+
+use crate::codec::Encoding;
+use crate::protocol::{CHALLENGE_LEN, FrameHeader};
+
+/// Longest response head we accept, the server's are well below it.
+const MAX_HEAD: usize = 1024;
+
+/// Asks for the next image of `device`.
+pub struct Request<'a> {
+    pub host: &'a str,
+    pub device: [u8; 6],
+    /// `HMAC-SHA256(key, nonce || device)` over the nonce of the server's
+    /// last answer, for devices with a key.
+    pub signature: Option<[u8; 32]>,
+    /// Checksum and encoding of the image on the display, the server answers
+    /// 304 when it would send it again.
+    pub shown: Option<(u32, Encoding)>,
+    /// The header and received bytes of a download that broke off, to get
+    /// the rest of the same image.
+    pub resume: Option<(&'a FrameHeader, usize)>,
+}
+
+/// What the server answered, the socket is left at the start of the body.
+pub enum Response {
+    /// The image on the display is still the current one.
+    NotModified,
+    /// The panels follow, from `offset` on.
+    Frame(FrameHeader),
+}
+
+impl Request<'_> {
+    pub fn format(&self) -> String {
+        let [a, b, c, d, e, f] = self.device;
+        let mut request = format!(
+            "GET /frames/{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{f:02x}/next HTTP/1.1\r\n\
+             Host: {}\r\n\
+             Frame-Encodings: {}, {}\r\n\
+             Connection: close\r\n",
+            self.host,
+            Encoding::Raw.name(),
+            Encoding::Lz.name()
+        );
+        // Writing to a String can't fail
+        if let Some(signature) = self.signature {
+            request.push_str("Authorization: Frame ");
+            for byte in signature {
+                let _ = write!(request, "{byte:02x}");
+            }
+            request.push_str("\r\n");
+        }
+        if let Some((image, encoding)) = self.shown {
+            let _ = write!(request, "If-None-Match: {}\r\n", etag(image, encoding));
+        }
+        if let Some((header, received)) = self.resume {
+            let _ = write!(
+                request,
+                "Range: bytes={received}-\r\nIf-Range: {}\r\n",
+                etag(header.image_crc, header.encoding)
+            );
+        }
+        request.push_str("\r\n");
+        request
+    }
+}
+
+/// The server's ETag for an image in an encoding.
+pub fn etag(image: u32, encoding: Encoding) -> String {
+    format!("\"{image:08x}-{}\"", encoding.name())
+}
+
+/// Reads the status line and headers of the response, and the nonce to sign
+/// the next request with when the server sent one.
+pub fn read_response(
+    socket: &mut impl Read,
+    nonce: &mut Option<[u8; CHALLENGE_LEN]>,
+) -> Result<Response, &'static str> {
+    let mut head = [0u8; MAX_HEAD];
+    let mut len = 0;
+    // Byte by byte, anything past the head belongs to the panels
+    while !head[..len].ends_with(b"\r\n\r\n") {
+        if len == head.len() {
+            return Err("HTTP response head is too long");
+        }
+        socket.read_exact(&mut head[len..len + 1]).map_err(|e| {
+            log::error!("Socket read error: {:?}", e);
+            "Failed to read HTTP response"
+        })?;
+        len += 1;
+    }
+    let head = core::str::from_utf8(&head[..len]).map_err(|_| "HTTP response isn't text")?;
+    parse_response(head, nonce)
+}
+
+fn parse_response(
+    head: &str,
+    nonce: &mut Option<[u8; CHALLENGE_LEN]>,
+) -> Result<Response, &'static str> {
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .ok_or("Malformed HTTP status line")?;
+
+    let mut encoding = None;
+    let mut panels = None;
+    let mut checksums = None;
+    let mut offset = None;
+    let mut chunked = false;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("frame-encoding") {
+            encoding = Encoding::from_name(value);
+        } else if name.eq_ignore_ascii_case("frame-panels") {
+            panels = parse_fields::<2>(value, 10);
+        } else if name.eq_ignore_ascii_case("frame-checksums") {
+            checksums = parse_fields::<3>(value, 16);
+        } else if name.eq_ignore_ascii_case("content-range") {
+            offset = value
+                .strip_prefix("bytes ")
+                .and_then(|range| range.split_once('-'))
+                .and_then(|(start, _)| start.parse().ok());
+        } else if name.eq_ignore_ascii_case("frame-nonce") {
+            *nonce = parse_hex(value);
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            chunked = true;
+        }
+    }
+
+    match status {
+        "200" | "206" => {}
+        "304" => return Ok(Response::NotModified),
+        // Also on the first request after power on, there's no nonce yet
+        "401" => return Err("Server refused the signature, retrying with its new nonce"),
+        "403" => return Err("Server doesn't allow this frame"),
+        _ => {
+            log::error!("HTTP status {status}");
+            return Err("Unexpected HTTP status");
+        }
+    }
+
+    if chunked {
+        return Err("Chunked HTTP responses aren't supported");
+    }
+    let [left_len, right_len] = panels.ok_or("Missing or malformed Frame-Panels")?;
+    let [left_crc, right_crc, image_crc] =
+        checksums.ok_or("Missing or malformed Frame-Checksums")?;
+    let offset = match status {
+        "206" => offset.ok_or("Missing or malformed Content-Range")?,
+        _ => 0,
+    };
+    Ok(Response::Frame(FrameHeader {
+        encoding: encoding.ok_or("Missing or unknown Frame-Encoding")?,
+        left_len,
+        right_len,
+        left_crc,
+        right_crc,
+        image_crc,
+        offset,
+    }))
+}
+
+fn parse_hex<const N: usize>(text: &str) -> Option<[u8; N]> {
+    if text.len() != 2 * N {
+        return None;
+    }
+    let mut bytes = [0u8; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(text.get(2 * i..2 * i + 2)?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+/// Parses exactly `N` numbers separated by spaces.
+fn parse_fields<const N: usize>(value: &str, radix: u32) -> Option<[u32; N]> {
+    let mut fields = [0; N];
+    let mut parts = value.split_ascii_whitespace();
+    for field in &mut fields {
+        *field = u32::from_str_radix(parts.next()?, radix).ok()?;
+    }
+    parts.next().is_none().then_some(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+
+    const FRAME: &str = "Frame-Encoding: lz\r\n\
+                         Frame-Panels: 3000 2000\r\n\
+                         Frame-Checksums: 0000000a 0000000b 0000000c\r\n";
+
+    fn head(status: &str, headers: &str) -> String {
+        format!("HTTP/1.1 {status}\r\n{headers}Connection: close\r\n\r\n")
+    }
+
+    fn frame(head: &str) -> Result<FrameHeader, &'static str> {
+        match parse_response(head, &mut None)? {
+            Response::Frame(header) => Ok(header),
+            Response::NotModified => Err("not modified"),
+        }
+    }
+
+    #[test]
+    fn reads_whole_and_partial_frames() {
+        let header = frame(&head("200 OK", FRAME)).unwrap();
+        assert_eq!(header.encoding, Encoding::Lz);
+        assert_eq!((header.left_len, header.right_len), (3000, 2000));
+        assert_eq!(
+            (header.left_crc, header.right_crc, header.image_crc),
+            (0xa, 0xb, 0xc)
+        );
+        assert_eq!(header.offset, 0);
+
+        let range = FRAME.to_string() + "Content-Range: bytes 1200-4999/5000\r\n";
+        let header = frame(&head("206 Partial Content", &range)).unwrap();
+        assert_eq!(header.offset, 1200);
+        // A whole frame starts at the beginning whatever the range says
+        assert_eq!(frame(&head("200 OK", &range)).unwrap().offset, 0);
+
+        assert!(frame(&head("206 Partial Content", FRAME)).is_err());
+        let range = FRAME.to_string() + "Content-Range: 1200-4999/5000\r\n";
+        assert!(frame(&head("206 Partial Content", &range)).is_err());
+
+        assert!(matches!(
+            parse_response(&head("304 Not Modified", ""), &mut None),
+            Ok(Response::NotModified)
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_frame_headers() {
+        let checksums = "Frame-Encoding: raw\r\nFrame-Checksums: a b c\r\n";
+        let panels = "Frame-Encoding: raw\r\nFrame-Panels: 3000 2000\r\n";
+        for headers in [
+            format!("{checksums}Frame-Panels: 3000\r\n"),
+            format!("{checksums}Frame-Panels: 3000 2000 1000\r\n"),
+            format!("{checksums}Frame-Panels: 3000 -2000\r\n"),
+            format!("{checksums}Frame-Panels: 0xbb8 2000\r\n"),
+            format!("{checksums}Frame-Panels: 3000 4294967296\r\n"),
+            checksums.to_string(),
+            format!("{panels}Frame-Checksums: a b\r\n"),
+            format!("{panels}Frame-Checksums: a b c d\r\n"),
+            format!("{panels}Frame-Checksums: a b g\r\n"),
+            format!("{panels}Frame-Checksums: a b 100000000\r\n"),
+            panels.to_string(),
+            FRAME.replace("lz", "zip"),
+        ] {
+            assert!(frame(&head("200 OK", &headers)).is_err(), "{headers}");
+        }
+    }
+
+    #[test]
+    fn takes_the_nonce_from_a_refusal() {
+        let mut nonce = None;
+        let refusal = head(
+            "401 Unauthorized",
+            "Frame-Nonce: 000102030405060708090a0b0c0d0e0f\r\n",
+        );
+        assert!(parse_response(&refusal, &mut nonce).is_err());
+        assert_eq!(nonce, Some(core::array::from_fn(|i| i as u8)));
+
+        // A malformed nonce isn't signed with
+        let refusal = head("401 Unauthorized", "Frame-Nonce: 0001\r\n");
+        assert!(parse_response(&refusal, &mut nonce).is_err());
+        assert_eq!(nonce, None);
+    }
+
+    #[test]
+    fn ignores_the_case_of_header_names() {
+        let headers = "FRAME-ENCODING: lz\r\n\
+                       frame-panels: 3000 2000\r\n\
+                       Frame-checksums: a b c\r\n\
+                       content-RANGE: bytes 10-4999/5000\r\n\
+                       frame-NONCE: 000102030405060708090a0b0c0d0e0f\r\n";
+        let mut nonce = None;
+        let Ok(Response::Frame(header)) =
+            parse_response(&head("206 Partial Content", headers), &mut nonce)
+        else {
+            panic!("no frame");
+        };
+        assert_eq!(
+            (header.left_len, header.image_crc, header.offset),
+            (3000, 0xc, 10)
+        );
+        assert!(nonce.is_some());
+    }
+
+    #[test]
+    fn refuses_chunked_bodies() {
+        let headers = FRAME.to_string() + "Transfer-Encoding: chunked\r\n";
+        assert!(frame(&head("200 OK", &headers)).is_err());
+        let headers = FRAME.to_string() + "transfer-encoding: gzip, chunked\r\n";
+        assert!(frame(&head("200 OK", &headers)).is_err());
+    }
+}
@@ -1,5 +1,22 @@
-#![no_std]
+//! Firmware for the frame. The hardware modules only build for the ESP32, the
+//! rest also builds on the host, where its tests run with
+//! `cargo +stable test --lib --features http --target x86_64-unknown-linux-gnu`
+//! (or your host's target).
 
+#![cfg_attr(not(test), no_std)]
+
+extern crate alloc;
+
+pub mod codec;
+pub mod crc;
+#[cfg(target_arch = "xtensa")]
 pub mod dev_config;
+#[cfg(target_arch = "xtensa")]
 pub mod epd13in3;
+#[cfg(feature = "http")]
+pub mod http;
+#[cfg(target_arch = "xtensa")]
 pub mod network;
+pub mod protocol;
+#[cfg(feature = "tls")]
+pub mod tls;
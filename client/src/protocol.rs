@@ -0,0 +1,91 @@
+//! Handshake with the photo server, see the server's `protocol` module.
+
+use crate::codec::Encoding;
+
+pub const MAGIC: [u8; 3] = *b"SPF";
+pub const VERSION: u8 = 6;
+/// Random bytes the server answers the hello with.
+pub const CHALLENGE_LEN: usize = 16;
+/// Sent instead of a challenge when the server has no key for us.
+pub const NO_CHALLENGE: [u8; CHALLENGE_LEN] = [0; CHALLENGE_LEN];
+/// Panel bytes the server may send ahead of our acks.
+pub const WINDOW: u32 = 8 * 1024;
+/// Panel bytes received between acks, well below the window so it never stalls.
+pub const ACK_INTERVAL: usize = 2 * 1024;
+
+/// Opens the connection, telling the server which encodings we can decode,
+/// who we are, with a non zero `offset` which image to resume from where, and
+/// how far ahead of our acks it may send.
+pub struct Hello {
+    pub encodings: u8,
+    pub device: [u8; 6],
+    pub image: u32,
+    pub offset: u32,
+    pub window: u32,
+}
+
+impl Hello {
+    pub const LEN: usize = 23;
+
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let mut bytes = [0u8; Self::LEN];
+        bytes[..3].copy_from_slice(&MAGIC);
+        bytes[3] = VERSION;
+        bytes[4] = self.encodings;
+        bytes[5..11].copy_from_slice(&self.device);
+        bytes[11..15].copy_from_slice(&self.image.to_le_bytes());
+        bytes[15..19].copy_from_slice(&self.offset.to_le_bytes());
+        bytes[19..23].copy_from_slice(&self.window.to_le_bytes());
+        bytes
+    }
+}
+
+/// Proves we know the pre-shared key, `HMAC-SHA256(key, challenge || device)`.
+pub fn challenge_response(
+    key: &[u8],
+    challenge: &[u8; CHALLENGE_LEN],
+    device: &[u8; 6],
+) -> [u8; 32] {
+    let mut mac = hmac_sha256::HMAC::new(key);
+    mac.update(challenge);
+    mac.update(device);
+    mac.finalize()
+}
+
+/// Sent by the server ahead of the panels, with their encoded lengths, the
+/// CRC-32 of the decoded left panel, right panel and whole image, and the
+/// offset into the panels the data that follows starts at.
+pub struct FrameHeader {
+    pub encoding: Encoding,
+    pub left_len: u32,
+    pub right_len: u32,
+    pub left_crc: u32,
+    pub right_crc: u32,
+    pub image_crc: u32,
+    pub offset: u32,
+}
+
+impl FrameHeader {
+    pub const LEN: usize = 29;
+
+    pub fn parse(bytes: &[u8; Self::LEN]) -> Result<Self, &'static str> {
+        if bytes[..3] != MAGIC {
+            return Err("Frame header doesn't start with the protocol magic");
+        }
+        if bytes[3] != VERSION {
+            return Err("Unsupported protocol version");
+        }
+        let field = |at: usize| {
+            u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+        };
+        Ok(FrameHeader {
+            encoding: Encoding::from_u8(bytes[4]).ok_or("Unknown encoding")?,
+            left_len: field(5),
+            right_len: field(9),
+            left_crc: field(13),
+            right_crc: field(17),
+            image_crc: field(21),
+            offset: field(25),
+        })
+    }
+}
@@ -0,0 +1,90 @@
+//! TLS to the server, trusting only the certificate the firmware is built with.
+
+use alloc::{boxed::Box, vec};
+
+use embedded_io::{Read, Write};
+use embedded_tls::NoClock;
+use embedded_tls::blocking::{
+    Aes128GcmSha256, Certificate, CryptoProvider, TlsConfig, TlsConnection, TlsContext, TlsError,
+    TlsVerifier,
+};
+use embedded_tls::webpki::CertVerifier;
+use rand_core::{CryptoRng, CryptoRngCore, RngCore};
+
+/// The server's certificate in DER, see `SERVER_CERT` in `.cargo/config.toml`.
+const SERVER_CERT: &[u8] = include_bytes!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/",
+    env!("SERVER_CERT")
+));
+/// The name the certificate was issued for.
+const SERVER_NAME: &str = env!("SERVER_NAME");
+/// A whole TLS record, the server doesn't negotiate smaller ones.
+const READ_BUFFER: usize = 16_640;
+/// We only ever send the handshake, the hello and acks.
+const WRITE_BUFFER: usize = 4096;
+const MAX_CERT_SIZE: usize = 2048;
+
+pub type Connection<'a, S> = TlsConnection<'a, S, Aes128GcmSha256>;
+
+/// Record buffers, kept on the heap and reused for every connection.
+pub struct Buffers {
+    read: Box<[u8]>,
+    write: Box<[u8]>,
+}
+
+impl Buffers {
+    pub fn new() -> Self {
+        Buffers {
+            read: vec![0u8; READ_BUFFER].into_boxed_slice(),
+            write: vec![0u8; WRITE_BUFFER].into_boxed_slice(),
+        }
+    }
+}
+
+impl Default for Buffers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Verifies the server against its certificate, used as the only trusted CA.
+struct PinnedProvider<R> {
+    rng: R,
+    verifier: CertVerifier<Aes128GcmSha256, NoClock, MAX_CERT_SIZE>,
+}
+
+impl<R: CryptoRng + RngCore> CryptoProvider for PinnedProvider<R> {
+    type CipherSuite = Aes128GcmSha256;
+    type Signature = &'static [u8];
+
+    fn rng(&mut self) -> impl CryptoRngCore {
+        &mut self.rng
+    }
+
+    fn verifier(&mut self) -> Result<&mut impl TlsVerifier<Self::CipherSuite>, TlsError> {
+        Ok(&mut self.verifier)
+    }
+}
+
+/// Runs the TLS handshake over an open socket.
+pub fn connect<'a, S: Read + Write>(
+    socket: S,
+    buffers: &'a mut Buffers,
+    rng: impl CryptoRng + RngCore,
+) -> Result<Connection<'a, S>, &'static str> {
+    let config = TlsConfig::new()
+        .with_server_name(SERVER_NAME)
+        .with_ca(Certificate::X509(SERVER_CERT));
+    let provider = PinnedProvider {
+        rng,
+        verifier: CertVerifier::new(),
+    };
+
+    let mut tls = TlsConnection::new(socket, &mut buffers.read, &mut buffers.write);
+    tls.open(TlsContext::new(&config, provider)).map_err(|e| {
+        log::error!("TLS error: {:?}", e);
+        "TLS handshake failed"
+    })?;
+    Ok(tls)
+}
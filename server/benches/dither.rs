@@ -0,0 +1,66 @@
+//! Time it takes to dither a full canvas to the panel's palette.
+//!
+//! Each palette is dithered with its colour lookup table and, as the baseline,
+//! with a search of the palette for every pixel. The first run with the table
+//! includes building it, the following ones reuse it. Run with
+//! `cargo bench --bench dither`, optionally followed by `-- <runs>`.
+
+use std::time::{Duration, Instant};
+
+use image::{Rgb, RgbImage};
+use server::{
+    config::{Dither, Palette},
+    image_ops::{dither_image, dither_image_exact},
+};
+
+type Dithering = fn(&mut RgbImage, &Palette, Dither);
+
+fn main() {
+    let runs = std::env::args()
+        .skip(1)
+        .find_map(|arg| arg.parse().ok())
+        .unwrap_or(5usize)
+        .max(2);
+
+    // Smooth gradients over every hue, the worst case for the lookup table
+    let canvas = RgbImage::from_fn(1600, 1200, |x, y| {
+        Rgb([
+            (x * 255 / 1599) as u8,
+            (y * 255 / 1199) as u8,
+            ((x + y) % 256) as u8,
+        ])
+    });
+    let muted = Palette {
+        black: [25, 30, 35],
+        white: [200, 200, 190],
+        yellow: [210, 190, 30],
+        red: [150, 40, 40],
+        blue: [40, 60, 120],
+        green: [50, 90, 60],
+    };
+
+    println!("Dithering a 1600x1200 canvas, {runs} runs");
+    for (name, palette) in [("default", Palette::default()), ("muted", muted)] {
+        for dither in [Dither::FloydSteinberg, Dither::None] {
+            let ways: [(&str, Dithering); 2] =
+                [("table", dither_image), ("search", dither_image_exact)];
+            for (way, dither_image) in ways {
+                let times: Vec<Duration> = (0..runs)
+                    .map(|_| {
+                        let mut img = canvas.clone();
+                        let start = Instant::now();
+                        dither_image(&mut img, &palette, dither);
+                        start.elapsed()
+                    })
+                    .collect();
+                let rest = times[1..].iter().sum::<Duration>() / (runs - 1) as u32;
+                println!(
+                    "{name:<8} {:<15} {way:<7} first {:>8.1} ms  then {:>8.1} ms",
+                    format!("{dither:?}"),
+                    times[0].as_secs_f64() * 1000.0,
+                    rest.as_secs_f64() * 1000.0
+                );
+            }
+        }
+    }
+}
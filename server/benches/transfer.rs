@@ -0,0 +1,128 @@
+//! Throughput of the panel transfer against a simulated frame on localhost.
+//!
+//! Every ack the frame sends is held back for the given round trip time, so
+//! the stop-and-wait transfer old frames use can be compared with the windowed
+//! one under latency. Run with `cargo bench --bench transfer`, optionally
+//! followed by `-- <kilobytes> <round trip ms>...`.
+
+use std::time::{Duration, Instant};
+
+use server::transfer;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+
+/// What the frame grants and how often it acks, as in the client firmware.
+const WINDOW: usize = 8 * 1024;
+const ACK_INTERVAL: usize = 2 * 1024;
+
+#[derive(Clone, Copy, Debug)]
+enum Mode {
+    StopAndWait,
+    Windowed,
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<usize> = std::env::args()
+        .skip(1)
+        .filter_map(|arg| arg.parse().ok())
+        .collect();
+    let kilobytes = args.first().copied().unwrap_or(256);
+    let round_trips = match args.get(1..) {
+        Some(rtts) if !rtts.is_empty() => rtts.to_vec(),
+        _ => vec![0, 1, 5, 20],
+    };
+
+    let data: Vec<u8> = (0..kilobytes * 1024)
+        .map(|i| (i * 31 % 251) as u8)
+        .collect();
+    println!("Sending {kilobytes} KiB, window {WINDOW} bytes, ack every {ACK_INTERVAL} bytes");
+    for rtt in round_trips {
+        for mode in [Mode::StopAndWait, Mode::Windowed] {
+            let elapsed = run(mode, &data, Duration::from_millis(rtt as u64)).await;
+            let throughput = data.len() as f64 / 1024.0 / elapsed.as_secs_f64();
+            println!(
+                "rtt {rtt:>3} ms  {:<12} {:>8.3} s  {throughput:>10.1} KiB/s",
+                format!("{mode:?}"),
+                elapsed.as_secs_f64()
+            );
+        }
+    }
+}
+
+async fn run(mode: Mode, data: &[u8], rtt: Duration) -> Duration {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let client = tokio::spawn(simulated_frame(
+        TcpStream::connect(address),
+        mode,
+        data.len(),
+        rtt,
+    ));
+
+    let (mut socket, _) = listener.accept().await.unwrap();
+    socket.set_nodelay(true).unwrap();
+    let start = Instant::now();
+    match mode {
+        Mode::StopAndWait => transfer::send_buffer(&mut socket, data).await.unwrap(),
+        Mode::Windowed => transfer::send_windowed(&mut socket, &[data], 0, WINDOW)
+            .await
+            .unwrap(),
+    }
+    let elapsed = start.elapsed();
+
+    assert_eq!(client.await.unwrap(), data.len());
+    elapsed
+}
+
+/// Reads everything the server sends, acking the way the given mode expects.
+async fn simulated_frame(
+    connect: impl Future<Output = std::io::Result<TcpStream>>,
+    mode: Mode,
+    len: usize,
+    rtt: Duration,
+) -> usize {
+    let socket = connect.await.unwrap();
+    socket.set_nodelay(true).unwrap();
+    let (mut reader, mut writer) = socket.into_split();
+
+    // Acks go through a queue that delivers each one a round trip later
+    let (acks, mut queue) = mpsc::unbounded_channel::<(Instant, Vec<u8>)>();
+    let delayed = tokio::spawn(async move {
+        while let Some((due, ack)) = queue.recv().await {
+            tokio::time::sleep_until(due.into()).await;
+            if writer.write_all(&ack).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut buffer = [0u8; 1024];
+    let mut received = 0;
+    let mut unacked = 0;
+    while received < len {
+        let n = reader.read(&mut buffer).await.unwrap();
+        assert!(n > 0, "server closed the connection early");
+        received += n;
+        unacked += n;
+
+        let ack = match mode {
+            Mode::StopAndWait => Some(b"Ok".to_vec()),
+            Mode::Windowed if unacked >= ACK_INTERVAL || received == len => {
+                unacked = 0;
+                Some((received as u32).to_le_bytes().to_vec())
+            }
+            Mode::Windowed => None,
+        };
+        if let Some(ack) = ack {
+            acks.send((Instant::now() + rtt, ack)).unwrap();
+        }
+    }
+
+    drop(acks);
+    delayed.await.unwrap();
+    received
+}
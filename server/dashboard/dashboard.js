@@ -0,0 +1,552 @@
+// The dashboard, talking to the admin API with the token kept in localStorage.
+"use strict";
+
+const PAGE_SIZE = 24;
+const THUMBNAIL_WIDTH = 320;
+const PREVIEW_WIDTH = 800;
+// Wait after the last change before processing the preview again.
+const PREVIEW_DELAY_MS = 600;
+
+const TONE_OPS = {
+  auto_levels: { label: "Auto levels", fields: { clip: ["Clip", 0.005, 0.001] } },
+  gamma: { label: "Brightness (gamma)", fields: { value: ["Gamma", 1.2, 0.05] } },
+  contrast: { label: "Contrast", fields: { amount: ["Amount", 1.1, 0.05] } },
+  saturation: { label: "Saturation", fields: { amount: ["Amount", 1.3, 0.05] } },
+  shadow_lift: { label: "Lift shadows", fields: { amount: ["Amount", 0.15, 0.05] } },
+  sharpen: {
+    label: "Sharpen",
+    fields: { sigma: ["Radius", 1.0, 0.1], threshold: ["Threshold", 2, 1] },
+  },
+  clahe: {
+    label: "Local contrast",
+    fields: { tiles: ["Tiles", 8, 1], clip_limit: ["Limit", 2.0, 0.1] },
+  },
+};
+
+const state = {
+  token: localStorage.getItem("token") || "",
+  devices: [],
+  images: [],
+  shown: 0,
+  processing: null,
+  previewTimer: null,
+};
+
+const $ = (selector) => document.querySelector(selector);
+
+// Creates an element with attributes, event handlers (`on...`) and children.
+function el(tag, attributes = {}, ...children) {
+  const element = document.createElement(tag);
+  for (const [name, value] of Object.entries(attributes)) {
+    if (name.startsWith("on")) {
+      element.addEventListener(name.slice(2), value);
+    } else if (value !== false && value !== null && value !== undefined) {
+      element.setAttribute(name, value === true ? "" : value);
+    }
+  }
+  element.append(...children.filter((child) => child !== null));
+  return element;
+}
+
+async function api(method, path, body) {
+  const options = { method, headers: { Authorization: `Bearer ${state.token}` } };
+  if (body instanceof FormData) {
+    options.body = body;
+  } else if (body !== undefined) {
+    options.headers["Content-Type"] = "application/json";
+    options.body = JSON.stringify(body);
+  }
+  const response = await fetch(path, options);
+  if (response.status === 401) {
+    signOut();
+    throw new Error("The admin token was refused");
+  }
+  if (!response.ok) {
+    throw new Error((await response.text()).trim() || response.statusText);
+  }
+  return response;
+}
+
+async function json(path) {
+  return (await api("GET", path)).json();
+}
+
+function show(message, error = false) {
+  const element = $("#message");
+  element.textContent = message;
+  element.classList.toggle("error", error);
+  element.hidden = false;
+  clearTimeout(show.timer);
+  show.timer = setTimeout(() => (element.hidden = true), 5000);
+}
+
+// Runs an action, reporting how it went.
+async function act(action, done) {
+  try {
+    await action();
+    if (done) show(done);
+  } catch (e) {
+    show(e.message, true);
+  }
+}
+
+// Loads a preview into the image, which can't send the token itself.
+async function loadImage(img, path, options = {}) {
+  const response = await api(options.body ? "POST" : "GET", path, options.body);
+  const url = URL.createObjectURL(await response.blob());
+  if (img.src.startsWith("blob:")) URL.revokeObjectURL(img.src);
+  img.src = url;
+}
+
+function thumbnail(path) {
+  const img = el("img", { alt: "" });
+  loadImage(img, `${path}?inks=true&width=${THUMBNAIL_WIDTH}`).catch(() => {
+    img.replaceWith(el("div", { class: "placeholder" }, "No preview"));
+  });
+  return img;
+}
+
+function ago(seconds) {
+  if (seconds === null) return "never";
+  const minutes = Math.round((Date.now() / 1000 - seconds) / 60);
+  if (minutes < 1) return "just now";
+  if (minutes < 60) return `${minutes} min ago`;
+  const hours = Math.round(minutes / 60);
+  if (hours < 48) return `${hours} h ago`;
+  return new Date(seconds * 1000).toLocaleString();
+}
+
+// Frames
+
+async function loadFrames() {
+  state.devices = await json("/api/devices");
+  const target = $("#upload").device;
+  target.replaceChildren(
+    el("option", { value: "" }, "no frame in particular"),
+    ...state.devices.map((device) => el("option", { value: device.device }, device.device)),
+  );
+  const cards = $("#frames .cards");
+  cards.replaceChildren();
+  if (state.devices.length === 0) {
+    cards.append(el("p", {}, "No frame has asked for a photo yet."));
+  }
+  for (const device of state.devices) {
+    const status = device.image === null
+      ? "Nothing sent yet"
+      : `${device.image}${device.delivered ? "" : " (still downloading)"}`;
+    cards.append(
+      el(
+        "article",
+        { class: "card" },
+        device.image === null
+          ? el("div", { class: "placeholder" }, "No photo yet")
+          : thumbnail(`/api/devices/${device.device}/preview`),
+        el("h2", {}, device.device),
+        el(
+          "dl",
+          {},
+          el("dt", {}, "Last check-in"),
+          el("dd", {}, ago(device.last_seen)),
+          el("dt", {}, "Address"),
+          el("dd", {}, device.address ?? "unknown"),
+          el("dt", {}, "Showing"),
+          el("dd", {}, status),
+          el("dt", {}, "Next"),
+          el(
+            "dd",
+            {},
+            device.next ?? "a random photo",
+            device.next === null
+              ? null
+              : el(
+                  "button",
+                  {
+                    class: "small",
+                    onclick: () =>
+                      act(async () => {
+                        await api("DELETE", `/api/devices/${device.device}/next`);
+                        await loadFrames();
+                      }, "Back to random photos"),
+                  },
+                  "Cancel",
+                ),
+          ),
+        ),
+      ),
+    );
+  }
+}
+
+// Photos
+
+async function loadPhotos() {
+  await loadUploads();
+  state.images = await json("/api/images");
+  state.shown = 0;
+  $("#photos .cards").replaceChildren();
+  showMorePhotos();
+  fillSamples();
+}
+
+function showMorePhotos() {
+  const cards = $("#photos .cards");
+  const page = state.images.slice(state.shown, state.shown + PAGE_SIZE);
+  for (const image of page) {
+    cards.append(photoCard(image));
+  }
+  state.shown += page.length;
+  $("#more").hidden = state.shown >= state.images.length;
+  if (state.images.length === 0) {
+    cards.append(el("p", {}, "No photos yet, they show up while the albums are processed."));
+  }
+}
+
+async function loadUploads() {
+  const uploads = await json("/api/uploads");
+  const list = $("#uploads");
+  list.replaceChildren();
+  for (const upload of uploads) {
+    const limits = [];
+    if (upload.expires_at !== null) {
+      limits.push(`until ${new Date(upload.expires_at * 1000).toLocaleString()}`);
+    }
+    if (upload.displays_left !== null) limits.push(`${upload.displays_left} more times`);
+    list.append(
+      el(
+        "li",
+        {},
+        `${upload.name || upload.id}, shown ${limits.join(" or ") || "until it's removed"}`,
+        el(
+          "button",
+          {
+            class: "small",
+            onclick: () =>
+              act(async () => {
+                await api("DELETE", `/api/uploads/${upload.id}`);
+                await loadUploads();
+              }, "Removed"),
+          },
+          "Remove",
+        ),
+      ),
+    );
+  }
+}
+
+function photoCard(image) {
+  const target = el(
+    "select",
+    {},
+    ...state.devices.map((device) => el("option", { value: device.device }, device.device)),
+  );
+  const card = el(
+    "article",
+    { class: "card" },
+    thumbnail(`/api/images/${encodeURIComponent(image.id)}/preview`),
+    el("h2", { title: image.id }, image.template ? `Collage: ${image.template}` : "Photo"),
+    state.devices.length === 0
+      ? null
+      : el(
+          "div",
+          { class: "row" },
+          target,
+          el(
+            "button",
+            {
+              onclick: () =>
+                act(async () => {
+                  await api("PUT", `/api/devices/${target.value}/next`, { image: image.id });
+                  await loadFrames();
+                }, `Showing it on ${target.value} when it next wakes up`),
+            },
+            "Show next",
+          ),
+        ),
+    el(
+      "div",
+      { class: "row" },
+      el(
+        "button",
+        {
+          title: "Leave it out until the next refresh",
+          onclick: () =>
+            act(async () => {
+              await api("POST", `/api/images/${encodeURIComponent(image.id)}/skip`);
+              card.remove();
+            }, "Skipped until the next refresh"),
+        },
+        "Skip",
+      ),
+      el(
+        "button",
+        {
+          class: "danger",
+          title: "Never show these photos again",
+          onclick: () => {
+            const photos = image.assets.length === 1 ? "this photo" : "these photos";
+            if (!confirm(`Never show ${photos} again?`)) return;
+            act(async () => {
+              for (const asset of image.assets) {
+                await api("PUT", `/api/blacklist/${encodeURIComponent(asset)}`);
+              }
+              card.remove();
+              await loadBlacklist();
+            }, "Hidden for good");
+          },
+        },
+        "Never show",
+      ),
+    ),
+  );
+  return card;
+}
+
+// Picture settings
+
+async function loadSettings() {
+  state.processing = await json("/api/processing");
+  renderSettings();
+}
+
+function renderSettings() {
+  const form = $("form.settings");
+  const processing = state.processing;
+  form.fit.value = processing.fit;
+  form.dither.value = processing.dither;
+  form.gamut.checked = processing.gamut !== null;
+  form.knee.value = processing.gamut?.knee ?? 0.8;
+  form.knee.disabled = processing.gamut === null;
+
+  const list = $("#tone");
+  list.replaceChildren();
+  processing.tone.forEach((op, index) => list.append(toneRow(op, index)));
+}
+
+function toneRow(op, index) {
+  const { label, fields } = TONE_OPS[op.op];
+  const tone = state.processing.tone;
+  const move = (offset) => () => {
+    const [moved] = tone.splice(index, 1);
+    tone.splice(index + offset, 0, moved);
+    settingsChanged(true);
+  };
+  return el(
+    "li",
+    {},
+    el("span", { class: "name" }, label),
+    ...Object.entries(fields).map(([name, [fieldLabel, , step]]) =>
+      el(
+        "label",
+        {},
+        fieldLabel,
+        el("input", {
+          type: "number",
+          step,
+          value: op[name],
+          oninput: (event) => {
+            op[name] = Number(event.target.value);
+            settingsChanged(false);
+          },
+        }),
+      ),
+    ),
+    el("button", { type: "button", class: "small", disabled: index === 0, onclick: move(-1) }, "↑"),
+    el(
+      "button",
+      { type: "button", class: "small", disabled: index === tone.length - 1, onclick: move(1) },
+      "↓",
+    ),
+    el(
+      "button",
+      {
+        type: "button",
+        class: "small",
+        onclick: () => {
+          tone.splice(index, 1);
+          settingsChanged(true);
+        },
+      },
+      "Remove",
+    ),
+  );
+}
+
+// Picks up the form, redraws it when the tone list changed and processes the preview again.
+function settingsChanged(redraw) {
+  const form = $("form.settings");
+  const processing = state.processing;
+  processing.fit = form.fit.value;
+  processing.dither = form.dither.value;
+  processing.gamut = form.gamut.checked ? { knee: Number(form.knee.value) } : null;
+  if (redraw) renderSettings();
+  form.knee.disabled = !form.gamut.checked;
+
+  clearTimeout(state.previewTimer);
+  state.previewTimer = setTimeout(livePreview, PREVIEW_DELAY_MS);
+}
+
+function fillSamples() {
+  const sample = $("#sample");
+  const selected = sample.value;
+  sample.replaceChildren(
+    ...state.images.map((image) => el("option", { value: image.id }, image.id)),
+  );
+  if (state.images.some((image) => image.id === selected)) sample.value = selected;
+}
+
+async function livePreview() {
+  const id = $("#sample").value;
+  if (!id || !state.processing) return;
+  const img = $("#live-preview");
+  img.classList.add("loading");
+  try {
+    const inks = $("#inks").checked;
+    await loadImage(
+      img,
+      `/api/images/${encodeURIComponent(id)}/preview?inks=${inks}&width=${PREVIEW_WIDTH}`,
+      { body: state.processing },
+    );
+  } catch (e) {
+    show(e.message, true);
+  } finally {
+    img.classList.remove("loading");
+  }
+}
+
+// Hidden photos
+
+async function loadBlacklist() {
+  const assets = await json("/api/blacklist");
+  const list = $("#blacklist .list");
+  list.replaceChildren();
+  if (assets.length === 0) list.append(el("li", {}, "No hidden photos."));
+  for (const asset of assets) {
+    list.append(
+      el(
+        "li",
+        {},
+        el("code", {}, asset),
+        el(
+          "button",
+          {
+            class: "small",
+            onclick: () =>
+              act(async () => {
+                await api("DELETE", `/api/blacklist/${encodeURIComponent(asset)}`);
+                await loadBlacklist();
+              }, "Allowed again, it comes back with the next refresh"),
+          },
+          "Allow again",
+        ),
+      ),
+    );
+  }
+}
+
+// Signing in and out
+
+function signOut() {
+  state.token = "";
+  localStorage.removeItem("token");
+  for (const section of document.querySelectorAll("main section")) section.hidden = true;
+  $("header nav").hidden = true;
+  $("header .actions").hidden = true;
+  $("#sign-in").hidden = false;
+}
+
+function showTab(tab) {
+  for (const button of document.querySelectorAll("nav button")) {
+    button.classList.toggle("active", button.dataset.tab === tab);
+  }
+  for (const section of document.querySelectorAll("main section")) {
+    section.hidden = section.id !== tab;
+  }
+  if (tab === "settings") livePreview();
+}
+
+async function start() {
+  $("#sign-in").hidden = true;
+  $("header nav").hidden = false;
+  $("header .actions").hidden = false;
+  // Photo cards need the frames to offer them to
+  await loadFrames();
+  await Promise.all([loadPhotos(), loadSettings(), loadBlacklist()]);
+  showTab("frames");
+}
+
+$("#sign-in form").addEventListener("submit", (event) => {
+  event.preventDefault();
+  state.token = event.target.token.value;
+  localStorage.setItem("token", state.token);
+  act(start);
+});
+$("#sign-out").addEventListener("click", signOut);
+$("#refresh").addEventListener("click", () =>
+  act(
+    () => api("POST", "/api/refresh"),
+    "Refreshing, new photos show up here while they are processed",
+  ),
+);
+$("#more").addEventListener("click", showMorePhotos);
+$("#upload").addEventListener("submit", (event) => {
+  event.preventDefault();
+  const form = event.target;
+  const data = new FormData(form);
+  // Empty fields mean no limit, which the server takes as them being left out
+  for (const [name, value] of [...data.entries()]) {
+    if (value === "") data.delete(name);
+  }
+  act(async () => {
+    await api("POST", "/api/uploads", data);
+    form.reset();
+    await Promise.all([loadUploads(), loadFrames()]);
+  }, "Uploaded, it joins the photos the frames pick from");
+});
+for (const button of document.querySelectorAll("nav button")) {
+  button.addEventListener("click", () => {
+    showTab(button.dataset.tab);
+    const reload = { frames: loadFrames, photos: loadPhotos, blacklist: loadBlacklist }[
+      button.dataset.tab
+    ];
+    if (reload) act(reload);
+  });
+}
+
+const settings = $("form.settings");
+settings.addEventListener("change", (event) => {
+  if (event.target.closest("#tone") === null) settingsChanged(false);
+});
+settings.addEventListener("submit", (event) => {
+  event.preventDefault();
+  act(
+    () => api("PUT", "/api/processing", state.processing),
+    "Saved, the photos are processed again with these settings",
+  );
+});
+$("#reset").addEventListener("click", () => act(async () => {
+  await loadSettings();
+  livePreview();
+}));
+$("#sample").addEventListener("change", livePreview);
+$("#inks").addEventListener("change", livePreview);
+
+const addTone = $("#add-tone");
+addTone.append(
+  el("option", { value: "" }, "Choose an adjustment…"),
+  ...Object.entries(TONE_OPS).map(([op, { label }]) => el("option", { value: op }, label)),
+);
+addTone.addEventListener("change", () => {
+  const op = addTone.value;
+  if (!op) return;
+  const entry = { op };
+  for (const [name, [, value]] of Object.entries(TONE_OPS[op].fields)) entry[name] = value;
+  state.processing.tone.push(entry);
+  addTone.value = "";
+  settingsChanged(true);
+});
+
+if (state.token) {
+  act(start);
+} else {
+  signOut();
+}
@@ -0,0 +1,173 @@
+//! A frame simulated on the host, fetching one image over TLS.
+//!
+//! Pins the server certificate the way the firmware's `tls` feature does and
+//! runs the whole handshake, then checks the panels against their checksums.
+//!
+//! `cargo run --example tls_client -- <address> <cert.pem> [mac] [key]`
+
+use std::sync::Arc;
+
+use anyhow::{Result, anyhow};
+use rustls_pki_types::{CertificateDer, ServerName, UnixTime, pem::PemObject};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    TlsConnector,
+    rustls::{
+        self, DigitallySignedStruct, SignatureScheme,
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        crypto::{WebPkiSupportedAlgorithms, verify_tls12_signature, verify_tls13_signature},
+    },
+};
+
+#[path = "../src/codec.rs"]
+#[allow(dead_code)]
+mod codec;
+
+const VERSION: u8 = 6;
+const WINDOW: u32 = 8 * 1024;
+const ACK_INTERVAL: usize = 2 * 1024;
+const HEADER_LEN: usize = 29;
+
+/// Accepts exactly the pinned certificate, whatever name it was issued for.
+#[derive(Debug)]
+struct PinnedCertificate {
+    certificate: CertificateDer<'static>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if end_entity.as_ref() == self.certificate.as_ref() {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "Server certificate isn't the pinned one".to_string(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let [address, certificate, rest @ ..] = args.as_slice() else {
+        return Err(anyhow!(
+            "usage: tls_client <address> <cert.pem> [mac] [key]"
+        ));
+    };
+    let device: [u8; 6] = match rest.first() {
+        Some(mac) => mac
+            .split(':')
+            .map(|byte| u8::from_str_radix(byte, 16))
+            .collect::<Result<Vec<_>, _>>()?
+            .try_into()
+            .map_err(|_| anyhow!("Invalid MAC address {mac}"))?,
+        None => [0x02, 0, 0, 0, 0, 1],
+    };
+    let key = rest.get(1).map(String::as_str).unwrap_or("");
+
+    let verifier = PinnedCertificate {
+        certificate: CertificateDer::from_pem_file(certificate)?,
+        algorithms: rustls::crypto::aws_lc_rs::default_provider().signature_verification_algorithms,
+    };
+    let config = rustls::ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    let tcp = TcpStream::connect(address).await?;
+    let mut socket = TlsConnector::from(Arc::new(config))
+        .connect(ServerName::try_from("photo-frame")?, tcp)
+        .await?;
+    println!("TLS established with {address}");
+
+    let mut hello = b"SPF".to_vec();
+    hello.push(VERSION);
+    hello.push(codec::Encoding::Raw.mask() | codec::Encoding::Lz.mask());
+    hello.extend_from_slice(&device);
+    hello.extend_from_slice(&0u32.to_le_bytes());
+    hello.extend_from_slice(&0u32.to_le_bytes());
+    hello.extend_from_slice(&WINDOW.to_le_bytes());
+    socket.write_all(&hello).await?;
+
+    let mut challenge = [0u8; 16];
+    socket.read_exact(&mut challenge).await?;
+    // All zeros when the server has no key for us
+    if challenge != [0; 16] {
+        let mut response = hmac_sha256::HMAC::new(key);
+        response.update(challenge);
+        response.update(device);
+        socket.write_all(&response.finalize()).await?;
+    }
+
+    let mut header = [0u8; HEADER_LEN];
+    socket.read_exact(&mut header).await?;
+    let field = |at: usize| u32::from_le_bytes(header[at..at + 4].try_into().unwrap());
+    let encoding =
+        codec::Encoding::from_u8(header[4]).ok_or_else(|| anyhow!("Unknown encoding"))?;
+    let (left_len, right_len) = (field(5) as usize, field(9) as usize);
+    println!("Receiving {encoding:?} panels of {left_len} and {right_len} bytes");
+
+    let mut data = vec![0u8; left_len + right_len];
+    let mut received = 0;
+    while received < data.len() {
+        let end = (received + ACK_INTERVAL).min(data.len());
+        socket.read_exact(&mut data[received..end]).await?;
+        received = end;
+        socket.write_all(&(received as u32).to_le_bytes()).await?;
+    }
+
+    let left = codec::decode(&data[..left_len], encoding)?;
+    let right = codec::decode(&data[left_len..], encoding)?;
+    let mut image = crc32fast::Hasher::new();
+    image.update(&left);
+    image.update(&right);
+    let checks = [
+        ("left panel", crc32fast::hash(&left), field(13)),
+        ("right panel", crc32fast::hash(&right), field(17)),
+        ("image", image.finalize(), field(21)),
+    ];
+    for (name, actual, expected) in checks {
+        if actual != expected {
+            return Err(anyhow!(
+                "{name} checksum is {actual:08x}, expected {expected:08x}"
+            ));
+        }
+    }
+    println!(
+        "Received {} bytes, checksums match",
+        left.len() + right.len()
+    );
+    Ok(())
+}
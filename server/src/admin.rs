@@ -0,0 +1,533 @@
+//! HTTP API for looking after the server while it runs, and the dashboard on top of it.
+//!
+//! The dashboard at `/` is public, every API request needs
+//! `Authorization: Bearer <token>` with the token from `[server.admin]`.
+//! Devices are MAC addresses and images are frame ids, as `GET /api/images`
+//! lists them. Assets are `<album>:<id>`, the album's position in the config
+//! and the photo's id in its source.
+//!
+//! - `GET /api/images`: the pool, with the assets each frame shows.
+//! - `GET /api/images/{id}/preview`: the frame or upload as a PNG, as the panel will show it.
+//! - `POST /api/images/{id}/preview`: the frame processed with the settings in the body.
+//! - `POST /api/images/{id}/skip`: takes a frame out of the pool until the next refresh.
+//! - `POST /api/refresh`: refreshes the pool from the albums now.
+//! - `GET /api/uploads`: photos uploaded directly, with when they expire.
+//! - `POST /api/uploads`: processes an uploaded photo into the rotation, see [`crate::upload`].
+//! - `DELETE /api/uploads/{id}`: takes an uploaded photo out of the rotation.
+//! - `GET /api/devices`: every frame seen since the server started.
+//! - `GET /api/devices/{device}/preview`: the frame's current image as a PNG.
+//! - `PUT /api/devices/{device}/next`: shows `{"image": "<id>"}`, a frame or an upload,
+//!   on the frame's next wake.
+//! - `DELETE /api/devices/{device}/next`: goes back to random images.
+//! - `GET /api/blacklist`: the asset ids kept out of the pool.
+//! - `PUT /api/blacklist/{asset}`: keeps an asset out of the pool, across restarts.
+//! - `DELETE /api/blacklist/{asset}`: lets it back in with the next refresh.
+//! - `GET /api/processing`: the processing settings for albums without their own.
+//! - `PUT /api/processing`: replaces them across restarts and processes the pool again.
+//!
+//! Previews use the pure colours the panel is driven with, `?inks=true` shows
+//! them in the colours of `[frame.palette]` instead. `?width=` scales them down.
+
+use std::{collections::HashSet, io::ErrorKind, path::Path as FilePath, sync::Arc};
+
+use anyhow::{Result, anyhow};
+use axum::{
+    Json, Router,
+    body::Bytes,
+    extract::{DefaultBodyLimit, Path, Query, Request, State},
+    http::{HeaderMap, StatusCode, header},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post, put},
+};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+use uuid::Uuid;
+
+use crate::{
+    app_data::{AppData, DeviceStatus, Frame, ProccessedImage, UploadStatus, unix_time},
+    auth,
+    config::ProcessingConfig,
+    dashboard, load_frame,
+    pipeline::Pipeline,
+    protocol::DeviceId,
+    upload::UploadForm,
+};
+
+/// Largest photo that can be uploaded.
+const MAX_UPLOAD: usize = 64 * 1024 * 1024;
+
+struct AdminState {
+    app_data: Arc<AppData>,
+    pipeline: Arc<Pipeline>,
+    token: String,
+}
+
+type AdminError = (StatusCode, String);
+
+#[derive(Serialize)]
+struct ImageInfo {
+    id: String,
+    /// Immich album id or directory the photos come from.
+    album: String,
+    assets: Vec<String>,
+    /// Name of the collage template, none for a single photo.
+    template: Option<String>,
+}
+
+#[derive(Serialize)]
+struct DeviceInfo {
+    device: String,
+    #[serde(flatten)]
+    status: DeviceStatus,
+}
+
+#[derive(Serialize)]
+struct UploadInfo {
+    id: String,
+    #[serde(flatten)]
+    status: UploadStatus,
+}
+
+#[derive(Deserialize)]
+struct QueueRequest {
+    image: String,
+}
+
+#[derive(Deserialize)]
+struct PreviewQuery {
+    /// Simulates the palette's ink colours.
+    #[serde(default)]
+    inks: bool,
+    /// Scales the preview down to this width.
+    width: Option<u32>,
+}
+
+/// The admin API, answering requests that carry `token`.
+pub fn router(app_data: Arc<AppData>, pipeline: Arc<Pipeline>, token: String) -> Router {
+    let state = Arc::new(AdminState {
+        app_data,
+        pipeline,
+        token,
+    });
+    Router::new()
+        .route("/api/images", get(list_images))
+        .route(
+            "/api/images/{id}/preview",
+            get(preview_image).post(preview_processing),
+        )
+        .route("/api/images/{id}/skip", post(skip_image))
+        .route("/api/refresh", post(refresh))
+        .route(
+            "/api/uploads",
+            get(list_uploads)
+                .post(upload_photo)
+                .layer(DefaultBodyLimit::max(MAX_UPLOAD)),
+        )
+        .route("/api/uploads/{id}", delete(remove_upload))
+        .route("/api/devices", get(list_devices))
+        .route("/api/devices/{device}/preview", get(preview_device))
+        .route(
+            "/api/devices/{device}/next",
+            put(queue_image).delete(unqueue_image),
+        )
+        .route("/api/blacklist", get(list_blacklist))
+        .route(
+            "/api/blacklist/{asset}",
+            put(blacklist_asset).delete(unblacklist_asset),
+        )
+        .route("/api/processing", get(get_processing).put(set_processing))
+        .route_layer(middleware::from_fn_with_state(
+            Arc::clone(&state),
+            require_token,
+        ))
+        .with_state(state)
+        .merge(dashboard::router())
+}
+
+/// Serves the admin API on the listener until it fails.
+pub async fn serve(listener: TcpListener, router: Router) {
+    match listener.local_addr() {
+        Ok(address) => println!("Starting admin API on {address}..."),
+        Err(_) => println!("Starting admin API..."),
+    }
+    if let Err(e) = axum::serve(listener, router).await {
+        println!("Admin API failed: {e}");
+    }
+}
+
+/// Reads the blacklist file, which doesn't exist until something is blacklisted.
+pub fn load_blacklist(path: &FilePath) -> Result<HashSet<String>> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => Ok(content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(String::from)
+            .collect()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(HashSet::new()),
+        Err(e) => Err(anyhow!("Failed to read blacklist {}: {e}", path.display())),
+    }
+}
+
+/// Reads the processing settings the dashboard saved, if it ever did.
+pub fn load_processing(path: &FilePath) -> Result<Option<ProcessingConfig>> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(anyhow!(
+                "Failed to read processing settings {}: {e}",
+                path.display()
+            ));
+        }
+    };
+    let processing: ProcessingConfig = toml::from_str(&content).map_err(|e| {
+        anyhow!(
+            "Failed to parse processing settings {}: {e}",
+            path.display()
+        )
+    })?;
+    processing.validate()?;
+    Ok(Some(processing))
+}
+
+async fn require_token(
+    State(state): State<Arc<AdminState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let sent = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    if auth::verify_key(&state.token, sent).is_err() {
+        return (StatusCode::UNAUTHORIZED, "Missing or wrong admin token\n").into_response();
+    }
+    next.run(request).await
+}
+
+async fn list_images(State(state): State<Arc<AdminState>>) -> Json<Vec<ImageInfo>> {
+    let config = state.pipeline.config();
+    let images = state
+        .app_data
+        .frames()
+        .into_iter()
+        .map(|frame| ImageInfo {
+            album: state.pipeline.source_name(frame.album),
+            template: frame
+                .template
+                .map(|template| config.collage.templates[template].name.clone()),
+            id: frame.id,
+            assets: frame.assets,
+        })
+        .collect();
+    Json(images)
+}
+
+async fn preview_image(
+    State(state): State<Arc<AdminState>>,
+    Path(id): Path<String>,
+    Query(query): Query<PreviewQuery>,
+) -> Result<Response, AdminError> {
+    preview_pooled(&state, &id, &query).await
+}
+
+/// Processes the frame with settings that aren't saved yet, to try them out.
+async fn preview_processing(
+    State(state): State<Arc<AdminState>>,
+    Path(id): Path<String>,
+    Query(query): Query<PreviewQuery>,
+    Json(processing): Json<ProcessingConfig>,
+) -> Result<Response, AdminError> {
+    processing.validate().map_err(bad_request)?;
+    let frame = pool_frame(&state, &id)?;
+    let image = state
+        .pipeline
+        .render_with(&frame, processing)
+        .await
+        .map_err(internal_error)?;
+    render_preview(&state, image, &query).await
+}
+
+async fn skip_image(
+    State(state): State<Arc<AdminState>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AdminError> {
+    if !state.app_data.remove_frame(&id).await {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("No image {id} in the pool\n"),
+        ));
+    }
+    println!("Skipped image {id} until the next refresh");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn refresh(State(state): State<Arc<AdminState>>) -> StatusCode {
+    println!("Refresh requested through the admin API");
+    state.app_data.request_refresh();
+    StatusCode::ACCEPTED
+}
+
+async fn list_uploads(State(state): State<Arc<AdminState>>) -> Json<Vec<UploadInfo>> {
+    let uploads = state
+        .app_data
+        .uploads()
+        .into_iter()
+        .map(|(id, status)| UploadInfo { id, status })
+        .collect();
+    Json(uploads)
+}
+
+async fn upload_photo(
+    State(state): State<Arc<AdminState>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<(StatusCode, Json<UploadInfo>), AdminError> {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let form = UploadForm::parse(content_type, &body).map_err(bad_request)?;
+    let image = state
+        .pipeline
+        .process_upload(form.photo)
+        .await
+        .map_err(bad_request)?;
+
+    let id = format!("upload-{}", Uuid::new_v4().simple());
+    let status = UploadStatus {
+        name: form.name,
+        expires_at: form
+            .expires_after_secs
+            .map(|secs| unix_time().unwrap_or_default() + secs),
+        displays_left: form.displays,
+    };
+    println!("Uploaded {} as {id}", status.name);
+    state.app_data.add_upload(id.clone(), status.clone(), image);
+    if let Some(device) = form.device {
+        println!("Queued image {id} for {}", auth::format_mac(&device));
+        state.app_data.queue_image(device, Some(id.clone()));
+    }
+    Ok((StatusCode::CREATED, Json(UploadInfo { id, status })))
+}
+
+async fn remove_upload(
+    State(state): State<Arc<AdminState>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AdminError> {
+    if !state.app_data.remove_upload(&id) {
+        return Err((StatusCode::NOT_FOUND, format!("No upload {id}\n")));
+    }
+    println!("Removed upload {id}");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn list_devices(State(state): State<Arc<AdminState>>) -> Json<Vec<DeviceInfo>> {
+    let devices = state
+        .app_data
+        .devices()
+        .into_iter()
+        .map(|(device, status)| DeviceInfo {
+            device: auth::format_mac(&device),
+            status,
+        })
+        .collect();
+    Json(devices)
+}
+
+async fn preview_device(
+    State(state): State<Arc<AdminState>>,
+    Path(device): Path<String>,
+    Query(query): Query<PreviewQuery>,
+) -> Result<Response, AdminError> {
+    let device = parse_device(&device)?;
+    let Some(id) = state
+        .app_data
+        .device(&device)
+        .and_then(|status| status.image)
+    else {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("Device {} has no image yet\n", auth::format_mac(&device)),
+        ));
+    };
+    preview_pooled(&state, &id, &query).await
+}
+
+async fn queue_image(
+    State(state): State<Arc<AdminState>>,
+    Path(device): Path<String>,
+    Json(request): Json<QueueRequest>,
+) -> Result<StatusCode, AdminError> {
+    let device = parse_device(&device)?;
+    if !state.app_data.contains(&request.image) {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("No image {} in the pool\n", request.image),
+        ));
+    }
+    println!(
+        "Queued image {} for {}",
+        request.image,
+        auth::format_mac(&device)
+    );
+    state.app_data.queue_image(device, Some(request.image));
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn unqueue_image(
+    State(state): State<Arc<AdminState>>,
+    Path(device): Path<String>,
+) -> Result<StatusCode, AdminError> {
+    let device = parse_device(&device)?;
+    state.app_data.queue_image(device, None);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn list_blacklist(State(state): State<Arc<AdminState>>) -> Json<Vec<String>> {
+    Json(state.app_data.blacklist())
+}
+
+async fn blacklist_asset(
+    State(state): State<Arc<AdminState>>,
+    Path(asset): Path<String>,
+) -> Result<StatusCode, AdminError> {
+    println!("Blacklisted asset {asset}");
+    state.app_data.blacklist_asset(&asset).await;
+    save_blacklist(&state).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn unblacklist_asset(
+    State(state): State<Arc<AdminState>>,
+    Path(asset): Path<String>,
+) -> Result<StatusCode, AdminError> {
+    if !state.app_data.unblacklist_asset(&asset) {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("Asset {asset} isn't blacklisted\n"),
+        ));
+    }
+    println!("Removed asset {asset} from the blacklist");
+    save_blacklist(&state).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn save_blacklist(state: &AdminState) -> Result<(), AdminError> {
+    let path = &state.pipeline.config().storage.blacklist;
+    let content: String = state
+        .app_data
+        .blacklist()
+        .iter()
+        .map(|asset| format!("{asset}\n"))
+        .collect();
+    tokio::fs::write(path, content).await.map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to save blacklist {}: {e}\n", path.display()),
+        )
+    })
+}
+
+async fn get_processing(State(state): State<Arc<AdminState>>) -> Json<ProcessingConfig> {
+    Json(state.pipeline.processing())
+}
+
+async fn set_processing(
+    State(state): State<Arc<AdminState>>,
+    Json(processing): Json<ProcessingConfig>,
+) -> Result<StatusCode, AdminError> {
+    processing.validate().map_err(bad_request)?;
+    let path = &state.pipeline.config().storage.processing;
+    let content = toml::to_string(&processing).map_err(|e| internal_error(e.into()))?;
+    tokio::fs::write(path, content).await.map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!(
+                "Failed to save processing settings {}: {e}\n",
+                path.display()
+            ),
+        )
+    })?;
+
+    println!("Processing settings changed through the admin API");
+    state.pipeline.set_processing(processing);
+    // Albums with their own settings keep their images
+    let albums = &state.pipeline.config().albums;
+    state
+        .app_data
+        .forget_images(|album| albums[album].processing.is_none())
+        .await;
+    state.app_data.request_refresh();
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn pool_frame(state: &AdminState, id: &str) -> Result<Frame, AdminError> {
+    state.app_data.get_frame(id).ok_or_else(|| {
+        (
+            StatusCode::NOT_FOUND,
+            format!("No image {id} in the pool\n"),
+        )
+    })
+}
+
+/// Renders the frame from the pool or the upload as a PNG, processing the frame
+/// first if needed.
+async fn preview_pooled(
+    state: &AdminState,
+    id: &str,
+    query: &PreviewQuery,
+) -> Result<Response, AdminError> {
+    let image = match state.app_data.upload_image(id) {
+        Some(image) => image,
+        None => {
+            let frame = pool_frame(state, id)?;
+            load_frame(&state.app_data, &state.pipeline, &frame)
+                .await
+                .map_err(internal_error)?
+        }
+    };
+    render_preview(state, image, query).await
+}
+
+/// Renders the processed frame as a PNG.
+async fn render_preview(
+    state: &AdminState,
+    image: ProccessedImage,
+    query: &PreviewQuery,
+) -> Result<Response, AdminError> {
+    let png = state
+        .pipeline
+        .preview(image, query.inks, query.width)
+        .await
+        .map_err(internal_error)?;
+    Ok((
+        [
+            (header::CONTENT_TYPE, "image/png"),
+            (header::CACHE_CONTROL, "no-store"),
+        ],
+        png,
+    )
+        .into_response())
+}
+
+fn internal_error(e: anyhow::Error) -> AdminError {
+    (StatusCode::INTERNAL_SERVER_ERROR, format!("{e}\n"))
+}
+
+fn bad_request(e: anyhow::Error) -> AdminError {
+    (StatusCode::BAD_REQUEST, format!("{e}\n"))
+}
+
+fn parse_device(device: &str) -> Result<DeviceId, AdminError> {
+    auth::parse_mac(device).ok_or_else(|| {
+        (
+            StatusCode::NOT_FOUND,
+            format!("Device {device} isn't a MAC address\n"),
+        )
+    })
+}
@@ -1,31 +1,488 @@
-use std::sync::RwLock;
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::{Mutex, RwLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
 
+use anyhow::Result;
 use image::{ImageBuffer, Rgb};
+use serde::Serialize;
+use tokio::{sync::Notify, time::Instant};
+
+use crate::{
+    codec::{self, Encoding},
+    frame_store::FrameStore,
+    protocol::{CHALLENGE_LEN, DeviceId, RESUME_WINDOW},
+};
 
-#[derive(Default)]
 pub struct AppData {
-    images: RwLock<Vec<ProccessedImage>>,
+    frames: RwLock<Pool>,
+    store: Box<dyn FrameStore>,
+    /// The image each device is being sent, kept so an interrupted transfer can resume.
+    pins: Mutex<HashMap<DeviceId, Pin>>,
+    devices: Mutex<HashMap<DeviceId, DeviceStatus>>,
+    /// The nonce each device has to sign its next HTTP request with.
+    nonces: Mutex<HashMap<DeviceId, [u8; CHALLENGE_LEN]>>,
+    /// Assets kept out of the pool.
+    blacklist: RwLock<HashSet<String>>,
+    /// Photos uploaded directly, shown alongside the pool until they expire.
+    uploads: Mutex<HashMap<String, Upload>>,
+    refresh: Notify,
+}
+
+/// What the server knows about a frame, as the admin API shows it.
+#[derive(Clone, Default, Serialize)]
+pub struct DeviceStatus {
+    /// Where the frame last connected from.
+    pub address: Option<IpAddr>,
+    /// When, in seconds since the Unix epoch.
+    pub last_seen: Option<u64>,
+    /// Id of the frame last picked for it, none for the blank placeholder.
+    pub image: Option<String>,
+    /// Whether that frame arrived completely, as far as the server can tell.
+    pub delivered: bool,
+    /// Frame to show on its next wake instead of a random one.
+    pub next: Option<String>,
+}
+
+/// An uploaded photo, as the admin API shows it.
+#[derive(Clone, Serialize)]
+pub struct UploadStatus {
+    /// File name it was uploaded as.
+    pub name: String,
+    /// When it leaves the rotation, in seconds since the Unix epoch.
+    pub expires_at: Option<u64>,
+    /// How many more times it's shown before it leaves the rotation.
+    pub displays_left: Option<u32>,
+}
+
+struct Upload {
+    status: UploadStatus,
+    image: ProccessedImage,
+}
+
+/// The frames in the pool, indexed by id.
+#[derive(Default)]
+struct Pool {
+    frames: Vec<Frame>,
+    /// Position of every frame in `frames`.
+    index: HashMap<String, usize>,
+}
+
+impl Pool {
+    fn new(frames: Vec<Frame>) -> Self {
+        let mut pool = Pool {
+            frames,
+            index: HashMap::new(),
+        };
+        pool.reindex();
+        pool
+    }
+
+    fn get(&self, id: &str) -> Option<&Frame> {
+        self.index.get(id).map(|&i| &self.frames[i])
+    }
+
+    /// Adds the frame, replacing the one with the same id.
+    fn insert(&mut self, frame: Frame) {
+        match self.index.get(&frame.id) {
+            Some(&i) => self.frames[i] = frame,
+            None => {
+                self.index.insert(frame.id.clone(), self.frames.len());
+                self.frames.push(frame);
+            }
+        }
+    }
+
+    fn retain(&mut self, keep: impl FnMut(&Frame) -> bool) {
+        let before = self.frames.len();
+        self.frames.retain(keep);
+        if self.frames.len() != before {
+            self.reindex();
+        }
+    }
+
+    fn reindex(&mut self) {
+        self.index = self
+            .frames
+            .iter()
+            .enumerate()
+            .map(|(i, frame)| (frame.id.clone(), i))
+            .collect();
+    }
+}
+
+struct Pin {
+    image: ProccessedImage,
+    expires: Instant,
+}
+
+/// A frame in the pool: a single asset, or a collage of several when `template` is set.
+#[derive(Clone)]
+pub struct Frame {
+    pub id: String,
+    pub album: usize,
+    pub assets: Vec<String>,
+    pub template: Option<usize>,
+    /// Changes whenever one of its photos does.
+    pub version: String,
 }
 
 #[derive(Clone, Default)]
 pub struct ProccessedImage {
+    pub encoding: Encoding,
     pub left: Vec<u8>,
     pub right: Vec<u8>,
+    pub checksums: Checksums,
+}
+
+/// CRC-32 of the raw panel bytes, whichever encoding they are stored in.
+#[derive(Clone, Copy, Default)]
+pub struct Checksums {
+    pub left: u32,
+    pub right: u32,
+    /// Both panels, left first.
+    pub image: u32,
+}
+
+impl Checksums {
+    pub fn new(left: &[u8], right: &[u8]) -> Self {
+        let mut image = crc32fast::Hasher::new();
+        image.update(left);
+        image.update(right);
+        Checksums {
+            left: crc32fast::hash(left),
+            right: crc32fast::hash(right),
+            image: image.finalize(),
+        }
+    }
+}
+
+impl ProccessedImage {
+    /// Returns the image with both panels in the given encoding.
+    pub fn encoded(self, encoding: Encoding) -> Result<Self> {
+        if self.encoding == encoding {
+            return Ok(self);
+        }
+
+        let left = codec::decode(&self.left, self.encoding)?;
+        let right = codec::decode(&self.right, self.encoding)?;
+        Ok(ProccessedImage {
+            encoding,
+            left: codec::encode(&left, encoding),
+            right: codec::encode(&right, encoding),
+            checksums: self.checksums,
+        })
+    }
 }
 
 impl AppData {
-    pub fn get_random_image(&self) -> ProccessedImage {
-        let images = self.images.read().unwrap();
-        if images.len() == 0 {
-            return ProccessedImage::default();
+    pub fn new(store: Box<dyn FrameStore>) -> Self {
+        AppData {
+            frames: RwLock::default(),
+            store,
+            pins: Mutex::new(HashMap::new()),
+            devices: Mutex::new(HashMap::new()),
+            nonces: Mutex::new(HashMap::new()),
+            blacklist: RwLock::new(HashSet::new()),
+            uploads: Mutex::new(HashMap::new()),
+            refresh: Notify::new(),
+        }
+    }
+
+    /// Every frame in the pool.
+    pub fn frames(&self) -> Vec<Frame> {
+        self.frames.read().unwrap().frames.clone()
+    }
+
+    pub fn get_frame(&self, id: &str) -> Option<Frame> {
+        self.frames.read().unwrap().get(id).cloned()
+    }
+
+    /// Picks a frame from the pool or an upload, each as likely as the other.
+    pub fn random_id(&self) -> Option<String> {
+        let frames = &self.frames.read().unwrap().frames;
+        let mut uploads = self.uploads.lock().unwrap();
+        prune_uploads(&mut uploads);
+        let count = frames.len() + uploads.len();
+        if count == 0 {
+            return None;
+        }
+        let index = rand::random_range(0..count);
+        match frames.get(index) {
+            Some(frame) => Some(frame.id.clone()),
+            None => uploads.keys().nth(index - frames.len()).cloned(),
         }
-        let index = rand::random_range(0..images.len());
-        images[index].clone()
     }
 
-    pub fn set_images(&self, images: Vec<ProccessedImage>) {
-        *self.images.write().unwrap() = images;
+    /// Whether the id names a frame in the pool or an upload.
+    pub fn contains(&self, id: &str) -> bool {
+        self.get_frame(id).is_some() || self.upload_image(id).is_some()
     }
+
+    /// Returns the processed frame if the store still holds it.
+    pub async fn get_image(&self, id: &str) -> Option<ProccessedImage> {
+        self.store.get(id).await
+    }
+
+    /// Stores a processed frame without changing the pool.
+    pub async fn cache_image(&self, id: &str, image: ProccessedImage) {
+        self.store.insert(id, image).await;
+    }
+
+    /// Adds the frame to the pool, replacing an older version with the same id.
+    pub async fn insert_image(&self, frame: Frame, image: ProccessedImage) {
+        self.store.insert(&frame.id, image).await;
+        self.frames.write().unwrap().insert(frame);
+    }
+
+    /// Whether the pool holds the frame's image at this version already.
+    pub fn is_current(&self, frame: &Frame) -> bool {
+        let unchanged = self
+            .frames
+            .read()
+            .unwrap()
+            .get(&frame.id)
+            .is_some_and(|existing| existing.version == frame.version);
+        unchanged && self.store.contains(&frame.id)
+    }
+
+    /// Replaces the pool with frames that get processed when they're first requested.
+    pub async fn set_frames(&self, frames: Vec<Frame>) {
+        let ids = frames.iter().map(|frame| frame.id.clone()).collect();
+        self.retain_frames(&ids).await;
+        for frame in &frames {
+            if !self.is_current(frame) {
+                self.store.remove(&frame.id).await;
+            }
+        }
+        *self.frames.write().unwrap() = Pool::new(frames);
+    }
+
+    /// Keeps the image a device is being sent around for [`RESUME_WINDOW`].
+    pub fn pin_image(&self, device: DeviceId, image: ProccessedImage) {
+        let mut pins = self.pins.lock().unwrap();
+        let now = Instant::now();
+        pins.retain(|_, pin| pin.expires > now);
+        pins.insert(
+            device,
+            Pin {
+                image,
+                expires: now + RESUME_WINDOW,
+            },
+        );
+    }
+
+    /// Returns the image pinned for the device, unless its window has passed.
+    pub fn pinned_image(&self, device: &DeviceId) -> Option<ProccessedImage> {
+        let pins = self.pins.lock().unwrap();
+        let pin = pins.get(device)?;
+        (pin.expires > Instant::now()).then(|| pin.image.clone())
+    }
+
+    pub fn unpin_image(&self, device: &DeviceId) {
+        self.pins.lock().unwrap().remove(device);
+    }
+
+    /// Takes the frame out of the pool until it comes back with the next refresh.
+    pub async fn remove_frame(&self, id: &str) -> bool {
+        let removed = {
+            let mut frames = self.frames.write().unwrap();
+            let before = frames.frames.len();
+            frames.retain(|frame| frame.id != id);
+            frames.frames.len() != before
+        };
+        self.store.remove(id).await;
+        removed
+    }
+
+    pub fn devices(&self) -> Vec<(DeviceId, DeviceStatus)> {
+        let devices = self.devices.lock().unwrap();
+        let mut devices: Vec<_> = devices
+            .iter()
+            .map(|(device, status)| (*device, status.clone()))
+            .collect();
+        devices.sort_by_key(|(device, _)| *device);
+        devices
+    }
+
+    pub fn device(&self, device: &DeviceId) -> Option<DeviceStatus> {
+        self.devices.lock().unwrap().get(device).cloned()
+    }
+
+    /// Notes that the device connected from `address` just now.
+    pub fn check_in(&self, device: DeviceId, address: IpAddr) {
+        let mut devices = self.devices.lock().unwrap();
+        let status = devices.entry(device).or_default();
+        status.address = Some(address.to_canonical());
+        status.last_seen = unix_time();
+    }
+
+    /// Notes the frame picked for the device, which hasn't arrived yet.
+    pub fn record_image(&self, device: DeviceId, image: Option<String>) {
+        let mut devices = self.devices.lock().unwrap();
+        let status = devices.entry(device).or_default();
+        status.image = image;
+        status.delivered = false;
+    }
+
+    pub fn record_delivery(&self, device: DeviceId) {
+        let mut devices = self.devices.lock().unwrap();
+        devices.entry(device).or_default().delivered = true;
+    }
+
+    /// Replaces the nonce the device signs its next request with.
+    pub fn set_nonce(&self, device: DeviceId, nonce: [u8; CHALLENGE_LEN]) {
+        self.nonces.lock().unwrap().insert(device, nonce);
+    }
+
+    /// Returns the device's nonce, once.
+    pub fn take_nonce(&self, device: &DeviceId) -> Option<[u8; CHALLENGE_LEN]> {
+        self.nonces.lock().unwrap().remove(device)
+    }
+
+    /// Shows the frame on the device's next wake, `None` goes back to random ones.
+    pub fn queue_image(&self, device: DeviceId, id: Option<String>) {
+        let mut devices = self.devices.lock().unwrap();
+        devices.entry(device).or_default().next = id;
+    }
+
+    /// Returns the frame queued for the device, once.
+    pub fn take_queued(&self, device: &DeviceId) -> Option<String> {
+        let mut devices = self.devices.lock().unwrap();
+        devices.get_mut(device)?.next.take()
+    }
+
+    /// The blacklisted asset ids, sorted.
+    pub fn blacklist(&self) -> Vec<String> {
+        let mut assets: Vec<_> = self.blacklist.read().unwrap().iter().cloned().collect();
+        assets.sort();
+        assets
+    }
+
+    pub fn set_blacklist(&self, assets: HashSet<String>) {
+        *self.blacklist.write().unwrap() = assets;
+    }
+
+    /// Keeps the asset out of the pool, dropping every frame showing it now.
+    pub async fn blacklist_asset(&self, asset: &str) {
+        self.blacklist.write().unwrap().insert(asset.to_string());
+        let ids = self
+            .frames
+            .read()
+            .unwrap()
+            .frames
+            .iter()
+            .filter(|frame| self.allows(frame))
+            .map(|frame| frame.id.clone())
+            .collect();
+        self.retain_frames(&ids).await;
+    }
+
+    /// Lets the asset back in, with the next refresh.
+    pub fn unblacklist_asset(&self, asset: &str) -> bool {
+        self.blacklist.write().unwrap().remove(asset)
+    }
+
+    /// Whether none of the frame's assets are blacklisted.
+    pub fn allows(&self, frame: &Frame) -> bool {
+        let blacklist = self.blacklist.read().unwrap();
+        !frame.assets.iter().any(|asset| blacklist.contains(asset))
+    }
+
+    /// Adds an uploaded photo to the rotation.
+    pub fn add_upload(&self, id: String, status: UploadStatus, image: ProccessedImage) {
+        let mut uploads = self.uploads.lock().unwrap();
+        uploads.insert(id, Upload { status, image });
+    }
+
+    /// The uploads still in the rotation, sorted by id.
+    pub fn uploads(&self) -> Vec<(String, UploadStatus)> {
+        let mut uploads = self.uploads.lock().unwrap();
+        prune_uploads(&mut uploads);
+        let mut uploads: Vec<_> = uploads
+            .iter()
+            .map(|(id, upload)| (id.clone(), upload.status.clone()))
+            .collect();
+        uploads.sort_by(|(a, _), (b, _)| a.cmp(b));
+        uploads
+    }
+
+    pub fn remove_upload(&self, id: &str) -> bool {
+        self.uploads.lock().unwrap().remove(id).is_some()
+    }
+
+    /// Returns the upload's image without counting it as displayed.
+    pub fn upload_image(&self, id: &str) -> Option<ProccessedImage> {
+        let mut uploads = self.uploads.lock().unwrap();
+        prune_uploads(&mut uploads);
+        uploads.get(id).map(|upload| upload.image.clone())
+    }
+
+    /// Counts a display of the upload, if the id is one, dropping it once it
+    /// has been shown as often as it should.
+    pub fn count_display(&self, id: &str) {
+        let mut uploads = self.uploads.lock().unwrap();
+        let Some(upload) = uploads.get_mut(id) else {
+            return;
+        };
+        if let Some(displays) = &mut upload.status.displays_left {
+            *displays = displays.saturating_sub(1);
+            if *displays == 0 {
+                uploads.remove(id);
+            }
+        }
+    }
+
+    /// Wakes the refresh loop up early.
+    pub fn request_refresh(&self) {
+        self.refresh.notify_one();
+    }
+
+    /// Resolves once a refresh was requested.
+    pub async fn refresh_requested(&self) {
+        self.refresh.notified().await;
+    }
+
+    /// Drops the processed images of the frames from albums `album` picks,
+    /// they get processed again when next needed.
+    pub async fn forget_images(&self, album: impl Fn(usize) -> bool) {
+        for frame in self.frames() {
+            if album(frame.album) {
+                self.store.remove(&frame.id).await;
+            }
+        }
+    }
+
+    /// Drops every frame whose id isn't in `ids`.
+    pub async fn retain_frames(&self, ids: &HashSet<String>) {
+        let mut removed = Vec::new();
+        self.frames.write().unwrap().retain(|frame| {
+            let keep = ids.contains(&frame.id);
+            if !keep {
+                removed.push(frame.id.clone());
+            }
+            keep
+        });
+        for id in removed {
+            self.store.remove(&id).await;
+        }
+    }
+}
+
+/// Seconds since the Unix epoch.
+pub fn unix_time() -> Option<u64> {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .ok()
+        .map(|time| time.as_secs())
+}
+
+/// Drops the uploads whose time is up.
+fn prune_uploads(uploads: &mut HashMap<String, Upload>) {
+    let now = unix_time().unwrap_or_default();
+    uploads.retain(|_, upload| upload.status.expires_at.is_none_or(|expires| expires > now));
 }
 
 /*
@@ -41,12 +498,10 @@ impl AppData {
 -----------------
 */
 impl From<ImageBuffer<Rgb<u8>, Vec<u8>>> for ProccessedImage {
-    fn from(mut value: ImageBuffer<Rgb<u8>, Vec<u8>>) -> Self {
+    fn from(value: ImageBuffer<Rgb<u8>, Vec<u8>>) -> Self {
         let mut left_panel = Vec::new();
         let mut right_panel = Vec::new();
 
-        value = image::imageops::rotate270(&value);
-
         for y in 0..value.height() {
             for x in (0..value.width()).step_by(2) {
                 let pixel = value.get_pixel(x, y);
@@ -64,6 +519,8 @@ impl From<ImageBuffer<Rgb<u8>, Vec<u8>>> for ProccessedImage {
         }
 
         ProccessedImage {
+            encoding: Encoding::Raw,
+            checksums: Checksums::new(&left_panel, &right_panel),
             left: left_panel,
             right: right_panel,
         }
@@ -0,0 +1,212 @@
+//! Deciding which frames may connect, see [`AuthConfig`].
+//!
+//! Only keys and networks keep anyone out. A device listed without a key is
+//! let in on the MAC address it reports, which any client can claim.
+
+use std::net::IpAddr;
+
+use anyhow::{Result, anyhow};
+use hmac_sha256::{HMAC, Hash};
+
+use crate::{
+    config::AuthConfig,
+    protocol::{CHALLENGE_LEN, DeviceId, NO_CHALLENGE, RESPONSE_LEN},
+};
+
+/// Checks the address a frame connects from against the allowed networks.
+pub fn check_peer(config: &AuthConfig, ip: IpAddr) -> Result<()> {
+    // Dual stack sockets see IPv4 peers as IPv4 mapped IPv6 addresses
+    let ip = ip.to_canonical();
+    if config.allowed_networks.is_empty()
+        || config.allowed_networks.iter().any(|net| net.contains(&ip))
+    {
+        Ok(())
+    } else {
+        Err(anyhow!("{ip} is outside of the allowed networks"))
+    }
+}
+
+/// Returns the key the device has to prove it knows, if it's allowed at all.
+pub fn device_key<'a>(config: &'a AuthConfig, device: &DeviceId) -> Result<Option<&'a str>> {
+    if config.devices.is_empty() {
+        return Ok(None);
+    }
+    config
+        .devices
+        .iter()
+        .find(|allowed| allowed.mac == *device)
+        .map(|allowed| allowed.key.as_deref())
+        .ok_or_else(|| anyhow!("Device {} is not allowed", format_mac(device)))
+}
+
+/// Frames from before the handshake can't say who they are.
+pub fn allows_anonymous(config: &AuthConfig) -> bool {
+    config.devices.is_empty()
+}
+
+pub fn new_challenge() -> [u8; CHALLENGE_LEN] {
+    loop {
+        let challenge = rand::random();
+        if challenge != NO_CHALLENGE {
+            return challenge;
+        }
+    }
+}
+
+/// `HMAC-SHA256(key, challenge || device)`, what the frame answers a challenge with.
+pub fn verify(
+    key: &str,
+    challenge: &[u8; CHALLENGE_LEN],
+    device: &DeviceId,
+    response: &[u8; RESPONSE_LEN],
+) -> Result<()> {
+    let mut mac = HMAC::new(key);
+    mac.update(challenge);
+    mac.update(device);
+    if mac.finalize_verify(response) {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "Device {} failed the challenge, wrong key",
+            format_mac(device)
+        ))
+    }
+}
+
+/// Checks a key sent as is, as frames do over HTTP, in constant time.
+pub fn verify_key(key: &str, sent: &str) -> Result<()> {
+    // Comparing digests hides the key's length as well as where they differ
+    let key = Hash::hash(key.as_bytes());
+    let sent = Hash::hash(sent.as_bytes());
+    let difference = key.iter().zip(sent).fold(0, |acc, (a, b)| acc | (a ^ b));
+    if difference == 0 {
+        Ok(())
+    } else {
+        Err(anyhow!("Wrong key"))
+    }
+}
+
+/// Parses a MAC address written as six bytes of two hex digits, separated by colons.
+pub fn parse_mac(text: &str) -> Option<DeviceId> {
+    let bytes: Vec<u8> = text
+        .split(':')
+        .map(|byte| match byte.len() {
+            // from_str_radix would also take a sign
+            2 if byte.bytes().all(|digit| digit.is_ascii_hexdigit()) => {
+                u8::from_str_radix(byte, 16).ok()
+            }
+            _ => None,
+        })
+        .collect::<Option<_>>()?;
+    bytes.try_into().ok()
+}
+
+pub fn format_mac(device: &DeviceId) -> String {
+    device
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DeviceConfig;
+
+    const DEVICE: DeviceId = [0x02, 0, 0, 0, 0, 0x01];
+
+    fn response(key: &str, challenge: &[u8; CHALLENGE_LEN], device: &DeviceId) -> [u8; 32] {
+        HMAC::mac([&challenge[..], device].concat(), key)
+    }
+
+    fn config(devices: Vec<DeviceConfig>, networks: &[&str]) -> AuthConfig {
+        AuthConfig {
+            allowed_networks: networks.iter().map(|net| net.parse().unwrap()).collect(),
+            devices,
+        }
+    }
+
+    #[test]
+    fn verifies_challenge_responses() {
+        let challenge = new_challenge();
+        assert_ne!(challenge, NO_CHALLENGE);
+        let answer = response("secret", &challenge, &DEVICE);
+        assert!(verify("secret", &challenge, &DEVICE, &answer).is_ok());
+
+        assert!(verify("other", &challenge, &DEVICE, &answer).is_err());
+        let other_device = [0x02, 0, 0, 0, 0, 0x02];
+        assert!(verify("secret", &challenge, &other_device, &answer).is_err());
+        // An answer overheard once is no good for the next challenge
+        let next = new_challenge();
+        assert_ne!(next, challenge);
+        assert!(verify("secret", &next, &DEVICE, &answer).is_err());
+    }
+
+    #[test]
+    fn checks_peers_against_the_networks() {
+        let open = config(Vec::new(), &[]);
+        assert!(check_peer(&open, "203.0.113.7".parse().unwrap()).is_ok());
+
+        let home = config(Vec::new(), &["192.168.1.0/24", "fd00::/8"]);
+        assert!(check_peer(&home, "192.168.1.20".parse().unwrap()).is_ok());
+        assert!(check_peer(&home, "::ffff:192.168.1.20".parse().unwrap()).is_ok());
+        assert!(check_peer(&home, "::ffff:192.168.2.20".parse().unwrap()).is_err());
+        assert!(check_peer(&home, "fd12::1".parse().unwrap()).is_ok());
+        assert!(check_peer(&home, "2001:db8::1".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn parses_mac_addresses() {
+        assert_eq!(parse_mac("02:00:00:00:00:01"), Some(DEVICE));
+        assert_eq!(
+            parse_mac("AA:bb:0c:00:ff:10"),
+            Some([0xAA, 0xBB, 0x0C, 0, 0xFF, 0x10])
+        );
+        assert_eq!(format_mac(&DEVICE), "02:00:00:00:00:01");
+        for mac in [[0; 6], [0xFF; 6], [0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC]] {
+            assert_eq!(parse_mac(&format_mac(&mac)), Some(mac));
+        }
+
+        for text in [
+            "",
+            "02:00:00:00:00",
+            "02:00:00:00:00:01:02",
+            "02-00-00-00-00-01",
+            "2:0:0:0:0:1",
+            "+2:00:00:00:00:01",
+            "02:00:00:00:00:0g",
+            "02:00:00:00:00:001",
+        ] {
+            assert_eq!(parse_mac(text), None, "{text}");
+        }
+    }
+
+    #[test]
+    fn looks_up_device_keys() {
+        let open = config(Vec::new(), &[]);
+        assert!(allows_anonymous(&open));
+        assert_eq!(device_key(&open, &DEVICE).unwrap(), None);
+
+        let listed = config(
+            vec![
+                DeviceConfig {
+                    mac: DEVICE,
+                    key: Some("secret".to_string()),
+                },
+                DeviceConfig {
+                    mac: [0x02, 0, 0, 0, 0, 0x02],
+                    key: None,
+                },
+            ],
+            &[],
+        );
+        assert!(!allows_anonymous(&listed));
+        assert_eq!(device_key(&listed, &DEVICE).unwrap(), Some("secret"));
+        assert_eq!(
+            device_key(&listed, &[0x02, 0, 0, 0, 0, 0x02]).unwrap(),
+            None
+        );
+        assert!(device_key(&listed, &[0x02, 0, 0, 0, 0, 0x03]).is_err());
+    }
+}
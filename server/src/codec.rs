@@ -0,0 +1,437 @@
+//! Compression for packed panel data.
+//!
+//! The format is a sequence of tokens, each starting with a control byte:
+//!
+//! - `0x00..=0x3F`: literal run, the next `c + 1` bytes are copied as is.
+//! - `0x40..=0x7F`: packed literal run of `(c & 0x3F) + 1` groups. Each group
+//!   is a little endian `u16` holding six pixels as base 6 digits, lowest digit
+//!   first, indexing [`PIXELS`]. A group unpacks to three bytes, the earlier
+//!   pixel of each pair in the high nibble.
+//! - `0x80..=0xBF`: byte run, the next byte repeated `(c & 0x3F) + 3` times.
+//! - `0xC0..=0xFF`: back reference, `(c & 0x3F) + 4` bytes copied from the
+//!   little endian `u16` distance that follows, counted back from the end of
+//!   the output.
+//!
+//! Back references never reach further than [`WINDOW`] bytes, so a decoder
+//! only has to remember that much of its output.
+
+use anyhow::{Result, anyhow};
+use serde::Deserialize;
+
+/// How far back references may reach.
+pub const WINDOW: usize = 8192;
+
+/// The nibbles the panel uses for its six colours.
+pub const PIXELS: [u8; 6] = [0x0, 0x1, 0x2, 0x3, 0x5, 0x6];
+
+const MAX_LITERALS: usize = 64;
+const MAX_GROUPS: usize = 64;
+const GROUP_BYTES: usize = 3;
+/// Literals gathered before they are written out, a whole number of groups.
+const MAX_PENDING: usize = MAX_GROUPS * GROUP_BYTES;
+const MIN_RUN: usize = 3;
+const MAX_RUN: usize = 66;
+const MIN_MATCH: usize = 4;
+const MAX_MATCH: usize = 67;
+/// Shorter runs and matches cost about as much as packing them as literals.
+const USEFUL_RUN: usize = 4;
+const USEFUL_MATCH: usize = 6;
+const HASH_BITS: u32 = 14;
+const MAX_CHAIN: usize = 32;
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+#[repr(u8)]
+pub enum Encoding {
+    #[default]
+    Raw = 0,
+    Lz = 1,
+}
+
+impl Encoding {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Encoding::Raw),
+            1 => Some(Encoding::Lz),
+            _ => None,
+        }
+    }
+
+    /// Bit representing the encoding in a client's list of supported encodings.
+    pub fn mask(self) -> u8 {
+        1 << self as u8
+    }
+
+    /// Name of the encoding in the config and HTTP headers.
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Raw => "raw",
+            Encoding::Lz => "lz",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "raw" => Some(Encoding::Raw),
+            "lz" => Some(Encoding::Lz),
+            _ => None,
+        }
+    }
+}
+
+pub fn encode(data: &[u8], encoding: Encoding) -> Vec<u8> {
+    match encoding {
+        Encoding::Raw => data.to_vec(),
+        Encoding::Lz => compress(data),
+    }
+}
+
+pub fn decode(data: &[u8], encoding: Encoding) -> Result<Vec<u8>> {
+    match encoding {
+        Encoding::Raw => Ok(data.to_vec()),
+        Encoding::Lz => decompress(data),
+    }
+}
+
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() / 2);
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut chain = vec![usize::MAX; WINDOW];
+    // Start of the literals not written out yet
+    let mut literals = None;
+
+    let insert = |position: usize, head: &mut Vec<usize>, chain: &mut Vec<usize>| {
+        if let Some(hash) = hash_at(data, position) {
+            chain[position % WINDOW] = head[hash];
+            head[hash] = position;
+        }
+    };
+
+    let mut i = 0;
+    while i < data.len() {
+        let run = data[i..]
+            .iter()
+            .take(MAX_RUN)
+            .take_while(|byte| **byte == data[i])
+            .count();
+        let (length, distance) = find_match(data, i, &head, &chain);
+
+        let token_length = if run >= USEFUL_RUN && run >= length {
+            if let Some(start) = literals.take() {
+                write_literals(&mut out, &data[start..i]);
+            }
+            out.push(0x80 | (run - MIN_RUN) as u8);
+            out.push(data[i]);
+            run
+        } else if length >= USEFUL_MATCH {
+            if let Some(start) = literals.take() {
+                write_literals(&mut out, &data[start..i]);
+            }
+            out.push(0xC0 | (length - MIN_MATCH) as u8);
+            out.extend_from_slice(&(distance as u16).to_le_bytes());
+            length
+        } else {
+            let start = *literals.get_or_insert(i);
+            if i + 1 - start == MAX_PENDING {
+                write_literals(&mut out, &data[start..=i]);
+                literals = None;
+            }
+            1
+        };
+
+        for position in i..i + token_length {
+            insert(position, &mut head, &mut chain);
+        }
+        i += token_length;
+    }
+    if let Some(start) = literals {
+        write_literals(&mut out, &data[start..]);
+    }
+
+    out
+}
+
+fn write_literals(out: &mut Vec<u8>, literals: &[u8]) {
+    let packable = literals
+        .iter()
+        .all(|byte| PIXELS.contains(&(byte >> 4)) && PIXELS.contains(&(byte & 0xF)));
+    let (groups, rest) = if packable {
+        literals.split_at(literals.len() / GROUP_BYTES * GROUP_BYTES)
+    } else {
+        (&[][..], literals)
+    };
+
+    for chunk in groups.chunks(MAX_GROUPS * GROUP_BYTES) {
+        out.push(0x40 | (chunk.len() / GROUP_BYTES - 1) as u8);
+        for group in chunk.chunks(GROUP_BYTES) {
+            out.extend_from_slice(&pack_group(group).to_le_bytes());
+        }
+    }
+    for chunk in rest.chunks(MAX_LITERALS) {
+        out.push((chunk.len() - 1) as u8);
+        out.extend_from_slice(chunk);
+    }
+}
+
+fn pack_group(group: &[u8]) -> u16 {
+    group
+        .iter()
+        .flat_map(|byte| [byte >> 4, byte & 0xF])
+        .rev()
+        .fold(0, |value, pixel| {
+            value * 6 + PIXELS.iter().position(|p| *p == pixel).unwrap() as u16
+        })
+}
+
+fn unpack_group(mut value: u16) -> [u8; GROUP_BYTES] {
+    let mut pixels = [0u8; 2 * GROUP_BYTES];
+    for pixel in pixels.iter_mut() {
+        *pixel = PIXELS[(value % 6) as usize];
+        value /= 6;
+    }
+    [
+        pixels[0] << 4 | pixels[1],
+        pixels[2] << 4 | pixels[3],
+        pixels[4] << 4 | pixels[5],
+    ]
+}
+
+fn hash_at(data: &[u8], position: usize) -> Option<usize> {
+    let bytes: [u8; MIN_MATCH] = data.get(position..position + MIN_MATCH)?.try_into().ok()?;
+    Some((u32::from_le_bytes(bytes).wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize)
+}
+
+/// Longest earlier match for the data at `position` within the window.
+fn find_match(data: &[u8], position: usize, head: &[usize], chain: &[usize]) -> (usize, usize) {
+    let Some(hash) = hash_at(data, position) else {
+        return (0, 0);
+    };
+
+    let limit = (data.len() - position).min(MAX_MATCH);
+    let mut best = (0, 0);
+    let mut candidate = head[hash];
+    for _ in 0..MAX_CHAIN {
+        if candidate == usize::MAX || position - candidate > WINDOW {
+            break;
+        }
+        let length = data[candidate..]
+            .iter()
+            .zip(&data[position..position + limit])
+            .take_while(|(a, b)| a == b)
+            .count();
+        if length > best.0 {
+            best = (length, position - candidate);
+            if length == limit {
+                break;
+            }
+        }
+
+        let next = chain[candidate % WINDOW];
+        if next == usize::MAX || next >= candidate {
+            break;
+        }
+        candidate = next;
+    }
+    best
+}
+
+fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len() * 2);
+    let mut i = 0;
+    let truncated = || anyhow!("Compressed data ends in the middle of a token");
+
+    while i < data.len() {
+        let control = data[i];
+        i += 1;
+        match control {
+            0x00..=0x3F => {
+                let length = control as usize + 1;
+                out.extend_from_slice(data.get(i..i + length).ok_or_else(truncated)?);
+                i += length;
+            }
+            0x40..=0x7F => {
+                let length = ((control & 0x3F) as usize + 1) * 2;
+                for group in data.get(i..i + length).ok_or_else(truncated)?.chunks(2) {
+                    let value = u16::from_le_bytes([group[0], group[1]]);
+                    if value >= 6u16.pow(6) {
+                        return Err(anyhow!("Packed group {value} is out of range"));
+                    }
+                    out.extend_from_slice(&unpack_group(value));
+                }
+                i += length;
+            }
+            0x80..=0xBF => {
+                let byte = *data.get(i).ok_or_else(truncated)?;
+                out.extend(std::iter::repeat_n(
+                    byte,
+                    (control & 0x3F) as usize + MIN_RUN,
+                ));
+                i += 1;
+            }
+            0xC0..=0xFF => {
+                let distance = data.get(i..i + 2).ok_or_else(truncated)?;
+                let distance = u16::from_le_bytes([distance[0], distance[1]]) as usize;
+                if distance == 0 || distance > out.len() {
+                    return Err(anyhow!(
+                        "Back reference reaches before the start of the data"
+                    ));
+                }
+                for _ in 0..(control & 0x3F) as usize + MIN_MATCH {
+                    out.push(out[out.len() - distance]);
+                }
+                i += 2;
+            }
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+    use crate::{
+        app_data::ProccessedImage,
+        config::{Dither, Palette},
+        image_ops::dither_image,
+    };
+
+    /// Deterministic noise, so failures reproduce.
+    fn noise(len: usize, mut seed: u32) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                seed as u8
+            })
+            .collect()
+    }
+
+    /// The control byte of every token, with the distance of back references.
+    fn tokens(compressed: &[u8]) -> Vec<(u8, usize)> {
+        let mut tokens = Vec::new();
+        let mut i = 0;
+        while i < compressed.len() {
+            let control = compressed[i];
+            let (length, distance) = match control {
+                0x00..=0x3F => (control as usize + 1, 0),
+                0x40..=0x7F => (((control & 0x3F) as usize + 1) * 2, 0),
+                0x80..=0xBF => (1, 0),
+                0xC0..=0xFF => (
+                    2,
+                    u16::from_le_bytes([compressed[i + 1], compressed[i + 2]]) as usize,
+                ),
+            };
+            tokens.push((control, distance));
+            i += 1 + length;
+        }
+        tokens
+    }
+
+    fn round_trip(data: &[u8]) -> Vec<(u8, usize)> {
+        let compressed = compress(data);
+        assert_eq!(decompress(&compressed).unwrap(), data);
+        tokens(&compressed)
+    }
+
+    #[test]
+    fn round_trips_random_bytes() {
+        for len in [0, 1, 2, 3, 63, 64, 65, 192, 193, 10_000] {
+            round_trip(&noise(len, len as u32 + 1));
+        }
+        // Random pixels pack, but never into runs or matches
+        let pixels: Vec<u8> = noise(9_000, 7)
+            .chunks(2)
+            .map(|pair| PIXELS[pair[0] as usize % 6] << 4 | PIXELS[pair[1] as usize % 6])
+            .collect();
+        assert!(
+            round_trip(&pixels)
+                .iter()
+                .all(|(control, _)| (0x40..=0x7F).contains(control) || *control < 0x40)
+        );
+    }
+
+    #[test]
+    fn round_trips_a_dithered_panel() {
+        let mut img = RgbImage::from_fn(1200, 400, |x, y| {
+            Rgb([(x * 255 / 1199) as u8, (y * 255 / 399) as u8, 128])
+        });
+        dither_image(&mut img, &Palette::default(), Dither::FloydSteinberg);
+        let panel = ProccessedImage::from(img);
+        for data in [panel.left, panel.right] {
+            let compressed = compress(&data);
+            assert!(compressed.len() < data.len() / 2);
+            assert_eq!(decompress(&compressed).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn round_trips_long_runs() {
+        let mut data = vec![0x11; 10_000];
+        data.extend_from_slice(&[0x23; 3]);
+        data.extend_from_slice(&[0x56; MAX_RUN + 1]);
+        let tokens = round_trip(&data);
+        assert!(tokens.contains(&(0x80 | (MAX_RUN - MIN_RUN) as u8, 0)));
+        assert!(round_trip(&[0x11; 50_000]).len() < 50_000 / MAX_RUN + 2);
+    }
+
+    #[test]
+    fn round_trips_matches_of_every_length() {
+        let block = noise(3 * MAX_MATCH, 3);
+        let mut data = block.clone();
+        for length in [
+            MIN_MATCH,
+            USEFUL_MATCH,
+            MAX_MATCH - 1,
+            MAX_MATCH,
+            2 * MAX_MATCH,
+        ] {
+            data.extend_from_slice(&noise(10, length as u32));
+            data.extend_from_slice(&block[..length]);
+        }
+        let controls: Vec<u8> = round_trip(&data)
+            .iter()
+            .map(|(control, _)| *control)
+            .collect();
+        assert!(controls.contains(&(0xC0 | (USEFUL_MATCH - MIN_MATCH) as u8)));
+        assert!(controls.contains(&(0xC0 | (MAX_MATCH - 1 - MIN_MATCH) as u8)));
+        // One at the longest length, two more for twice that
+        assert_eq!(
+            controls.iter().filter(|control| **control == 0xFF).count(),
+            3
+        );
+    }
+
+    #[test]
+    fn keeps_matches_within_the_window() {
+        let block = noise(100, 11);
+        let mut data = block.clone();
+        data.extend_from_slice(&noise(WINDOW - block.len(), 12));
+        // Exactly a window back
+        data.extend_from_slice(&block);
+        data.extend_from_slice(&noise(WINDOW, 13));
+        // One byte too far
+        let far = data.len() - WINDOW - 1;
+        let repeat = data[far..far + 100].to_vec();
+        data.extend_from_slice(&repeat);
+
+        let tokens = round_trip(&data);
+        let distances: Vec<usize> = tokens
+            .iter()
+            .filter(|(control, _)| *control >= 0xC0)
+            .map(|(_, distance)| *distance)
+            .collect();
+        assert!(distances.contains(&WINDOW));
+        assert!(distances.iter().all(|distance| *distance <= WINDOW));
+    }
+
+    #[test]
+    fn rejects_corrupt_data() {
+        assert!(decompress(&[0x05, 1, 2]).is_err());
+        assert!(decompress(&[0x40, 0xFF, 0xFF]).is_err());
+        assert!(decompress(&[0x00, 1, 0xC0, 2, 0]).is_err());
+        assert!(decompress(&[0x80]).is_err());
+    }
+}
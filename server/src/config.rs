@@ -0,0 +1,678 @@
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Result, anyhow};
+use image::Rgb;
+use ipnet::IpNet;
+use serde::{Deserialize, Deserializer, Serialize, de::Error};
+use uuid::Uuid;
+
+use crate::{auth, codec::Encoding, protocol::DeviceId};
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct Config {
+    pub frame: FrameConfig,
+    pub server: ServerConfig,
+    pub auth: AuthConfig,
+    pub workers: WorkerConfig,
+    pub storage: StorageConfig,
+    pub processing: ProcessingConfig,
+    pub collage: CollageConfig,
+    pub albums: Vec<AlbumConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Addresses frames connect to, IPv4 or IPv6.
+    pub bind: Vec<IpAddr>,
+    pub port: u16,
+    /// Lets IPv6 addresses accept IPv4 connections too, so `::` alone covers both.
+    pub dual_stack: bool,
+    /// An already listening socket to accept connections on instead of binding.
+    pub listen_fd: Option<i32>,
+    /// Also serves frames over TLS, on the bind addresses at its own port.
+    pub tls: Option<TlsConfig>,
+    /// Also serves frames over HTTP, on the bind addresses at its own port.
+    pub http: Option<HttpConfig>,
+    /// Serves the admin API, on the bind addresses at its own port.
+    pub admin: Option<AdminConfig>,
+    /// Connections served at once, further frames wait to be accepted.
+    pub max_connections: usize,
+    pub read_timeout_secs: u64,
+    pub write_timeout_secs: u64,
+    /// How long a whole connection may take.
+    pub connection_timeout_secs: u64,
+}
+
+#[derive(Deserialize)]
+pub struct TlsConfig {
+    #[serde(default = "default_tls_port")]
+    pub port: u16,
+    /// PEM certificate chain, the frames pin the first certificate.
+    pub certificate: PathBuf,
+    /// PEM private key of the certificate.
+    pub key: PathBuf,
+}
+
+#[derive(Deserialize)]
+pub struct HttpConfig {
+    #[serde(default = "default_http_port")]
+    pub port: u16,
+}
+
+#[derive(Deserialize)]
+pub struct AdminConfig {
+    #[serde(default = "default_admin_port")]
+    pub port: u16,
+    /// Bearer token every admin request has to carry.
+    pub token: String,
+}
+
+/// Who may fetch frames. Everyone may while both lists are empty.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct AuthConfig {
+    /// Networks frames may connect from.
+    pub allowed_networks: Vec<IpNet>,
+    /// Frames that may connect, by MAC address.
+    pub devices: Vec<DeviceConfig>,
+}
+
+#[derive(Deserialize)]
+pub struct DeviceConfig {
+    #[serde(deserialize_with = "deserialize_mac")]
+    pub mac: DeviceId,
+    /// Key the frame has to prove it knows. Without one any client claiming the
+    /// MAC gets in, the entry only tells frames apart and isn't a security boundary.
+    pub key: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct WorkerConfig {
+    /// Threads processing images, 0 uses one per CPU core.
+    pub threads: usize,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    /// Where the disk backend keeps its frames.
+    pub directory: PathBuf,
+    /// How much memory the disk and lazy backends may use to cache frames.
+    pub memory_budget_mb: usize,
+    /// How frames are compressed while stored.
+    pub encoding: Encoding,
+    /// File listing the asset ids kept out of the pool, one per line.
+    pub blacklist: PathBuf,
+    /// File the dashboard saves processing settings to, replacing `[processing]`.
+    pub processing: PathBuf,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageBackend {
+    /// Keeps every processed frame in memory.
+    #[default]
+    Memory,
+    /// Keeps every processed frame on disk, caching recently used ones in memory.
+    /// The files don't outlive a restart.
+    Disk,
+    /// Processes frames when they're first requested and caches recently used ones.
+    Lazy,
+}
+
+/// Photos to show. Each album reads from exactly one source.
+#[derive(Deserialize, Default)]
+pub struct AlbumConfig {
+    /// Immich album.
+    pub id: Option<Uuid>,
+    /// Local directory, such as a Syncthing folder.
+    pub directory: Option<DirectoryConfig>,
+    /// WebDAV collection, such as a Nextcloud folder.
+    pub webdav: Option<WebDavConfig>,
+    /// Objects in an S3-compatible bucket.
+    pub s3: Option<S3Config>,
+    /// Images linked from an RSS or Atom feed or a list of urls.
+    pub feed: Option<FeedConfig>,
+    /// Replaces the global processing settings for photos from this album.
+    pub processing: Option<ProcessingConfig>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct DirectoryConfig {
+    pub path: PathBuf,
+    /// Globs of the files to show, relative to `path`.
+    #[serde(default = "default_include")]
+    pub include: Vec<String>,
+    /// Globs of files and directories to skip, even when included.
+    #[serde(default = "default_exclude")]
+    pub exclude: Vec<String>,
+    /// Refreshes as soon as files change instead of waiting for the next refresh.
+    #[serde(default = "default_watch")]
+    pub watch: bool,
+    /// Quiet time after a change before refreshing, so a sync is picked up in one go.
+    #[serde(default = "default_debounce_secs")]
+    pub debounce_secs: u64,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct WebDavConfig {
+    /// The collection, its subfolders are shown too.
+    pub url: String,
+    /// Sent with `password` as basic auth, for Nextcloud best an app password.
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct S3Config {
+    /// Where the storage answers, like `https://s3.example.com`.
+    pub endpoint: String,
+    #[serde(default = "default_region")]
+    pub region: String,
+    pub bucket: String,
+    /// Only keys starting with it are shown.
+    #[serde(default)]
+    pub prefix: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    /// Puts the bucket in the path rather than the host name, as MinIO and Garage expect.
+    #[serde(default = "default_path_style")]
+    pub path_style: bool,
+    /// Globs of the keys to show, relative to `prefix`.
+    #[serde(default = "default_include")]
+    pub include: Vec<String>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct FeedConfig {
+    /// RSS or Atom feed, or a text file with one image url per line.
+    pub url: String,
+    /// Shows only the first this many images, feeds list the newest first.
+    #[serde(default = "default_feed_limit")]
+    pub limit: usize,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct FrameConfig {
+    pub orientation: Orientation,
+    pub palette: Palette,
+}
+
+/// The colours the panel's inks actually show, used for gamut mapping and dithering.
+#[derive(Deserialize, Clone, Copy)]
+#[serde(default)]
+pub struct Palette {
+    pub black: [u8; 3],
+    pub white: [u8; 3],
+    pub yellow: [u8; 3],
+    pub red: [u8; 3],
+    pub blue: [u8; 3],
+    pub green: [u8; 3],
+}
+
+/// How the frame is mounted, as seen by someone looking at it.
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Orientation {
+    #[default]
+    Landscape,
+    Portrait,
+    LandscapeFlipped,
+    PortraitFlipped,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct ProcessingConfig {
+    /// How photos are fitted to the canvas or their collage cell.
+    pub fit: Fit,
+    /// Tone adjustments applied in order before dithering.
+    pub tone: Vec<ToneOp>,
+    /// Maps colours into the palette's gamut after the tone adjustments.
+    pub gamut: Option<GamutConfig>,
+    pub dither: Dither,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Fit {
+    /// Fills the space, cropping what sticks out.
+    #[default]
+    Crop,
+    /// Shows the whole photo, with bars in the background colour around it.
+    Contain,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Dither {
+    /// Spreads the error of every pixel to its neighbours.
+    #[default]
+    FloydSteinberg,
+    /// Maps every pixel to the nearest colour, flat areas stay clean but gradients band.
+    None,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct GamutConfig {
+    /// Fraction of the gamut boundary up to which chroma is left untouched.
+    #[serde(default = "default_knee")]
+    pub knee: f32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum ToneOp {
+    /// Stretches the histogram, ignoring the given fraction of pixels at either end.
+    AutoLevels {
+        #[serde(default = "default_clip")]
+        clip: f32,
+    },
+    /// Values above 1 brighten midtones, values below 1 darken them.
+    Gamma { value: f32 },
+    /// Scales the distance from mid grey, 1 keeps the image unchanged.
+    Contrast { amount: f32 },
+    /// Scales the distance from the pixel's luminance, 1 keeps the image unchanged.
+    Saturation { amount: f32 },
+    /// Brightens dark tones while keeping black and white in place.
+    ShadowLift { amount: f32 },
+    /// Unsharp mask with the given blur radius and difference threshold.
+    Sharpen {
+        #[serde(default = "default_sigma")]
+        sigma: f32,
+        #[serde(default)]
+        threshold: i32,
+    },
+    /// Contrast limited adaptive histogram equalization on a `tiles` x `tiles` grid.
+    Clahe {
+        #[serde(default = "default_tiles")]
+        tiles: u32,
+        #[serde(default = "default_clip_limit")]
+        clip_limit: f32,
+    },
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct CollageConfig {
+    pub templates: Vec<CollageTemplate>,
+}
+
+/// A collage layout on a `columns` x `rows` grid laid over the panel canvas.
+#[derive(Deserialize, Clone)]
+pub struct CollageTemplate {
+    pub name: String,
+    pub columns: u32,
+    pub rows: u32,
+    /// Space between cells and around the border, in pixels.
+    #[serde(default)]
+    pub gap: u32,
+    #[serde(default = "default_background")]
+    pub background: [u8; 3],
+    pub cells: Vec<CollageCell>,
+}
+
+/// A cell placed at grid position `x`, `y` spanning `width` x `height` grid units.
+#[derive(Deserialize, Clone)]
+pub struct CollageCell {
+    pub x: u32,
+    pub y: u32,
+    #[serde(default = "default_span")]
+    pub width: u32,
+    #[serde(default = "default_span")]
+    pub height: u32,
+}
+
+impl Orientation {
+    /// Width and height of the canvas images are composed on.
+    pub fn canvas_size(self) -> (u32, u32) {
+        match self {
+            Orientation::Landscape | Orientation::LandscapeFlipped => (1600, 1200),
+            Orientation::Portrait | Orientation::PortraitFlipped => (1200, 1600),
+        }
+    }
+
+    pub fn is_portrait(self) -> bool {
+        matches!(self, Orientation::Portrait | Orientation::PortraitFlipped)
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind: vec![IpAddr::V4(Ipv4Addr::UNSPECIFIED)],
+            port: 2025,
+            dual_stack: false,
+            listen_fd: None,
+            tls: None,
+            http: None,
+            admin: None,
+            max_connections: 32,
+            read_timeout_secs: 30,
+            write_timeout_secs: 30,
+            connection_timeout_secs: 300,
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            backend: StorageBackend::default(),
+            directory: PathBuf::from("frames"),
+            memory_budget_mb: 256,
+            encoding: Encoding::default(),
+            blacklist: PathBuf::from("blacklist.txt"),
+            processing: PathBuf::from("processing.toml"),
+        }
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette {
+            black: [0, 0, 0],
+            white: [255, 255, 255],
+            yellow: [255, 255, 0],
+            red: [255, 0, 0],
+            blue: [0, 0, 255],
+            green: [0, 255, 0],
+        }
+    }
+}
+
+impl Palette {
+    /// The palette in the order dithering indexes it.
+    pub fn colors(&self) -> [Rgb<u8>; 6] {
+        [
+            Rgb(self.black),
+            Rgb(self.red),
+            Rgb(self.green),
+            Rgb(self.blue),
+            Rgb(self.yellow),
+            Rgb(self.white),
+        ]
+    }
+}
+
+fn deserialize_mac<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DeviceId, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    auth::parse_mac(&text).ok_or_else(|| D::Error::custom(format!("invalid MAC address {text}")))
+}
+
+fn default_tls_port() -> u16 {
+    2026
+}
+
+fn default_http_port() -> u16 {
+    8080
+}
+
+fn default_admin_port() -> u16 {
+    8081
+}
+
+fn default_include() -> Vec<String> {
+    [
+        "*.jpg", "*.jpeg", "*.png", "*.webp", "*.gif", "*.bmp", "*.tif", "*.tiff",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+fn default_exclude() -> Vec<String> {
+    // Hidden files and Syncthing's own folders and temporary files
+    [".*", "~syncthing~*"].map(String::from).to_vec()
+}
+
+fn default_watch() -> bool {
+    true
+}
+
+fn default_debounce_secs() -> u64 {
+    5
+}
+
+fn default_region() -> String {
+    "us-east-1".to_string()
+}
+
+fn default_path_style() -> bool {
+    true
+}
+
+fn default_feed_limit() -> usize {
+    50
+}
+
+fn default_background() -> [u8; 3] {
+    [255, 255, 255]
+}
+
+fn default_span() -> u32 {
+    1
+}
+
+fn default_clip() -> f32 {
+    0.005
+}
+
+fn default_knee() -> f32 {
+    0.8
+}
+
+fn default_sigma() -> f32 {
+    1.0
+}
+
+fn default_tiles() -> u32 {
+    8
+}
+
+fn default_clip_limit() -> f32 {
+    2.0
+}
+
+impl Config {
+    /// Loads the config file, falling back to defaults when it doesn't exist.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read config {}: {e:?}", path.display()))?;
+        let config: Config = toml::from_str(&content)
+            .map_err(|e| anyhow!("Failed to parse config {}: {e}", path.display()))?;
+
+        if config.server.bind.is_empty() && config.server.listen_fd.is_none() {
+            return Err(anyhow!("server.bind needs at least one address"));
+        }
+        if config
+            .server
+            .admin
+            .as_ref()
+            .is_some_and(|admin| admin.token.is_empty())
+        {
+            return Err(anyhow!("server.admin.token must not be empty"));
+        }
+        if config.server.max_connections == 0 {
+            return Err(anyhow!("server.max_connections must be at least 1"));
+        }
+        config.processing.validate()?;
+        for album in &config.albums {
+            album.validate()?;
+        }
+        for template in &config.collage.templates {
+            template.validate()?;
+        }
+
+        Ok(config)
+    }
+}
+
+impl AlbumConfig {
+    /// An Immich album with the global processing settings.
+    pub fn immich(id: Uuid) -> Self {
+        AlbumConfig {
+            id: Some(id),
+            ..Default::default()
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        let sources = [
+            self.id.is_some(),
+            self.directory.is_some(),
+            self.webdav.is_some(),
+            self.s3.is_some(),
+            self.feed.is_some(),
+        ];
+        if sources.into_iter().filter(|&source| source).count() != 1 {
+            return Err(anyhow!(
+                "Every album needs exactly one source, an id, a directory, webdav, s3 or a feed"
+            ));
+        }
+        if let Some(processing) = &self.processing {
+            processing.validate()?;
+        }
+        Ok(())
+    }
+}
+
+impl ProcessingConfig {
+    /// Rejects values the image operations can't work with.
+    pub fn validate(&self) -> Result<()> {
+        if let Some(gamut) = &self.gamut
+            && !(0.0..=1.0).contains(&gamut.knee)
+        {
+            return Err(anyhow!("Gamut knee must be between 0 and 1"));
+        }
+        for op in &self.tone {
+            let valid = match *op {
+                ToneOp::AutoLevels { clip } => (0.0..0.5).contains(&clip),
+                ToneOp::Gamma { value } => value > 0.0 && value.is_finite(),
+                ToneOp::Contrast { amount }
+                | ToneOp::Saturation { amount }
+                | ToneOp::ShadowLift { amount } => amount.is_finite(),
+                ToneOp::Sharpen { sigma, .. } => sigma > 0.0 && sigma.is_finite(),
+                ToneOp::Clahe { tiles, clip_limit } => tiles > 0 && clip_limit > 0.0,
+            };
+            if !valid {
+                return Err(anyhow!("Tone adjustment {op:?} is out of range"));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl CollageTemplate {
+    fn validate(&self) -> Result<()> {
+        if !(3..=6).contains(&self.cells.len()) {
+            return Err(anyhow!(
+                "Collage template {} must have between 3 and 6 cells",
+                self.name
+            ));
+        }
+        // Every unit needs a pixel left after the gaps, whichever way the frame hangs
+        let (width, height) = Orientation::Landscape.canvas_size();
+        let fits = |units: u32| {
+            units > 0
+                && self
+                    .gap
+                    .checked_mul(units + 1)
+                    .and_then(|gaps| gaps.checked_add(units))
+                    .is_some_and(|needed| needed <= width.min(height))
+        };
+        if !fits(self.columns) || !fits(self.rows) {
+            return Err(anyhow!(
+                "Collage template {} doesn't fit {} columns and {} rows with a gap of {} on the canvas",
+                self.name,
+                self.columns,
+                self.rows,
+                self.gap
+            ));
+        }
+        for (i, cell) in self.cells.iter().enumerate() {
+            if cell.width == 0
+                || cell.height == 0
+                || cell.x.saturating_add(cell.width) > self.columns
+                || cell.y.saturating_add(cell.height) > self.rows
+            {
+                return Err(anyhow!(
+                    "Collage template {} has a cell outside of its {}x{} grid",
+                    self.name,
+                    self.columns,
+                    self.rows
+                ));
+            }
+            if self.cells[..i].iter().any(|other| cell.overlaps(other)) {
+                return Err(anyhow!(
+                    "Collage template {} has overlapping cells at {}, {}",
+                    self.name,
+                    cell.x,
+                    cell.y
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl CollageCell {
+    fn overlaps(&self, other: &CollageCell) -> bool {
+        self.x < other.x + other.width
+            && other.x < self.x + self.width
+            && self.y < other.y + other.height
+            && other.y < self.y + self.height
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn template(gap: u32, cells: &str) -> CollageTemplate {
+        toml::from_str(&format!(
+            "name = \"test\"\ncolumns = 3\nrows = 2\ngap = {gap}\ncells = {cells}"
+        ))
+        .unwrap()
+    }
+
+    const THREE_CELLS: &str =
+        "[{ x = 0, y = 0, height = 2 }, { x = 1, y = 0, width = 2 }, { x = 1, y = 1 }]";
+
+    #[test]
+    fn accepts_collages_that_fit() {
+        template(12, THREE_CELLS).validate().unwrap();
+        // Three columns of a pixel with four gaps fill the short side exactly
+        template(299, THREE_CELLS).validate().unwrap();
+    }
+
+    #[test]
+    fn rejects_gaps_wider_than_the_canvas() {
+        assert!(template(300, THREE_CELLS).validate().is_err());
+        assert!(template(u32::MAX, THREE_CELLS).validate().is_err());
+    }
+
+    #[test]
+    fn rejects_overlapping_cells() {
+        let cells = "[{ x = 0, y = 0, width = 2 }, { x = 1, y = 0 }, { x = 2, y = 1 }]";
+        assert!(template(0, cells).validate().is_err());
+    }
+
+    #[test]
+    fn rejects_cells_outside_the_grid() {
+        let cells = "[{ x = 0, y = 0 }, { x = 1, y = 0 }, { x = 2, y = 1, height = 2 }]";
+        assert!(template(0, cells).validate().is_err());
+    }
+}
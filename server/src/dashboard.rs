@@ -0,0 +1,26 @@
+//! The web dashboard, a single page built into the binary that works through
+//! the admin API. It asks for the admin token and keeps it in the browser.
+
+use axum::{Router, http::header, response::IntoResponse, routing::get};
+
+const INDEX: &str = include_str!("../dashboard/index.html");
+const SCRIPT: &str = include_str!("../dashboard/dashboard.js");
+const STYLE: &str = include_str!("../dashboard/dashboard.css");
+
+/// Serves the dashboard's files, which hold nothing secret.
+pub fn router() -> Router {
+    Router::new()
+        .route("/", get(|| asset(INDEX, "text/html; charset=utf-8")))
+        .route(
+            "/dashboard.js",
+            get(|| asset(SCRIPT, "text/javascript; charset=utf-8")),
+        )
+        .route(
+            "/dashboard.css",
+            get(|| asset(STYLE, "text/css; charset=utf-8")),
+        )
+}
+
+async fn asset(content: &'static str, content_type: &'static str) -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, content_type)], content)
+}
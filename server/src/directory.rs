@@ -0,0 +1,401 @@
+//! Photos from a local directory, such as a Syncthing folder.
+//!
+//! Files are listed recursively and filtered with the album's include and
+//! exclude globs. A file's size and modification time fingerprint it, so a
+//! refresh only processes files that changed. While watching, inotify events
+//! for photos trigger a refresh once the directory has been quiet for
+//! `debounce_secs`.
+
+use std::{
+    path::{Component, Path},
+    sync::Arc,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::{Result, anyhow};
+use futures::future::BoxFuture;
+use tokio::sync::Notify;
+
+use crate::{
+    config::DirectoryConfig,
+    source::{PhotoSource, SourcePhoto},
+};
+
+pub struct DirectorySource {
+    config: DirectoryConfig,
+    /// Watches every directory the last listing went through.
+    watcher: Option<Arc<Watcher>>,
+    changed: Arc<Notify>,
+}
+
+impl DirectorySource {
+    /// Opens the directory, watching it when configured to. Needs a Tokio runtime.
+    pub fn new(config: &DirectoryConfig) -> Result<Self> {
+        if !config.path.is_dir() {
+            return Err(anyhow!(
+                "Photo directory {} doesn't exist",
+                config.path.display()
+            ));
+        }
+
+        let changed = Arc::new(Notify::new());
+        let watcher = match config.watch.then(Watcher::new).transpose() {
+            Ok(watcher) => watcher.map(Arc::new),
+            Err(e) => {
+                println!(
+                    "Can't watch {}, it's only scanned on refreshes: {e}",
+                    config.path.display()
+                );
+                None
+            }
+        };
+        if let Some(watcher) = &watcher {
+            tokio::spawn(watch(
+                Arc::clone(watcher),
+                config.clone(),
+                Arc::clone(&changed),
+            ));
+        }
+
+        Ok(DirectorySource {
+            config: config.clone(),
+            watcher,
+            changed,
+        })
+    }
+}
+
+impl PhotoSource for DirectorySource {
+    fn name(&self) -> String {
+        self.config.path.display().to_string()
+    }
+
+    fn list(&self) -> BoxFuture<'_, Result<Vec<SourcePhoto>>> {
+        let config = self.config.clone();
+        let watcher = self.watcher.clone();
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || scan(&config, watcher.as_deref())).await?
+        })
+    }
+
+    fn fetch<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<Vec<u8>>> {
+        Box::pin(async move {
+            // Ids come from listings, but never read outside the directory
+            let relative = Path::new(id);
+            if id.is_empty()
+                || !relative
+                    .components()
+                    .all(|component| matches!(component, Component::Normal(_)))
+            {
+                return Err(anyhow!("Invalid photo path {id}"));
+            }
+            let path = self.config.path.join(relative);
+            tokio::fs::read(&path)
+                .await
+                .map_err(|e| anyhow!("Failed to read {}: {e}", path.display()))
+        })
+    }
+
+    fn changed(&self) -> BoxFuture<'_, ()> {
+        Box::pin(self.changed.notified())
+    }
+}
+
+/// Lists the photos under the directory by their path relative to it, watching
+/// every directory on the way.
+fn scan(config: &DirectoryConfig, watcher: Option<&Watcher>) -> Result<Vec<SourcePhoto>> {
+    let mut photos = Vec::new();
+    let mut directories = vec![String::new()];
+    while let Some(directory) = directories.pop() {
+        let path = config.path.join(&directory);
+        if let Some(watcher) = watcher {
+            watcher.watch(&path, &directory);
+        }
+        let entries = match std::fs::read_dir(&path) {
+            Ok(entries) => entries,
+            // Without the top directory the album would look empty, better keep the pool
+            Err(e) if directory.is_empty() => {
+                return Err(anyhow!("Failed to read {}: {e}", path.display()));
+            }
+            Err(e) => {
+                println!("Skipping {}: {e}", path.display());
+                continue;
+            }
+        };
+
+        for entry in entries.flatten() {
+            let Ok(name) = entry.file_name().into_string() else {
+                println!("Skipping {}, its name isn't UTF-8", entry.path().display());
+                continue;
+            };
+            let relative = match directory.as_str() {
+                "" => name,
+                directory => format!("{directory}/{name}"),
+            };
+            if is_excluded(config, &relative) {
+                continue;
+            }
+            // Follows links to files, but not to directories that could loop
+            let Ok(file_type) = entry.file_type() else {
+                continue;
+            };
+            if file_type.is_dir() {
+                directories.push(relative);
+                continue;
+            }
+            if !is_included(config, &relative) {
+                continue;
+            }
+            let Ok(metadata) = std::fs::metadata(entry.path()) else {
+                continue;
+            };
+            if !metadata.is_file() {
+                continue;
+            }
+            let modified = metadata
+                .modified()
+                .ok()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .unwrap_or_default();
+            photos.push(SourcePhoto {
+                id: relative,
+                version: format!("{:x}-{:x}", metadata.len(), modified.as_nanos()),
+            });
+        }
+    }
+    photos.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(photos)
+}
+
+fn is_included(config: &DirectoryConfig, relative: &str) -> bool {
+    config
+        .include
+        .iter()
+        .any(|pattern| glob_matches(pattern, relative))
+}
+
+fn is_excluded(config: &DirectoryConfig, relative: &str) -> bool {
+    config
+        .exclude
+        .iter()
+        .any(|pattern| glob_matches(pattern, relative))
+}
+
+/// Matches a path relative to the directory against a glob, ignoring case.
+/// `*` and `?` stay within one path component and `**` matches any number of
+/// them. Patterns without a `/` match the last component in any directory.
+pub(crate) fn glob_matches(pattern: &str, relative: &str) -> bool {
+    if !pattern.contains('/') {
+        let name = relative.rsplit('/').next().unwrap_or(relative);
+        return component_matches(&chars(pattern), &chars(name));
+    }
+
+    let pattern: Vec<_> = pattern.trim_start_matches('/').split('/').collect();
+    let relative: Vec<_> = relative.split('/').collect();
+    components_match(&pattern, &relative)
+}
+
+fn components_match(pattern: &[&str], relative: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => relative.is_empty(),
+        Some((&"**", rest)) => {
+            (0..=relative.len()).any(|skip| components_match(rest, &relative[skip..]))
+        }
+        Some((first, rest)) => relative.split_first().is_some_and(|(name, relative)| {
+            component_matches(&chars(first), &chars(name)) && components_match(rest, relative)
+        }),
+    }
+}
+
+fn component_matches(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|skip| component_matches(rest, &name[skip..])),
+        Some(('?', rest)) => !name.is_empty() && component_matches(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && component_matches(rest, &name[1..]),
+    }
+}
+
+fn chars(text: &str) -> Vec<char> {
+    text.chars().flat_map(char::to_lowercase).collect()
+}
+
+/// Waits for changes to photos and signals `changed` once they settle.
+async fn watch(watcher: Arc<Watcher>, config: DirectoryConfig, changed: Arc<Notify>) {
+    let debounce = Duration::from_secs(config.debounce_secs);
+    loop {
+        match watcher.changes(&config).await {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(e) => {
+                println!("Stopped watching {}: {e}", config.path.display());
+                return;
+            }
+        }
+        // A sync writes many files in a row, refresh once it's done
+        loop {
+            match tokio::time::timeout(debounce, watcher.changes(&config)).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => {
+                    println!("Stopped watching {}: {e}", config.path.display());
+                    return;
+                }
+                Err(_) => break,
+            }
+        }
+        println!("Photos in {} changed", config.path.display());
+        changed.notify_one();
+    }
+}
+
+#[cfg(target_os = "linux")]
+use inotify::Watcher;
+
+#[cfg(target_os = "linux")]
+mod inotify {
+    use std::{
+        collections::HashMap,
+        ffi::CString,
+        io,
+        os::{
+            fd::{AsRawFd, FromRawFd, OwnedFd},
+            unix::ffi::OsStrExt,
+        },
+        path::Path,
+        sync::Mutex,
+    };
+
+    use tokio::io::unix::AsyncFd;
+
+    use super::{is_excluded, is_included};
+    use crate::config::DirectoryConfig;
+
+    const WATCH_MASK: u32 = libc::IN_CREATE
+        | libc::IN_DELETE
+        | libc::IN_CLOSE_WRITE
+        | libc::IN_MOVED_FROM
+        | libc::IN_MOVED_TO;
+    /// Size of `struct inotify_event` before the name.
+    const EVENT_HEADER_LEN: usize = 16;
+
+    pub struct Watcher {
+        fd: AsyncFd<OwnedFd>,
+        /// The directory of each watch, relative to the photo directory.
+        directories: Mutex<HashMap<i32, String>>,
+    }
+
+    impl Watcher {
+        pub fn new() -> io::Result<Self> {
+            // SAFETY: takes no pointers, the descriptor it returns is ours to own
+            let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: fd was just opened and nothing else holds it
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            Ok(Watcher {
+                fd: AsyncFd::new(fd)?,
+                directories: Mutex::new(HashMap::new()),
+            })
+        }
+
+        /// Watches the directory at `path`, known as `relative` in events.
+        /// Watching it again just updates its name.
+        pub fn watch(&self, path: &Path, relative: &str) {
+            let Ok(c_path) = CString::new(path.as_os_str().as_bytes()) else {
+                return;
+            };
+            // SAFETY: c_path is a valid C string for the duration of the call
+            let wd = unsafe {
+                libc::inotify_add_watch(self.fd.as_raw_fd(), c_path.as_ptr(), WATCH_MASK)
+            };
+            if wd < 0 {
+                println!(
+                    "Failed to watch {}: {}",
+                    path.display(),
+                    io::Error::last_os_error()
+                );
+                return;
+            }
+            self.directories
+                .lock()
+                .unwrap()
+                .insert(wd, relative.to_string());
+        }
+
+        /// Waits for the next batch of events and returns whether any of them
+        /// is about a photo or a directory that could hold some.
+        pub async fn changes(&self, config: &DirectoryConfig) -> io::Result<bool> {
+            let mut buffer = [0u8; 4096];
+            loop {
+                let mut guard = self.fd.readable().await?;
+                let read = guard.try_io(|fd| {
+                    // SAFETY: the buffer is valid for writes of its whole length
+                    let read = unsafe {
+                        libc::read(fd.as_raw_fd(), buffer.as_mut_ptr().cast(), buffer.len())
+                    };
+                    if read < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    Ok(read as usize)
+                });
+                if let Ok(read) = read {
+                    return Ok(self.any_relevant(&buffer[..read?], config));
+                }
+            }
+        }
+
+        fn any_relevant(&self, mut events: &[u8], config: &DirectoryConfig) -> bool {
+            let mut directories = self.directories.lock().unwrap();
+            let mut relevant = false;
+            while events.len() >= EVENT_HEADER_LEN {
+                let field =
+                    |i: usize| u32::from_ne_bytes(events[i * 4..i * 4 + 4].try_into().unwrap());
+                let (wd, mask) = (field(0) as i32, field(1));
+                let end = (EVENT_HEADER_LEN + field(3) as usize).min(events.len());
+                let name = &events[EVENT_HEADER_LEN..end];
+                let name = String::from_utf8_lossy(name);
+                let name = name.trim_end_matches('\0');
+                events = &events[end..];
+
+                if mask & libc::IN_Q_OVERFLOW != 0 {
+                    relevant = true;
+                    continue;
+                }
+                if mask & libc::IN_IGNORED != 0 {
+                    directories.remove(&wd);
+                    continue;
+                }
+                let Some(directory) = directories.get(&wd) else {
+                    continue;
+                };
+                let relative = match directory.as_str() {
+                    "" => name.to_string(),
+                    directory => format!("{directory}/{name}"),
+                };
+                relevant |= !is_excluded(config, &relative)
+                    && (mask & libc::IN_ISDIR != 0 || is_included(config, &relative));
+            }
+            relevant
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+struct Watcher;
+
+#[cfg(not(target_os = "linux"))]
+impl Watcher {
+    fn new() -> std::io::Result<Self> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "watching needs inotify, which is Linux only",
+        ))
+    }
+
+    fn watch(&self, _path: &Path, _relative: &str) {}
+
+    async fn changes(&self, _config: &DirectoryConfig) -> std::io::Result<bool> {
+        futures::future::pending().await
+    }
+}
@@ -0,0 +1,288 @@
+//! Photos linked from an RSS or Atom feed, or from a text file with one image
+//! url per line, such as a museum's art of the day.
+//!
+//! The feed and every image are fetched with `If-None-Match` once their ETag
+//! is known, so a refresh only downloads what changed. An image downloaded
+//! while listing is kept until it's processed, as long as the kept ones fit
+//! in [`MAX_KEPT_LEN`].
+
+use std::{collections::HashMap, sync::Mutex};
+
+use anyhow::{Result, anyhow};
+use futures::{StreamExt, future::BoxFuture};
+use reqwest::{Response, StatusCode, Url, header};
+
+use crate::{
+    config::FeedConfig,
+    remote,
+    source::{PhotoSource, SourcePhoto},
+    xml::{self, Element},
+};
+
+/// Images checked for changes at the same time.
+const CONCURRENT_CHECKS: usize = 4;
+/// Downloaded images waiting to be processed take at most this much memory,
+/// the rest are downloaded again when they're processed.
+const MAX_KEPT_LEN: usize = 512 * 1024 * 1024;
+
+pub struct FeedSource {
+    config: FeedConfig,
+    url: Url,
+    client: reqwest::Client,
+    /// The feed's image urls as of its ETag.
+    feed: Mutex<Option<(String, Vec<Url>)>>,
+    /// Every image in the feed, by url.
+    images: Mutex<HashMap<Url, CachedImage>>,
+}
+
+struct CachedImage {
+    version: String,
+    etag: Option<String>,
+    /// Downloaded while listing and not processed yet.
+    data: Option<Vec<u8>>,
+}
+
+impl FeedSource {
+    pub fn new(config: &FeedConfig) -> Result<Self> {
+        let url =
+            Url::parse(&config.url).map_err(|e| anyhow!("Invalid feed url {}: {e}", config.url))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(anyhow!("Feed url {} isn't http or https", config.url));
+        }
+
+        Ok(FeedSource {
+            config: config.clone(),
+            url,
+            client: remote::client()
+                .build()
+                .map_err(|e| anyhow!("Failed to create the feed client: {e}"))?,
+            feed: Mutex::new(None),
+            images: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Sends a `GET`, conditional on the ETag when there is one. Returns none
+    /// when nothing changed since.
+    async fn get(&self, url: &Url, etag: Option<&str>) -> Result<Option<Response>> {
+        let mut request = self.client.get(url.clone());
+        if let Some(etag) = etag {
+            request = request.header(header::IF_NONE_MATCH, etag);
+        }
+        let response = remote::send(request)
+            .await
+            .map_err(|e| anyhow!("Failed to fetch {url}: {e}"))?;
+        match response.status() {
+            StatusCode::NOT_MODIFIED if etag.is_some() => Ok(None),
+            status if status.is_success() => Ok(Some(response)),
+            status => Err(anyhow!("Fetching {url} failed with {status}")),
+        }
+    }
+
+    /// The image urls in the feed, newest first.
+    async fn image_urls(&self) -> Result<Vec<Url>> {
+        let etag = self
+            .feed
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|(etag, _)| etag.clone());
+        let Some(response) = self.get(&self.url, etag.as_deref()).await? else {
+            if let Some((_, urls)) = self.feed.lock().unwrap().as_ref() {
+                return Ok(urls.clone());
+            }
+            return Err(anyhow!("{} didn't send the feed", self.url));
+        };
+
+        let etag = etag_of(&response);
+        let body = response
+            .text()
+            .await
+            .map_err(|e| anyhow!("Failed to read the feed {}: {e}", self.url))?;
+        let mut urls =
+            parse_feed(&body, &self.url).map_err(|e| anyhow!("Invalid feed {}: {e}", self.url))?;
+        urls.truncate(self.config.limit);
+        *self.feed.lock().unwrap() = etag.map(|etag| (etag, urls.clone()));
+        Ok(urls)
+    }
+
+    /// Lists the image, downloading it unless it's unchanged since last time.
+    async fn check(&self, url: Url) -> Result<SourcePhoto> {
+        let etag = self
+            .images
+            .lock()
+            .unwrap()
+            .get(&url)
+            .and_then(|image| image.etag.clone());
+        let Some(response) = self.get(&url, etag.as_deref()).await? else {
+            if let Some(image) = self.images.lock().unwrap().get(&url) {
+                return Ok(SourcePhoto {
+                    id: url.to_string(),
+                    version: image.version.clone(),
+                });
+            }
+            return Err(anyhow!("{url} didn't send the image"));
+        };
+
+        let etag = etag_of(&response);
+        let data = remote::bytes(response).await?;
+        // Without an ETag the content has to tell whether it changed
+        let version = etag
+            .clone()
+            .unwrap_or_else(|| format!("{:08x}", crc32fast::hash(&data)));
+        let mut images = self.images.lock().unwrap();
+        let kept: usize = images
+            .iter()
+            .filter(|(kept, _)| **kept != url)
+            .filter_map(|(_, image)| image.data.as_ref().map(Vec::len))
+            .sum();
+        images.insert(
+            url.clone(),
+            CachedImage {
+                version: version.clone(),
+                etag,
+                data: Some(data).filter(|data| kept + data.len() <= MAX_KEPT_LEN),
+            },
+        );
+        Ok(SourcePhoto {
+            id: url.to_string(),
+            version,
+        })
+    }
+}
+
+impl PhotoSource for FeedSource {
+    fn name(&self) -> String {
+        self.url.to_string()
+    }
+
+    fn list(&self) -> BoxFuture<'_, Result<Vec<SourcePhoto>>> {
+        Box::pin(async move {
+            let urls = self.image_urls().await?;
+            let checked: Vec<_> = futures::stream::iter(urls.iter().cloned())
+                .map(|url| self.check(url))
+                .buffered(CONCURRENT_CHECKS)
+                .collect()
+                .await;
+            self.images
+                .lock()
+                .unwrap()
+                .retain(|url, _| urls.contains(url));
+
+            // One broken link shouldn't empty the album
+            let mut photos = Vec::new();
+            for photo in checked {
+                match photo {
+                    Ok(photo) => photos.push(photo),
+                    Err(e) => println!("Skipping an image of {}: {e:?}", self.url),
+                }
+            }
+            Ok(photos)
+        })
+    }
+
+    fn fetch<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<Vec<u8>>> {
+        Box::pin(async move {
+            let url = Url::parse(id).map_err(|e| anyhow!("Invalid image url {id}: {e}"))?;
+            let cached = self
+                .images
+                .lock()
+                .unwrap()
+                .get_mut(&url)
+                .and_then(|image| image.data.take());
+            if let Some(data) = cached {
+                return Ok(data);
+            }
+
+            let response = self
+                .get(&url, None)
+                .await?
+                .ok_or_else(|| anyhow!("{url} didn't send the image"))?;
+            remote::bytes(response).await
+        })
+    }
+}
+
+fn etag_of(response: &Response) -> Option<String> {
+    response
+        .headers()
+        .get(header::ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .map(String::from)
+}
+
+/// The image urls of an RSS or Atom feed, or of a list with one per line where
+/// `#` starts a comment. Relative urls are resolved against the feed's.
+fn parse_feed(body: &str, base: &Url) -> Result<Vec<Url>> {
+    let links: Vec<String> = if body.trim_start().starts_with('<') {
+        let root = xml::parse(body)?;
+        // RSS 2.0 keeps items in the channel, RSS 1.0 next to it and Atom calls them entries
+        let items = root
+            .child("channel")
+            .into_iter()
+            .flat_map(|channel| channel.children("item"))
+            .chain(root.children("item"))
+            .chain(root.children("entry"));
+        items.filter_map(item_image).collect()
+    } else {
+        body.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(String::from)
+            .collect()
+    };
+
+    let mut urls: Vec<Url> = Vec::new();
+    for link in links {
+        match base.join(&link) {
+            Ok(url) if !urls.contains(&url) => urls.push(url),
+            Ok(_) => {}
+            Err(e) => println!("Skipping image link {link} of {base}: {e}"),
+        }
+    }
+    Ok(urls)
+}
+
+/// The image of a feed item: an enclosure, Media RSS content or else the first
+/// picture in its text.
+fn item_image(item: &Element) -> Option<String> {
+    for child in &item.children {
+        let is_image = child
+            .attribute("type")
+            .is_none_or(|mime| mime.starts_with("image/"))
+            && child
+                .attribute("medium")
+                .is_none_or(|medium| medium == "image");
+        let link = match child.name.as_str() {
+            "enclosure" if is_image => child.attribute("url").map(String::from),
+            "link" if is_image && child.attribute("rel") == Some("enclosure") => {
+                child.attribute("href").map(String::from)
+            }
+            // Media RSS, Atom's own content has no url
+            "content" if is_image => child.attribute("url").map(String::from),
+            "group" => item_image(child),
+            _ => None,
+        };
+        if link.is_some() {
+            return link;
+        }
+    }
+
+    ["encoded", "description", "content", "summary"]
+        .iter()
+        .filter_map(|name| item.child(name))
+        .find_map(|text| first_img_src(&text.text))
+}
+
+/// The `src` of the first `<img>` in a piece of HTML.
+fn first_img_src(html: &str) -> Option<String> {
+    let tag = &html[html.find("<img")?..];
+    let tag = &tag[..tag.find('>')?];
+    // Not data-src and the like
+    let (start, _) = tag
+        .match_indices("src=")
+        .find(|(start, _)| tag[..*start].ends_with(char::is_whitespace))?;
+    let value = &tag[start + 4..];
+    let quote = value.chars().next().filter(|c| matches!(c, '"' | '\''))?;
+    let value = &value[1..];
+    Some(value[..value.find(quote)?].replace("&amp;", "&"))
+}
@@ -0,0 +1,379 @@
+use std::{
+    collections::{HashMap, HashSet, hash_map::DefaultHasher},
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    sync::{Mutex, RwLock},
+};
+
+use anyhow::{Result, anyhow};
+use futures::future::BoxFuture;
+
+use crate::{
+    app_data::{Checksums, ProccessedImage},
+    codec::Encoding,
+};
+
+/// Where processed frames are kept between being rendered and being sent.
+pub trait FrameStore: Send + Sync {
+    /// Returns the frame, or `None` when it was never stored or has been evicted.
+    fn get<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Option<ProccessedImage>>;
+    /// Whether [`FrameStore::get`] would return the frame, without loading it.
+    fn contains(&self, id: &str) -> bool;
+    fn insert<'a>(&'a self, id: &'a str, image: ProccessedImage) -> BoxFuture<'a, ()>;
+    fn remove<'a>(&'a self, id: &'a str) -> BoxFuture<'a, ()>;
+}
+
+/// Keeps every frame in memory.
+#[derive(Default)]
+pub struct MemoryStore {
+    images: RwLock<HashMap<String, ProccessedImage>>,
+}
+
+impl FrameStore for MemoryStore {
+    fn get<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Option<ProccessedImage>> {
+        Box::pin(futures::future::ready(
+            self.images.read().unwrap().get(id).cloned(),
+        ))
+    }
+
+    fn contains(&self, id: &str) -> bool {
+        self.images.read().unwrap().contains_key(id)
+    }
+
+    fn insert<'a>(&'a self, id: &'a str, image: ProccessedImage) -> BoxFuture<'a, ()> {
+        self.images.write().unwrap().insert(id.to_string(), image);
+        Box::pin(futures::future::ready(()))
+    }
+
+    fn remove<'a>(&'a self, id: &'a str) -> BoxFuture<'a, ()> {
+        self.images.write().unwrap().remove(id);
+        Box::pin(futures::future::ready(()))
+    }
+}
+
+/// Writes every frame to disk and keeps the most recently used ones in memory.
+///
+/// The files only outlive the pool they were written for until the next start:
+/// which version of a photo they hold isn't known then, so they're removed and
+/// the frames processed again.
+pub struct DiskStore {
+    directory: PathBuf,
+    /// Ids of the frames written to disk.
+    on_disk: Mutex<HashSet<String>>,
+    cache: Mutex<LruCache>,
+}
+
+impl DiskStore {
+    /// Opens the directory, removing frames left behind by an earlier run.
+    pub fn new(directory: PathBuf, memory_budget: usize) -> Result<Self> {
+        std::fs::create_dir_all(&directory)
+            .map_err(|e| anyhow!("Failed to create {}: {e:?}", directory.display()))?;
+        for entry in std::fs::read_dir(&directory)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "frame") {
+                std::fs::remove_file(path)?;
+            }
+        }
+
+        Ok(DiskStore {
+            directory,
+            on_disk: Mutex::new(HashSet::new()),
+            cache: Mutex::new(LruCache::new(memory_budget)),
+        })
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        // Ids of collages get too long for a file name
+        let mut hasher = DefaultHasher::new();
+        id.hash(&mut hasher);
+        self.directory
+            .join(format!("{:016x}.frame", hasher.finish()))
+    }
+}
+
+/// Runs file IO on the blocking pool, out of the way of the async tasks.
+async fn blocking<T: Send + 'static>(
+    io: impl FnOnce() -> std::io::Result<T> + Send + 'static,
+) -> std::io::Result<T> {
+    tokio::task::spawn_blocking(io)
+        .await
+        .unwrap_or_else(|e| Err(std::io::Error::other(e)))
+}
+
+fn read_frame(path: &Path) -> std::io::Result<ProccessedImage> {
+    let data = std::fs::read(path)?;
+    let invalid = || std::io::Error::from(std::io::ErrorKind::InvalidData);
+    let (header, panels) = data.split_at_checked(17).ok_or_else(invalid)?;
+    let encoding = Encoding::from_u8(header[0]).ok_or_else(invalid)?;
+    let field = |i: usize| u32::from_le_bytes(header[1 + i * 4..5 + i * 4].try_into().unwrap());
+    let (left, right) = panels
+        .split_at_checked(field(0) as usize)
+        .ok_or_else(invalid)?;
+    Ok(ProccessedImage {
+        encoding,
+        left: left.to_vec(),
+        right: right.to_vec(),
+        checksums: Checksums {
+            left: field(1),
+            right: field(2),
+            image: field(3),
+        },
+    })
+}
+
+fn write_frame(path: &Path, image: &ProccessedImage) -> std::io::Result<()> {
+    let mut data = Vec::with_capacity(17 + image.left.len() + image.right.len());
+    data.push(image.encoding as u8);
+    data.extend_from_slice(&(image.left.len() as u32).to_le_bytes());
+    data.extend_from_slice(&image.checksums.left.to_le_bytes());
+    data.extend_from_slice(&image.checksums.right.to_le_bytes());
+    data.extend_from_slice(&image.checksums.image.to_le_bytes());
+    data.extend_from_slice(&image.left);
+    data.extend_from_slice(&image.right);
+    std::fs::write(path, data)
+}
+
+impl FrameStore for DiskStore {
+    fn get<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Option<ProccessedImage>> {
+        Box::pin(async move {
+            if let Some(image) = self.cache.lock().unwrap().get(id) {
+                return Some(image);
+            }
+            if !self.on_disk.lock().unwrap().contains(id) {
+                return None;
+            }
+
+            let path = self.path(id);
+            let image = match blocking(move || read_frame(&path)).await {
+                Ok(image) => image,
+                Err(e) => {
+                    println!("Failed to read frame {id} from disk: {e:?}");
+                    return None;
+                }
+            };
+            self.cache.lock().unwrap().insert(id, image.clone());
+            Some(image)
+        })
+    }
+
+    fn contains(&self, id: &str) -> bool {
+        self.cache.lock().unwrap().contains(id) || self.on_disk.lock().unwrap().contains(id)
+    }
+
+    fn insert<'a>(&'a self, id: &'a str, image: ProccessedImage) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            self.cache.lock().unwrap().insert(id, image.clone());
+            let path = self.path(id);
+            match blocking(move || write_frame(&path, &image)).await {
+                Ok(()) => {
+                    self.on_disk.lock().unwrap().insert(id.to_string());
+                }
+                Err(e) => println!("Failed to store frame {id} on disk: {e:?}"),
+            }
+        })
+    }
+
+    fn remove<'a>(&'a self, id: &'a str) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            self.cache.lock().unwrap().remove(id);
+            if self.on_disk.lock().unwrap().remove(id) {
+                let path = self.path(id);
+                let _ = blocking(move || std::fs::remove_file(path)).await;
+            }
+        })
+    }
+}
+
+/// Keeps only the most recently used frames, the rest are processed again on request.
+pub struct LazyStore {
+    cache: Mutex<LruCache>,
+}
+
+impl LazyStore {
+    pub fn new(memory_budget: usize) -> Self {
+        LazyStore {
+            cache: Mutex::new(LruCache::new(memory_budget)),
+        }
+    }
+}
+
+impl FrameStore for LazyStore {
+    fn get<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Option<ProccessedImage>> {
+        Box::pin(futures::future::ready(self.cache.lock().unwrap().get(id)))
+    }
+
+    fn contains(&self, id: &str) -> bool {
+        self.cache.lock().unwrap().contains(id)
+    }
+
+    fn insert<'a>(&'a self, id: &'a str, image: ProccessedImage) -> BoxFuture<'a, ()> {
+        self.cache.lock().unwrap().insert(id, image);
+        Box::pin(futures::future::ready(()))
+    }
+
+    fn remove<'a>(&'a self, id: &'a str) -> BoxFuture<'a, ()> {
+        self.cache.lock().unwrap().remove(id);
+        Box::pin(futures::future::ready(()))
+    }
+}
+
+/// Frames by id, evicting the least recently used ones once their total size
+/// goes over the budget.
+struct LruCache {
+    budget: usize,
+    used: usize,
+    clock: u64,
+    entries: HashMap<String, (ProccessedImage, u64)>,
+}
+
+impl LruCache {
+    fn new(budget: usize) -> Self {
+        LruCache {
+            budget,
+            used: 0,
+            clock: 0,
+            entries: HashMap::new(),
+        }
+    }
+
+    fn get(&mut self, id: &str) -> Option<ProccessedImage> {
+        self.clock += 1;
+        let (image, last_used) = self.entries.get_mut(id)?;
+        *last_used = self.clock;
+        Some(image.clone())
+    }
+
+    fn contains(&self, id: &str) -> bool {
+        self.entries.contains_key(id)
+    }
+
+    fn insert(&mut self, id: &str, image: ProccessedImage) {
+        self.remove(id);
+        self.clock += 1;
+        self.used += size_of(&image);
+        self.entries.insert(id.to_string(), (image, self.clock));
+
+        while self.used > self.budget {
+            let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(id, _)| id.clone())
+            else {
+                break;
+            };
+            self.remove(&oldest);
+        }
+    }
+
+    fn remove(&mut self, id: &str) {
+        if let Some((image, _)) = self.entries.remove(id) {
+            self.used -= size_of(&image);
+        }
+    }
+}
+
+fn size_of(image: &ProccessedImage) -> usize {
+    image.left.len() + image.right.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A frame taking `len` bytes of budget, told apart by `seed`.
+    fn image(seed: u8, len: usize) -> ProccessedImage {
+        let left = vec![seed; len / 2];
+        let right = vec![seed.wrapping_add(1); len - len / 2];
+        ProccessedImage {
+            encoding: Encoding::Raw,
+            checksums: Checksums::new(&left, &right),
+            left,
+            right,
+        }
+    }
+
+    fn seed(image: &ProccessedImage) -> u8 {
+        image.left[0]
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_frames() {
+        let mut cache = LruCache::new(30);
+        cache.insert("a", image(1, 10));
+        cache.insert("b", image(2, 10));
+        cache.insert("c", image(3, 10));
+        assert!(cache.get("a").is_some());
+
+        cache.insert("d", image(4, 10));
+        assert!(!cache.contains("b"));
+        assert!(cache.contains("a") && cache.contains("c") && cache.contains("d"));
+
+        // Makes room for a bigger frame by dropping as many as needed
+        cache.insert("e", image(5, 25));
+        assert!(cache.contains("e") && !cache.contains("a") && !cache.contains("c"));
+        assert_eq!(cache.used, 25);
+    }
+
+    #[test]
+    fn replaces_frames_without_counting_them_twice() {
+        let mut cache = LruCache::new(30);
+        cache.insert("a", image(1, 20));
+        cache.insert("a", image(2, 20));
+        assert_eq!(cache.used, 20);
+        assert_eq!(seed(&cache.get("a").unwrap()), 2);
+
+        cache.remove("a");
+        assert_eq!(cache.used, 0);
+        // A frame over the whole budget isn't kept at all
+        cache.insert("b", image(3, 40));
+        assert!(!cache.contains("b"));
+        assert_eq!(cache.used, 0);
+    }
+
+    #[tokio::test]
+    async fn keeps_frames_in_memory() {
+        let store = MemoryStore::default();
+        store.insert("a", image(1, 1000)).await;
+        assert!(store.contains("a"));
+        assert_eq!(seed(&store.get("a").await.unwrap()), 1);
+        store.remove("a").await;
+        assert!(!store.contains("a") && store.get("a").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn keeps_recent_frames_lazily() {
+        let store = LazyStore::new(20);
+        store.insert("a", image(1, 10)).await;
+        store.insert("b", image(2, 10)).await;
+        store.insert("c", image(3, 10)).await;
+        assert!(store.get("a").await.is_none());
+        assert_eq!(seed(&store.get("c").await.unwrap()), 3);
+    }
+
+    #[tokio::test]
+    async fn reads_frames_back_from_disk() {
+        let directory = std::env::temp_dir().join(format!("frame-store-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("stale.frame"), b"old").unwrap();
+        std::fs::write(directory.join("notes.txt"), b"kept").unwrap();
+
+        // Without a memory budget every frame comes from its file
+        let store = DiskStore::new(directory.clone(), 0).unwrap();
+        assert!(!directory.join("stale.frame").exists());
+        assert!(directory.join("notes.txt").exists());
+
+        let original = image(7, 1001);
+        store.insert("a+b", original.clone()).await;
+        assert!(store.contains("a+b"));
+        let stored = store.get("a+b").await.unwrap();
+        assert_eq!(stored.encoding, original.encoding);
+        assert_eq!((stored.left, stored.right), (original.left, original.right));
+        assert_eq!(stored.checksums.image, original.checksums.image);
+
+        store.remove("a+b").await;
+        assert!(!store.contains("a+b") && store.get("a+b").await.is_none());
+        assert!(!store.path("a+b").exists());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,269 @@
+use image::{Rgb, RgbImage};
+
+use crate::config::Palette;
+
+/// Convex hull of the palette in OKLab, stored as planes `normal · x <= offset`.
+pub struct PaletteGamut {
+    black: [f32; 3],
+    white: [f32; 3],
+    planes: Vec<([f32; 3], f32)>,
+}
+
+impl PaletteGamut {
+    pub fn new(palette: &Palette) -> Self {
+        let points: Vec<[f32; 3]> = palette.colors().iter().map(srgb_to_oklab).collect();
+
+        // Every triangle with all other points on one side of it is a hull face
+        let mut planes = Vec::new();
+        for i in 0..points.len() {
+            for j in i + 1..points.len() {
+                for k in j + 1..points.len() {
+                    let normal = cross(sub(points[j], points[i]), sub(points[k], points[i]));
+                    if dot(normal, normal) < 1e-12 {
+                        continue;
+                    }
+                    let offset = dot(normal, points[i]);
+                    let sides: Vec<f32> = points.iter().map(|p| dot(normal, *p) - offset).collect();
+                    if sides.iter().all(|side| *side <= 1e-6) {
+                        planes.push((normal, offset));
+                    } else if sides.iter().all(|side| *side >= -1e-6) {
+                        planes.push((scale(normal, -1.0), -offset));
+                    }
+                }
+            }
+        }
+
+        PaletteGamut {
+            black: srgb_to_oklab(&Rgb(palette.black)),
+            white: srgb_to_oklab(&Rgb(palette.white)),
+            planes,
+        }
+    }
+
+    /// Maps the image into the palette's gamut.
+    ///
+    /// Lightness is scaled to the range between the palette's black and white,
+    /// then chroma is pulled towards the grey axis along a constant hue. Chroma
+    /// below `knee` times the gamut boundary is kept as is, above it is rolled
+    /// off smoothly so it approaches the boundary without ever crossing it.
+    pub fn map_image(&self, img: &mut RgbImage, knee: f32) {
+        // A flat palette has no inside to map into
+        if self.planes.len() < 4 {
+            return;
+        }
+
+        for pixel in img.pixels_mut() {
+            let [l, a, b] = srgb_to_oklab(pixel);
+
+            let t = l.clamp(0.0, 1.0);
+            let origin = add(self.black, scale(sub(self.white, self.black), t));
+            let offset = [0.0, a - origin[1], b - origin[2]];
+            let chroma = dot(offset, offset).sqrt();
+            if chroma < 1e-6 {
+                *pixel = oklab_to_srgb(origin);
+                continue;
+            }
+
+            let direction = scale(offset, 1.0 / chroma);
+            let limit = self.distance_to_boundary(origin, direction);
+            let start = limit * knee;
+            let mapped = if chroma <= start {
+                chroma
+            } else if limit - start < 1e-6 {
+                limit
+            } else {
+                start + (limit - start) * ((chroma - start) / (limit - start)).tanh()
+            };
+
+            *pixel = oklab_to_srgb(add(origin, scale(direction, mapped)));
+        }
+    }
+
+    fn distance_to_boundary(&self, origin: [f32; 3], direction: [f32; 3]) -> f32 {
+        self.planes
+            .iter()
+            .filter_map(|(normal, offset)| {
+                let speed = dot(*normal, direction);
+                (speed > 1e-9).then(|| (offset - dot(*normal, origin)) / speed)
+            })
+            .fold(f32::INFINITY, f32::min)
+            .max(0.0)
+    }
+}
+
+fn srgb_to_oklab(color: &Rgb<u8>) -> [f32; 3] {
+    let [r, g, b] = color.0.map(|c| {
+        let c = c as f32 / 255.0;
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    });
+
+    let l = (0.412_221_46 * r + 0.536_332_55 * g + 0.051_445_995 * b).cbrt();
+    let m = (0.211_903_5 * r + 0.680_699_5 * g + 0.107_396_96 * b).cbrt();
+    let s = (0.088_302_46 * r + 0.281_718_85 * g + 0.629_978_7 * b).cbrt();
+
+    [
+        0.210_454_26 * l + 0.793_617_8 * m - 0.004_072_047 * s,
+        1.977_998_5 * l - 2.428_592_2 * m + 0.450_593_7 * s,
+        0.025_904_037 * l + 0.782_771_77 * m - 0.808_675_77 * s,
+    ]
+}
+
+fn oklab_to_srgb([l, a, b]: [f32; 3]) -> Rgb<u8> {
+    let l_ = (l + 0.396_337_78 * a + 0.215_803_76 * b).powi(3);
+    let m_ = (l - 0.105_561_346 * a - 0.063_854_17 * b).powi(3);
+    let s_ = (l - 0.089_484_18 * a - 1.291_485_5 * b).powi(3);
+
+    let linear = [
+        4.076_741_7 * l_ - 3.307_711_6 * m_ + 0.230_969_94 * s_,
+        -1.268_438 * l_ + 2.609_757_4 * m_ - 0.341_319_38 * s_,
+        -0.004_196_086_3 * l_ - 0.703_418_6 * m_ + 1.707_614_7 * s_,
+    ];
+
+    Rgb(linear.map(|c| {
+        let c = c.clamp(0.0, 1.0);
+        let c = if c <= 0.003_130_8 {
+            c * 12.92
+        } else {
+            1.055 * c.powf(1.0 / 2.4) - 0.055
+        };
+        (c * 255.0).round() as u8
+    }))
+}
+
+fn add(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[0] + b[0], a[1] + b[1], a[2] + b[2]]
+}
+
+fn sub(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn scale(a: [f32; 3], factor: f32) -> [f32; 3] {
+    [a[0] * factor, a[1] * factor, a[2] * factor]
+}
+
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The panel's inks as they look, rather than as they're driven.
+    fn muted() -> Palette {
+        Palette {
+            black: [25, 30, 35],
+            white: [200, 200, 190],
+            yellow: [210, 190, 30],
+            red: [150, 40, 40],
+            blue: [40, 60, 120],
+            green: [50, 90, 60],
+        }
+    }
+
+    fn map(gamut: &PaletteGamut, colors: &[[u8; 3]], knee: f32) -> Vec<Rgb<u8>> {
+        let mut img = RgbImage::from_fn(colors.len() as u32, 1, |x, _| Rgb(colors[x as usize]));
+        gamut.map_image(&mut img, knee);
+        img.pixels().copied().collect()
+    }
+
+    fn hue(color: &Rgb<u8>) -> f32 {
+        let [_, a, b] = srgb_to_oklab(color);
+        b.atan2(a)
+    }
+
+    #[test]
+    fn keeps_colours_well_inside() {
+        let gamut = PaletteGamut::new(&Palette::default());
+        let colors = [
+            [128, 128, 128],
+            [150, 120, 110],
+            [90, 110, 100],
+            [200, 190, 120],
+            [120, 60, 60],
+        ];
+        for (color, mapped) in colors.iter().zip(map(&gamut, &colors, 0.8)) {
+            for (before, after) in color.iter().zip(mapped.0) {
+                assert!(before.abs_diff(after) <= 1, "{color:?} became {mapped:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn pulls_colours_into_the_hull() {
+        let colors = [
+            [0, 255, 255],
+            [255, 0, 255],
+            [255, 128, 0],
+            [0, 128, 255],
+            [255, 255, 255],
+            [0, 0, 0],
+        ];
+        // Mixing the primaries doesn't reach cyan or magenta
+        let gamut = PaletteGamut::new(&Palette::default());
+        for color in [[0, 255, 255], [255, 0, 255]] {
+            let point = srgb_to_oklab(&Rgb(color));
+            let outside = |(normal, offset): &([f32; 3], f32)| dot(*normal, point) > offset + 0.01;
+            assert!(gamut.planes.iter().any(outside), "{color:?}");
+        }
+
+        for palette in [Palette::default(), muted()] {
+            let gamut = PaletteGamut::new(&palette);
+            for knee in [0.0, 0.8, 1.0] {
+                for (color, mapped) in colors.iter().zip(map(&gamut, &colors, knee)) {
+                    let point = srgb_to_oklab(&mapped);
+                    for (normal, offset) in &gamut.planes {
+                        // A little room for rounding to whole sRGB values
+                        let outside = (dot(*normal, point) - offset) / dot(*normal, *normal).sqrt();
+                        assert!(
+                            outside < 0.01,
+                            "{color:?} became {mapped:?}, {outside} outside"
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_hue_and_greys() {
+        let gamut = PaletteGamut::new(&Palette::default());
+        let colors = [
+            [0, 255, 255],
+            [255, 0, 255],
+            [255, 128, 0],
+            [0, 128, 255],
+            [200, 40, 90],
+        ];
+        for (color, mapped) in colors.iter().zip(map(&gamut, &colors, 0.8)) {
+            let turned = (hue(&Rgb(*color)) - hue(&mapped)).abs();
+            assert!(
+                turned < 0.03,
+                "{color:?} became {mapped:?}, hue turned {turned}"
+            );
+        }
+
+        let greys: Vec<[u8; 3]> = (0..=255).step_by(15).map(|v| [v; 3]).collect();
+        for (grey, mapped) in greys.iter().zip(map(&gamut, &greys, 0.8)) {
+            let [r, g, b] = mapped.0;
+            assert!(
+                r.abs_diff(g) <= 1 && g.abs_diff(b) <= 1,
+                "{grey:?} became {mapped:?}"
+            );
+            assert!(r.abs_diff(grey[0]) <= 1, "{grey:?} became {mapped:?}");
+        }
+    }
+}
@@ -0,0 +1,372 @@
+//! Frames over HTTP, so they can sit behind ordinary reverse proxies.
+//!
+//! `GET /frames/{device}/next` answers with both panels back to back, left
+//! first, the device being the frame's MAC address. The frame lists the
+//! encodings it can decode in `Frame-Encodings`, e.g. `raw, lz`, and gets raw
+//! panels without it. The response headers describe the body:
+//!
+//! - `Frame-Encoding`: the encoding both panels are in.
+//! - `Frame-Panels`: the encoded lengths of the left and right panel.
+//! - `Frame-Checksums`: hex CRC-32 of the raw left panel, right panel and
+//!   whole image, as in the [`FrameHeader`](crate::protocol::FrameHeader).
+//!
+//! The ETag names the image and its encoding. A frame sending it as
+//! `If-None-Match` gets `304 Not Modified` when the next image is the one it
+//! already shows. A frame whose download broke off gets the rest of the same
+//! image with `Range: bytes=<received>-` and `If-Range: <etag>`, within
+//! [`RESUME_WINDOW`](crate::protocol::RESUME_WINDOW).
+//!
+//! Devices with a key never send it. Every answer to them carries a one time
+//! `Frame-Nonce`, and the next request is signed with
+//! `Authorization: Frame <hex HMAC-SHA256(key, nonce || device id)>`. A
+//! request without a valid signature gets `401 Unauthorized` with a fresh
+//! nonce to try again with. The panels themselves travel in the clear, put a
+//! reverse proxy doing TLS in front when that matters.
+
+use std::{convert::Infallible, net::SocketAddr, ops::Range, sync::Arc};
+
+use anyhow::anyhow;
+use axum::{
+    Router,
+    body::{Body, Bytes},
+    extract::{ConnectInfo, Path, State},
+    http::{HeaderMap, HeaderName, StatusCode, header},
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use futures::StreamExt;
+use tokio::net::TcpListener;
+
+use crate::{
+    app_data::{AppData, ProccessedImage},
+    auth,
+    codec::Encoding,
+    pick_photo,
+    pipeline::Pipeline,
+    protocol::DeviceId,
+    use_photo,
+};
+
+const FRAME_ENCODINGS: HeaderName = HeaderName::from_static("frame-encodings");
+const FRAME_ENCODING: HeaderName = HeaderName::from_static("frame-encoding");
+const FRAME_PANELS: HeaderName = HeaderName::from_static("frame-panels");
+const FRAME_CHECKSUMS: HeaderName = HeaderName::from_static("frame-checksums");
+const FRAME_NONCE: HeaderName = HeaderName::from_static("frame-nonce");
+
+struct HttpState {
+    app_data: Arc<AppData>,
+    pipeline: Arc<Pipeline>,
+}
+
+type HttpError = (StatusCode, anyhow::Error);
+
+/// Serves frames over HTTP on the listener until it fails.
+pub async fn serve(listener: TcpListener, app_data: Arc<AppData>, pipeline: Arc<Pipeline>) {
+    match listener.local_addr() {
+        Ok(address) => println!("Starting HTTP server on {address}..."),
+        Err(_) => println!("Starting HTTP server..."),
+    }
+    let router = Router::new()
+        .route("/frames/{device}/next", get(next_frame))
+        .with_state(Arc::new(HttpState { app_data, pipeline }));
+    let service = router.into_make_service_with_connect_info::<SocketAddr>();
+    if let Err(e) = axum::serve(listener, service).await {
+        println!("HTTP server failed: {e}");
+    }
+}
+
+async fn next_frame(
+    State(state): State<Arc<HttpState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Path(device): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    match frame_for(&state, peer, &device, &headers).await {
+        Ok(response) => {
+            println!("Answered {peer} over HTTP with {}", response.status());
+            response
+        }
+        Err((status, e)) => {
+            println!("Failed to send frame to {peer}: {e:?}");
+            (status, format!("{e}\n")).into_response()
+        }
+    }
+}
+
+async fn frame_for(
+    state: &HttpState,
+    peer: SocketAddr,
+    device: &str,
+    headers: &HeaderMap,
+) -> Result<Response, HttpError> {
+    let auth = &state.pipeline.config().auth;
+    auth::check_peer(auth, peer.ip()).map_err(|e| (StatusCode::FORBIDDEN, e))?;
+    let device = auth::parse_mac(device).ok_or_else(|| {
+        (
+            StatusCode::NOT_FOUND,
+            anyhow!("Device {device} isn't a MAC address"),
+        )
+    })?;
+    let key = auth::device_key(auth, &device).map_err(|e| (StatusCode::FORBIDDEN, e))?;
+    let Some(key) = key else {
+        return send_frame(state, peer, device, headers).await;
+    };
+
+    // Every answer carries the nonce for the next request, which only works once
+    let signed = is_signed(state, key, &device, headers);
+    let nonce = auth::new_challenge();
+    state.app_data.set_nonce(device, nonce);
+    let nonce = (FRAME_NONCE, hex(&nonce));
+    if !signed {
+        println!(
+            "Device {} didn't sign with its key and nonce",
+            auth::format_mac(&device)
+        );
+        return Ok((
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Frame".to_string()), nonce],
+            "Sign the request with the nonce\n",
+        )
+            .into_response());
+    }
+    let response = send_frame(state, peer, device, headers).await?;
+    Ok(([nonce], response).into_response())
+}
+
+/// Whether the request carries the device's signature of its current nonce.
+fn is_signed(state: &HttpState, key: &str, device: &DeviceId, headers: &HeaderMap) -> bool {
+    let Some(nonce) = state.app_data.take_nonce(device) else {
+        return false;
+    };
+    header_str(headers, &header::AUTHORIZATION)
+        .and_then(|value| value.strip_prefix("Frame "))
+        .and_then(parse_hex)
+        .is_some_and(|signature| auth::verify(key, &nonce, device, &signature).is_ok())
+}
+
+async fn send_frame(
+    state: &HttpState,
+    peer: SocketAddr,
+    device: DeviceId,
+    headers: &HeaderMap,
+) -> Result<Response, HttpError> {
+    state.app_data.check_in(device, peer.ip());
+
+    // A range continues the image the frame was downloading, while it's pinned
+    if let Some(range) = header_str(headers, &header::RANGE) {
+        let resumed = state.app_data.pinned_image(&device).filter(|photo| {
+            header_str(headers, &header::IF_RANGE).is_none_or(|tag| tag == etag(photo))
+        });
+        if let Some(photo) = resumed {
+            return Ok(partial_response(&state.app_data, device, &photo, range));
+        }
+    }
+
+    let pick = pick_photo(&state.app_data, &state.pipeline, Some(&device))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    let encoding = if accepted_encodings(headers).contains(&pick.photo.encoding) {
+        pick.photo.encoding
+    } else {
+        Encoding::Raw
+    };
+    let photo = pick
+        .photo
+        .clone()
+        .encoded(encoding)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+
+    // The frame keeps what it shows, and the pick stays queued or unused
+    let shown = header_str(headers, &header::IF_NONE_MATCH);
+    if shown.is_some_and(|tags| etag_matches(tags, &etag(&photo))) {
+        return Ok((StatusCode::NOT_MODIFIED, frame_headers(&photo)).into_response());
+    }
+
+    use_photo(&state.app_data, Some(&device), &pick);
+    state.app_data.record_image(device, pick.id);
+    state.app_data.pin_image(device, photo.clone());
+    let body = [&photo.left[..], &photo.right[..]].concat();
+    Ok(frame_response(&state.app_data, device, &photo, body))
+}
+
+/// The part of the panels the range asks for, or 416 when it's outside of them.
+fn partial_response(
+    app_data: &Arc<AppData>,
+    device: DeviceId,
+    photo: &ProccessedImage,
+    range: &str,
+) -> Response {
+    let total = photo.left.len() + photo.right.len();
+    let Some(range) = parse_range(range, total) else {
+        return (
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            [(header::CONTENT_RANGE, format!("bytes */{total}"))],
+        )
+            .into_response();
+    };
+
+    let content_range = format!("bytes {}-{}/{total}", range.start, range.end - 1);
+    let body = [&photo.left[..], &photo.right[..]].concat()[range].to_vec();
+    (
+        StatusCode::PARTIAL_CONTENT,
+        [(header::CONTENT_RANGE, content_range)],
+        frame_response(app_data, device, photo, body),
+    )
+        .into_response()
+}
+
+/// Sends `body`, all or part of the image's panels, and notes the image as
+/// delivered once all of it was handed to the connection.
+fn frame_response(
+    app_data: &Arc<AppData>,
+    device: DeviceId,
+    photo: &ProccessedImage,
+    body: Vec<u8>,
+) -> Response {
+    let len = body.len();
+    let app_data = Arc::clone(app_data);
+    let delivered = futures::stream::once(async move {
+        app_data.record_delivery(device);
+        Ok(Bytes::new())
+    });
+    let body =
+        futures::stream::once(async { Ok::<_, Infallible>(Bytes::from(body)) }).chain(delivered);
+    (
+        frame_headers(photo),
+        [(header::CONTENT_LENGTH, len.to_string())],
+        Body::from_stream(body),
+    )
+        .into_response()
+}
+
+/// Describes the image, whether its panels follow or not.
+fn frame_headers(photo: &ProccessedImage) -> [(HeaderName, String); 6] {
+    let checksums = photo.checksums;
+    [
+        (header::ETAG, etag(photo)),
+        (header::ACCEPT_RANGES, "bytes".to_string()),
+        // Each request may get a different image
+        (header::CACHE_CONTROL, "no-store".to_string()),
+        (FRAME_ENCODING, photo.encoding.name().to_string()),
+        (
+            FRAME_PANELS,
+            format!("{} {}", photo.left.len(), photo.right.len()),
+        ),
+        (
+            FRAME_CHECKSUMS,
+            format!(
+                "{:08x} {:08x} {:08x}",
+                checksums.left, checksums.right, checksums.image
+            ),
+        ),
+    ]
+}
+
+fn etag(photo: &ProccessedImage) -> String {
+    format!(
+        "\"{:08x}-{}\"",
+        photo.checksums.image,
+        photo.encoding.name()
+    )
+}
+
+/// Whether an `If-None-Match` list names the tag, weak or not.
+fn etag_matches(tags: &str, tag: &str) -> bool {
+    tags.split(',')
+        .map(|candidate| candidate.trim().trim_start_matches("W/"))
+        .any(|candidate| candidate == tag || candidate == "*")
+}
+
+fn accepted_encodings(headers: &HeaderMap) -> Vec<Encoding> {
+    header_str(headers, &FRAME_ENCODINGS)
+        .into_iter()
+        .flat_map(|names| names.split(','))
+        .filter_map(|name| Encoding::from_name(name.trim()))
+        .collect()
+}
+
+/// Parses a single `bytes=` range, `start-`, `start-end` or `-suffix`, over
+/// `len` bytes.
+fn parse_range(range: &str, len: usize) -> Option<Range<usize>> {
+    let (start, end) = range.strip_prefix("bytes=")?.split_once('-')?;
+    let range = match (start.trim(), end.trim()) {
+        ("", suffix) => len.saturating_sub(suffix.parse().ok()?)..len,
+        (start, "") => start.parse().ok()?..len,
+        (start, end) => start.parse().ok()?..end.parse::<usize>().ok()?.saturating_add(1).min(len),
+    };
+    (range.start < range.end).then_some(range)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn parse_hex<const N: usize>(text: &str) -> Option<[u8; N]> {
+    if text.len() != 2 * N {
+        return None;
+    }
+    let mut bytes = [0u8; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(text.get(2 * i..2 * i + 2)?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &HeaderName) -> Option<&'a str> {
+    headers.get(name)?.to_str().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_data::Checksums;
+
+    #[test]
+    fn parses_byte_ranges() {
+        assert_eq!(parse_range("bytes=0-", 100), Some(0..100));
+        assert_eq!(parse_range("bytes=40-", 100), Some(40..100));
+        assert_eq!(parse_range("bytes=10-19", 100), Some(10..20));
+        assert_eq!(parse_range("bytes=90-200", 100), Some(90..100));
+        assert_eq!(parse_range("bytes=-30", 100), Some(70..100));
+        assert_eq!(parse_range("bytes=-300", 100), Some(0..100));
+        assert_eq!(parse_range("bytes= 5 - 9 ", 100), Some(5..10));
+
+        assert_eq!(parse_range("bytes=100-", 100), None);
+        assert_eq!(parse_range("bytes=20-10", 100), None);
+        assert_eq!(parse_range("bytes=-0", 100), None);
+        assert_eq!(parse_range("bytes=a-", 100), None);
+        assert_eq!(parse_range("items=0-", 100), None);
+        assert_eq!(parse_range("bytes=0-0", 0), None);
+    }
+
+    #[test]
+    fn matches_etags() {
+        let photo = ProccessedImage {
+            encoding: Encoding::Lz,
+            checksums: Checksums::new(&[1, 2], &[3]),
+            ..Default::default()
+        };
+        let tag = etag(&photo);
+        assert_eq!(tag, format!("\"{:08x}-lz\"", photo.checksums.image));
+
+        assert!(etag_matches(&tag, &tag));
+        assert!(etag_matches(&format!("\"other\", W/{tag}"), &tag));
+        assert!(etag_matches("*", &tag));
+        assert!(!etag_matches("\"other\"", &tag));
+        // The same image in another encoding has other bytes
+        let raw = ProccessedImage {
+            encoding: Encoding::Raw,
+            ..photo
+        };
+        assert!(!etag_matches(&etag(&raw), &tag));
+    }
+
+    #[test]
+    fn parses_signatures() {
+        assert_eq!(parse_hex::<2>("0aff"), Some([0x0a, 0xff]));
+        assert_eq!(parse_hex::<2>("0af"), None);
+        assert_eq!(parse_hex::<2>("0aff00"), None);
+        assert_eq!(parse_hex::<2>("zzzz"), None);
+        assert_eq!(parse_hex::<1>("é"), None);
+    }
+}
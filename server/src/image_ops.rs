@@ -1,7 +1,27 @@
-use image::{ImageBuffer, Rgb, imageops::ColorMap};
+use std::sync::{Arc, Mutex};
 
-fn calculate_crop_cordinates(width: u32, height: u32) -> (u32, u32, u32, u32) {
-    let target_aspect = 4.0 / 3.0;
+use image::{ImageBuffer, Rgb, RgbImage, imageops::ColorMap};
+
+use crate::{
+    config::{
+        CollageTemplate, Dither, Fit, FrameConfig, Orientation, Palette, ProcessingConfig, ToneOp,
+    },
+    gamut::PaletteGamut,
+};
+
+/// Colours the panel encodes, in the order `Palette::colors` lists them.
+const PANEL_COLORS: [Rgb<u8>; 6] = [
+    image::Rgb([0, 0, 0]),
+    image::Rgb([255, 0, 0]),
+    image::Rgb([0, 255, 0]),
+    image::Rgb([0, 0, 255]),
+    image::Rgb([255, 255, 0]),
+    image::Rgb([255, 255, 255]),
+];
+
+const WHITE: Rgb<u8> = image::Rgb([255, 255, 255]);
+
+fn calculate_crop_cordinates(width: u32, height: u32, target_aspect: f32) -> (u32, u32, u32, u32) {
     let current_aspect = width as f32 / height as f32;
 
     if current_aspect > target_aspect {
@@ -17,43 +37,404 @@ fn calculate_crop_cordinates(width: u32, height: u32) -> (u32, u32, u32, u32) {
     }
 }
 
-pub fn process_image(image: Vec<u8>) -> Result<ImageBuffer<Rgb<u8>, Vec<u8>>, image::ImageError> {
-    let mut img = image::load_from_memory(&image)?;
+pub fn process_image(
+    image: &[u8],
+    frame: &FrameConfig,
+    processing: &ProcessingConfig,
+) -> Result<ImageBuffer<Rgb<u8>, Vec<u8>>, image::ImageError> {
+    let orientation = frame.orientation;
+    let mut img = image::load_from_memory(image)?;
 
-    // Make sure it in landscape orientation
-    if img.height() > img.width() {
+    // Make sure it matches the orientation of the frame
+    if (img.height() > img.width()) != orientation.is_portrait() {
         img = img.rotate90();
     }
-    let mut orig_img = img.to_rgb8();
 
-    let (x, y, width, height) = calculate_crop_cordinates(img.width(), img.height());
-    let img = image::imageops::crop(&mut orig_img, x, y, width, height).to_image();
-    let mut img = image::imageops::resize(&img, 1600, 1200, image::imageops::FilterType::Lanczos3);
+    let (width, height) = orientation.canvas_size();
+    let mut img = fit_image(img.to_rgb8(), width, height, processing.fit, WHITE);
+    apply_tone(&mut img, &processing.tone);
+    map_gamut(&mut img, &frame.palette, processing);
+    dither_image(&mut img, &frame.palette, processing.dither);
+    Ok(rotate_to_panel(img, orientation))
+}
+
+/// Composes one image per template cell onto a single canvas and dithers the result once.
+pub fn process_collage(
+    images: &[Vec<u8>],
+    template: &CollageTemplate,
+    frame: &FrameConfig,
+    processing: &ProcessingConfig,
+) -> Result<ImageBuffer<Rgb<u8>, Vec<u8>>, image::ImageError> {
+    let orientation = frame.orientation;
+    let (canvas_width, canvas_height) = orientation.canvas_size();
+    let mut canvas =
+        ImageBuffer::from_pixel(canvas_width, canvas_height, image::Rgb(template.background));
+
+    let layout = collage_layout(template, canvas_width, canvas_height);
+    for ((x, y, width, height), image) in layout.into_iter().zip(images) {
+        let img = image::load_from_memory(image)?.to_rgb8();
+        let background = image::Rgb(template.background);
+        let mut img = fit_image(img, width, height, processing.fit, background);
+        apply_tone(&mut img, &processing.tone);
+        image::imageops::replace(&mut canvas, &img, x as i64, y as i64);
+    }
+
+    map_gamut(&mut canvas, &frame.palette, processing);
+    dither_image(&mut canvas, &frame.palette, processing.dither);
+    Ok(rotate_to_panel(canvas, orientation))
+}
+
+/// Where each cell of the template goes on the canvas, as x, y, width and height.
+fn collage_layout(
+    template: &CollageTemplate,
+    canvas_width: u32,
+    canvas_height: u32,
+) -> Vec<(u32, u32, u32, u32)> {
+    let gap = template.gap;
+    let unit_width = (canvas_width - gap * (template.columns + 1)) / template.columns;
+    let unit_height = (canvas_height - gap * (template.rows + 1)) / template.rows;
+    template
+        .cells
+        .iter()
+        .map(|cell| {
+            (
+                gap + cell.x * (unit_width + gap),
+                gap + cell.y * (unit_height + gap),
+                cell.width * unit_width + (cell.width - 1) * gap,
+                cell.height * unit_height + (cell.height - 1) * gap,
+            )
+        })
+        .collect()
+}
+
+/// Rotates the canvas into the 1200x1600 order the panel memory expects.
+fn rotate_to_panel(
+    img: ImageBuffer<Rgb<u8>, Vec<u8>>,
+    orientation: Orientation,
+) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+    match orientation {
+        Orientation::Landscape => image::imageops::rotate270(&img),
+        Orientation::LandscapeFlipped => image::imageops::rotate90(&img),
+        Orientation::Portrait => img,
+        Orientation::PortraitFlipped => image::imageops::rotate180(&img),
+    }
+}
+
+/// Undoes [`rotate_to_panel`], turning panel memory back into the picture as mounted.
+pub fn rotate_from_panel(
+    img: ImageBuffer<Rgb<u8>, Vec<u8>>,
+    orientation: Orientation,
+) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+    match orientation {
+        Orientation::Landscape => image::imageops::rotate90(&img),
+        Orientation::LandscapeFlipped => image::imageops::rotate270(&img),
+        Orientation::Portrait => img,
+        Orientation::PortraitFlipped => image::imageops::rotate180(&img),
+    }
+}
+
+/// Scales the image to the target size, center cropping it to the target aspect
+/// ratio or centering it on the background.
+fn fit_image(
+    mut img: ImageBuffer<Rgb<u8>, Vec<u8>>,
+    width: u32,
+    height: u32,
+    fit: Fit,
+    background: Rgb<u8>,
+) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+    let filter = image::imageops::FilterType::Lanczos3;
+    match fit {
+        Fit::Crop => {
+            let (x, y, crop_width, crop_height) =
+                calculate_crop_cordinates(img.width(), img.height(), width as f32 / height as f32);
+            let img = image::imageops::crop(&mut img, x, y, crop_width, crop_height).to_image();
+            image::imageops::resize(&img, width, height, filter)
+        }
+        Fit::Contain => {
+            let scale = f32::min(
+                width as f32 / img.width() as f32,
+                height as f32 / img.height() as f32,
+            );
+            let scaled_width = ((img.width() as f32 * scale).round() as u32).clamp(1, width);
+            let scaled_height = ((img.height() as f32 * scale).round() as u32).clamp(1, height);
+            let img = image::imageops::resize(&img, scaled_width, scaled_height, filter);
+            let mut canvas = ImageBuffer::from_pixel(width, height, background);
+            let x = (width - scaled_width) / 2;
+            let y = (height - scaled_height) / 2;
+            image::imageops::replace(&mut canvas, &img, x as i64, y as i64);
+            canvas
+        }
+    }
+}
+
+/// Runs the tone adjustments in order over the image.
+fn apply_tone(img: &mut RgbImage, ops: &[ToneOp]) {
+    for op in ops {
+        match *op {
+            ToneOp::AutoLevels { clip } => auto_levels(img, clip),
+            ToneOp::Gamma { value } => apply_curve(img, |v| v.powf(1.0 / value)),
+            ToneOp::Contrast { amount } => apply_curve(img, |v| (v - 0.5) * amount + 0.5),
+            ToneOp::Saturation { amount } => saturate(img, amount),
+            // Peaks at a third of the range and leaves both ends untouched
+            ToneOp::ShadowLift { amount } => {
+                apply_curve(img, |v| v + amount * 6.75 * v * (1.0 - v) * (1.0 - v))
+            }
+            ToneOp::Sharpen { sigma, threshold } => {
+                *img = image::imageops::unsharpen(img, sigma, threshold)
+            }
+            ToneOp::Clahe { tiles, clip_limit } => clahe(img, tiles, clip_limit),
+        }
+    }
+}
+
+/// Maps every channel through a curve working on values between 0 and 1.
+fn apply_curve(img: &mut RgbImage, curve: impl Fn(f32) -> f32) {
+    let lut: [u8; 256] = std::array::from_fn(|i| to_channel(curve(i as f32 / 255.0) * 255.0));
+    for channel in img.iter_mut() {
+        *channel = lut[*channel as usize];
+    }
+}
+
+fn auto_levels(img: &mut RgbImage, clip: f32) {
+    let mut histogram = [0u32; 256];
+    for pixel in img.pixels() {
+        histogram[luminance(pixel).round() as usize] += 1;
+    }
+
+    let skip = (img.width() as f32 * img.height() as f32 * clip) as u32;
+    let mut seen = 0;
+    let low = histogram
+        .iter()
+        .position(|count| {
+            seen += count;
+            seen > skip
+        })
+        .unwrap_or(0);
+    seen = 0;
+    let high = 255
+        - histogram
+            .iter()
+            .rev()
+            .position(|count| {
+                seen += count;
+                seen > skip
+            })
+            .unwrap_or(0);
+    if high <= low {
+        return;
+    }
+
+    let (low, range) = (low as f32 / 255.0, (high - low) as f32 / 255.0);
+    apply_curve(img, |v| (v - low) / range);
+}
+
+fn saturate(img: &mut RgbImage, amount: f32) {
+    for pixel in img.pixels_mut() {
+        let luma = luminance(pixel);
+        for channel in pixel.0.iter_mut() {
+            *channel = to_channel(luma + (*channel as f32 - luma) * amount);
+        }
+    }
+}
+
+/// Equalizes the luminance per tile with a clipped histogram, blending between
+/// neighbouring tiles so their edges don't show. The luminance change is added
+/// to every channel to leave the colours alone.
+fn clahe(img: &mut RgbImage, tiles: u32, clip_limit: f32) {
+    let (width, height) = img.dimensions();
+    let tile_width = width.div_ceil(tiles.clamp(1, width));
+    let tile_height = height.div_ceil(tiles.clamp(1, height));
+    let tiles_x = width.div_ceil(tile_width);
+    let tiles_y = height.div_ceil(tile_height);
+
+    let luma: Vec<u8> = img.pixels().map(|p| luminance(p).round() as u8).collect();
+
+    let mut maps = Vec::with_capacity((tiles_x * tiles_y) as usize);
+    for tile_y in 0..tiles_y {
+        for tile_x in 0..tiles_x {
+            let mut histogram = [0u32; 256];
+            let rows = tile_y * tile_height..((tile_y + 1) * tile_height).min(height);
+            let columns = tile_x * tile_width..((tile_x + 1) * tile_width).min(width);
+            for y in rows.clone() {
+                for x in columns.clone() {
+                    histogram[luma[(y * width + x) as usize] as usize] += 1;
+                }
+            }
+            let pixels = rows.len() as u32 * columns.len() as u32;
+
+            // Spread whatever is above the limit evenly over all bins
+            let limit = ((clip_limit * pixels as f32 / 256.0) as u32).max(1);
+            let mut excess = 0;
+            for count in histogram.iter_mut() {
+                excess += count.saturating_sub(limit);
+                *count = (*count).min(limit);
+            }
+            for (i, count) in histogram.iter_mut().enumerate() {
+                *count += excess / 256 + u32::from((i as u32) < excess % 256);
+            }
+
+            let mut cdf = 0;
+            let map: [u8; 256] = std::array::from_fn(|i| {
+                cdf += histogram[i];
+                to_channel(cdf as f32 * 255.0 / pixels as f32)
+            });
+            maps.push(map);
+        }
+    }
+
+    // Position between the centres of the two closest tiles along one axis
+    let neighbours = |position: u32, tile_size: u32, tiles: u32| {
+        let center = ((position as f32 + 0.5) / tile_size as f32 - 0.5).max(0.0);
+        let first = (center as u32).min(tiles - 1);
+        let second = (first + 1).min(tiles - 1);
+        (first, second, (center - first as f32).min(1.0))
+    };
+
+    for y in 0..height {
+        let (top, bottom, weight_y) = neighbours(y, tile_height, tiles_y);
+        for x in 0..width {
+            let (left, right, weight_x) = neighbours(x, tile_width, tiles_x);
+            let value = luma[(y * width + x) as usize] as usize;
+            let mapped =
+                |tile_x: u32, tile_y: u32| maps[(tile_y * tiles_x + tile_x) as usize][value] as f32;
+
+            let upper = mapped(left, top) * (1.0 - weight_x) + mapped(right, top) * weight_x;
+            let lower = mapped(left, bottom) * (1.0 - weight_x) + mapped(right, bottom) * weight_x;
+            let delta = upper * (1.0 - weight_y) + lower * weight_y - value as f32;
+
+            let pixel = img.get_pixel_mut(x, y);
+            for channel in pixel.0.iter_mut() {
+                *channel = to_channel(*channel as f32 + delta);
+            }
+        }
+    }
+}
+
+fn luminance(pixel: &Rgb<u8>) -> f32 {
+    0.299 * pixel[0] as f32 + 0.587 * pixel[1] as f32 + 0.114 * pixel[2] as f32
+}
+
+fn to_channel(value: f32) -> u8 {
+    value.round().clamp(0.0, 255.0) as u8
+}
+
+fn map_gamut(img: &mut RgbImage, palette: &Palette, processing: &ProcessingConfig) {
+    if let Some(gamut) = &processing.gamut {
+        PaletteGamut::new(palette).map_image(img, gamut.knee);
+    }
+}
+
+/// Reduces the image to the palette and stores it in the colours the panel encodes.
+pub fn dither_image(img: &mut RgbImage, palette: &Palette, dither: Dither) {
+    dither_with(img, &Epd13in3ColorMap::for_palette(palette), dither);
+}
 
+/// Like [`dither_image`], but searches the palette for every pixel instead of
+/// using a lookup table. The baseline the table is benchmarked against.
+pub fn dither_image_exact(img: &mut RgbImage, palette: &Palette, dither: Dither) {
     let color_map = Epd13in3ColorMap {
-        colors: vec![
-            image::Rgb([0, 0, 0]),
-            image::Rgb([255, 0, 0]),
-            image::Rgb([0, 255, 0]),
-            image::Rgb([0, 0, 255]),
-            image::Rgb([255, 255, 0]),
-            image::Rgb([255, 255, 255]),
-        ],
+        colors: palette.colors(),
+        lut: vec![LUT_AMBIGUOUS; 1 << (3 * LUT_BITS)],
     };
+    dither_with(img, &color_map, dither);
+}
+
+fn dither_with(img: &mut RgbImage, color_map: &Epd13in3ColorMap, dither: Dither) {
+    match dither {
+        Dither::FloydSteinberg => image::imageops::dither(img, color_map),
+        Dither::None => {
+            for pixel in img.pixels_mut() {
+                color_map.map_color(pixel);
+            }
+        }
+    }
 
-    image::imageops::dither(&mut img, &color_map);
-    // Placeholder implementation
-    Ok(img)
+    // Diffusion works on the colours the inks show, the panel wants its own
+    if color_map.colors != PANEL_COLORS {
+        for pixel in img.pixels_mut() {
+            *pixel = PANEL_COLORS[color_map.index_of(pixel)];
+        }
+    }
 }
 
+/// Bits of each channel used to address the lookup cube, 64 cells per axis.
+const LUT_BITS: u32 = 6;
+const LUT_SHIFT: u32 = 8 - LUT_BITS;
+/// Marks cells that straddle the border between palette colours.
+const LUT_AMBIGUOUS: u8 = u8::MAX;
+
+type Colors = [Rgb<u8>; 6];
+
+/// Lookup tables kept for the palettes used last, most recent first. Every
+/// palette tried in a preview gets one, so older ones are dropped.
+static COLOR_MAPS: Mutex<Vec<Arc<Epd13in3ColorMap>>> = Mutex::new(Vec::new());
+const MAX_COLOR_MAPS: usize = 4;
+
 struct Epd13in3ColorMap {
-    colors: Vec<Rgb<u8>>,
+    colors: Colors,
+    /// Palette index for every cell of the RGB cube whose colours all map to it.
+    lut: Vec<u8>,
 }
 
-impl ColorMap for Epd13in3ColorMap {
-    type Color = image::Rgb<u8>;
+impl Epd13in3ColorMap {
+    /// Returns the color map for the palette, building its lookup table on first use.
+    fn for_palette(palette: &Palette) -> Arc<Self> {
+        let colors = palette.colors();
+        let mut color_maps = COLOR_MAPS.lock().unwrap();
+        let color_map = match color_maps.iter().position(|map| map.colors == colors) {
+            Some(index) => color_maps.remove(index),
+            None => Arc::new(Self::new(colors)),
+        };
+        color_maps.insert(0, Arc::clone(&color_map));
+        color_maps.truncate(MAX_COLOR_MAPS);
+        color_map
+    }
 
-    fn index_of(&self, pixel: &Self::Color) -> usize {
+    fn new(colors: Colors) -> Self {
+        let mut color_map = Epd13in3ColorMap {
+            colors,
+            lut: Vec::new(),
+        };
+
+        // Lowest and highest value of every cell along one axis
+        let cell_size = 1 << LUT_SHIFT;
+        let bounds: Vec<u8> = (0..1u32 << LUT_BITS)
+            .flat_map(|cell| [cell * cell_size, cell * cell_size + cell_size - 1])
+            .map(|value| value as u8)
+            .collect();
+
+        let mut corners = Vec::with_capacity(bounds.len().pow(3));
+        for r in &bounds {
+            for g in &bounds {
+                for b in &bounds {
+                    corners.push(color_map.nearest(&image::Rgb([*r, *g, *b])) as u8);
+                }
+            }
+        }
+
+        // Nearest colour regions are convex, so a cell whose corners all agree is
+        // entirely inside one of them
+        let cells = 1usize << LUT_BITS;
+        let corner =
+            |r: usize, g: usize, b: usize| corners[(r * bounds.len() + g) * bounds.len() + b];
+        let mut lut = Vec::with_capacity(cells.pow(3));
+        for r in 0..cells {
+            for g in 0..cells {
+                for b in 0..cells {
+                    let first = corner(2 * r, 2 * g, 2 * b);
+                    let uniform = (0..8).all(|i| {
+                        corner(2 * r + (i & 1), 2 * g + (i >> 1 & 1), 2 * b + (i >> 2)) == first
+                    });
+                    lut.push(if uniform { first } else { LUT_AMBIGUOUS });
+                }
+            }
+        }
+
+        color_map.lut = lut;
+        color_map
+    }
+
+    fn nearest(&self, pixel: &Rgb<u8>) -> usize {
         self.colors
             .iter()
             .enumerate()
@@ -61,10 +442,22 @@ impl ColorMap for Epd13in3ColorMap {
             .map(|(i, _)| i)
             .unwrap()
     }
+}
+
+impl ColorMap for Epd13in3ColorMap {
+    type Color = image::Rgb<u8>;
+
+    fn index_of(&self, pixel: &Self::Color) -> usize {
+        let [r, g, b] = pixel.0.map(|c| (c >> LUT_SHIFT) as usize);
+        match self.lut[(r << LUT_BITS | g) << LUT_BITS | b] {
+            LUT_AMBIGUOUS => self.nearest(pixel),
+            index => index as usize,
+        }
+    }
 
     fn map_color(&self, color: &mut Self::Color) {
         let index = self.index_of(color);
-        *color = self.colors[index].clone()
+        *color = self.colors[index]
     }
 }
 
@@ -74,3 +467,188 @@ fn color_distance(a: &Rgb<u8>, b: &Rgb<u8>) -> u32 {
     let db = a[2] as i32 - b[2] as i32;
     (dr * dr + dg * dg + db * db) as u32
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn template(toml: &str) -> CollageTemplate {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn looks_up_the_nearest_colour() {
+        let muted = Palette {
+            black: [25, 30, 35],
+            white: [200, 200, 190],
+            yellow: [210, 190, 30],
+            red: [150, 40, 40],
+            blue: [40, 60, 120],
+            green: [50, 90, 60],
+        };
+        for palette in [Palette::default(), muted] {
+            let color_map = Epd13in3ColorMap::new(palette.colors());
+            for r in (0..=255).step_by(3) {
+                for g in (0..=255).step_by(3) {
+                    for b in (0..=255).step_by(3) {
+                        let pixel = Rgb([r, g, b]);
+                        let nearest = palette
+                            .colors()
+                            .iter()
+                            .map(|color| color_distance(&pixel, color))
+                            .min()
+                            .unwrap();
+                        let index = color_map.index_of(&pixel);
+                        assert_eq!(
+                            color_distance(&pixel, &palette.colors()[index]),
+                            nearest,
+                            "{pixel:?}"
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_tables_for_the_recent_palettes() {
+        let palettes: Vec<Palette> = (0..=MAX_COLOR_MAPS as u8)
+            .map(|grey| Palette {
+                black: [grey; 3],
+                ..Palette::default()
+            })
+            .collect();
+        let first = Epd13in3ColorMap::for_palette(&palettes[0]);
+        assert!(Arc::ptr_eq(
+            &first,
+            &Epd13in3ColorMap::for_palette(&palettes[0])
+        ));
+
+        for palette in &palettes[1..] {
+            Epd13in3ColorMap::for_palette(palette);
+        }
+        let color_maps = COLOR_MAPS.lock().unwrap();
+        assert!(color_maps.len() <= MAX_COLOR_MAPS);
+        assert!(!color_maps.iter().any(|map| Arc::ptr_eq(map, &first)));
+    }
+
+    #[test]
+    fn lays_out_collage_cells_between_gaps() {
+        let mosaic = template(
+            r#"
+            name = "mosaic"
+            columns = 3
+            rows = 2
+            gap = 10
+            cells = [{ x = 0, y = 0, width = 2, height = 2 }, { x = 2, y = 0 }, { x = 2, y = 1 }]
+            "#,
+        );
+        // Units of (1600 - 40) / 3 = 520 by (1200 - 30) / 2 = 585
+        assert_eq!(
+            collage_layout(&mosaic, 1600, 1200),
+            [
+                (10, 10, 1050, 1180),
+                (1070, 10, 520, 585),
+                (1070, 605, 520, 585)
+            ]
+        );
+        // Units of (1200 - 40) / 3 = 386 by (1600 - 30) / 2 = 785
+        assert_eq!(
+            collage_layout(&mosaic, 1200, 1600),
+            [
+                (10, 10, 782, 1580),
+                (802, 10, 386, 785),
+                (802, 805, 386, 785)
+            ]
+        );
+    }
+
+    /// A row of grey pixels.
+    fn grey(values: &[u8]) -> RgbImage {
+        RgbImage::from_fn(values.len() as u32, 1, |x, _| {
+            let value = values[x as usize];
+            Rgb([value, value, value])
+        })
+    }
+
+    fn tone(values: &[u8], op: ToneOp) -> Vec<u8> {
+        let mut img = grey(values);
+        apply_tone(&mut img, &[op]);
+        img.pixels()
+            .map(|pixel| {
+                assert!(
+                    pixel[0] == pixel[1] && pixel[1] == pixel[2],
+                    "{pixel:?} isn't grey"
+                );
+                pixel[0]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn stretches_levels_past_the_clipped_pixels() {
+        let values = [0, 64, 64, 96, 96, 160, 160, 192, 192, 255];
+        // 192 - 64 = 128 levels become 255
+        assert_eq!(
+            tone(&values, ToneOp::AutoLevels { clip: 0.1 }),
+            [0, 0, 0, 64, 64, 191, 191, 255, 255, 255]
+        );
+        assert_eq!(tone(&values, ToneOp::AutoLevels { clip: 0.0 }), values);
+        assert_eq!(tone(&[80; 4], ToneOp::AutoLevels { clip: 0.0 }), [80; 4]);
+    }
+
+    #[test]
+    fn applies_curves() {
+        let values = [0, 64, 85, 128, 255];
+        assert_eq!(
+            tone(&values, ToneOp::Gamma { value: 2.0 }),
+            [0, 128, 147, 181, 255]
+        );
+        assert_eq!(
+            tone(&values, ToneOp::Contrast { amount: 1.5 }),
+            [0, 32, 64, 128, 255]
+        );
+        // Lifts a third of the range by amount
+        assert_eq!(
+            tone(&values, ToneOp::ShadowLift { amount: 0.2 }),
+            [0, 112, 136, 171, 255]
+        );
+    }
+
+    #[test]
+    fn scales_saturation_around_the_luminance() {
+        let mut img = RgbImage::from_pixel(1, 1, Rgb([255, 0, 0]));
+        apply_tone(&mut img, &[ToneOp::Saturation { amount: 0.5 }]);
+        assert_eq!(img.get_pixel(0, 0), &Rgb([166, 38, 38]));
+        apply_tone(&mut img, &[ToneOp::Saturation { amount: 0.0 }]);
+        assert_eq!(img.get_pixel(0, 0), &Rgb([76, 76, 76]));
+    }
+
+    #[test]
+    fn sharpens_edges_only() {
+        let values = [50, 50, 50, 50, 200, 200, 200, 200];
+        let op = ToneOp::Sharpen {
+            sigma: 1.0,
+            threshold: 0,
+        };
+        // Overshoots on both sides of the edge
+        assert_eq!(
+            tone(&values, op.clone()),
+            [50, 50, 42, 5, 245, 208, 200, 200]
+        );
+        assert_eq!(tone(&[120; 8], op), [120; 8]);
+    }
+
+    #[test]
+    fn blends_clahe_between_tile_centres() {
+        // Two tiles of four pixels, the left one maps 50 to white and the
+        // right one to black
+        let values = [50, 50, 50, 50, 200, 200, 200, 200];
+        let op = ToneOp::Clahe {
+            tiles: 2,
+            clip_limit: 256.0,
+        };
+        // Pixels past the centre of the left tile get 7/8 and 5/8 of its map
+        assert_eq!(tone(&values, op), [255, 255, 223, 159, 255, 255, 255, 255]);
+    }
+}
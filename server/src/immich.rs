@@ -1,10 +1,15 @@
+use std::sync::Arc;
+
 use anyhow::{Result, anyhow};
-use futures::TryFutureExt;
-use reqwest::ClientBuilder;
+use futures::{TryFutureExt, future::BoxFuture};
 use serde::Deserialize;
-use tokio::task::JoinSet;
 use uuid::Uuid;
 
+use crate::{
+    remote,
+    source::{PhotoSource, SourcePhoto},
+};
+
 pub struct Immich {
     server_url: String,
     api_key: String,
@@ -20,6 +25,9 @@ struct Album {
 #[derive(Deserialize)]
 struct Asset {
     id: String,
+    /// Of the original file, changes when it's edited.
+    #[serde(default)]
+    checksum: String,
 }
 
 impl Immich {
@@ -31,53 +39,76 @@ impl Immich {
     }
 
     fn create_client() -> reqwest::Client {
-        ClientBuilder::new()
+        remote::client()
             .danger_accept_invalid_certs(true)
             .build()
             .unwrap()
     }
 
     async fn get_album(base_url: &String, id: &Uuid, api_key: &String) -> Result<Album> {
-        Ok(Self::create_client()
-            .get(format!("{base_url}/albums/{id}?apiKey={api_key}"))
-            .send()
+        remote::send(Self::create_client().get(format!("{base_url}/albums/{id}?apiKey={api_key}")))
             .await
+            .and_then(reqwest::Response::error_for_status)
             .map_err(|e| anyhow!("Failed to fetch {e:?}"))?
             .json()
             .map_err(|e| anyhow!("Failed to parse data {e:?}"))
-            .await?)
+            .await
     }
 
-    pub async fn get_photos(&self, album_id: Uuid) -> Result<Vec<Vec<u8>>> {
+    /// Lists every asset in the album.
+    pub async fn get_assets(&self, album_id: Uuid) -> Result<Vec<SourcePhoto>> {
         let album = Self::get_album(&self.server_url, &album_id, &self.api_key).await?;
+        Ok(album
+            .assets
+            .into_iter()
+            .map(|asset| SourcePhoto {
+                id: asset.id,
+                version: asset.checksum,
+            })
+            .collect())
+    }
 
-        let mut join_set = JoinSet::new();
+    pub async fn get_asset(&self, id: &str) -> Result<Vec<u8>> {
+        Self::get_photo(
+            self.server_url.clone(),
+            id.to_string(),
+            self.api_key.clone(),
+        )
+        .await
+    }
 
-        for asset in &album.assets {
-            join_set.spawn(Self::get_photo(
-                self.server_url.clone(),
-                asset.id.clone(),
-                self.api_key.clone(),
-            ));
-        }
+    pub async fn get_photo(server_url: String, id: String, api_key: String) -> Result<Vec<u8>> {
+        let response = remote::send(Self::create_client().get(format!(
+            "{server_url}/assets/{id}/original?apiKey={api_key}"
+        )))
+        .await
+        .map_err(|e| anyhow!("Failed to fetch {e:?}"))?;
+        remote::bytes(response).await
+    }
+}
 
-        let mut ret = Vec::new();
-        while let Some(res) = join_set.join_next().await {
-            ret.push(res??);
-        }
-        Ok(ret)
+/// An Immich album as a photo source.
+pub struct ImmichAlbum {
+    immich: Arc<Immich>,
+    id: Uuid,
+}
+
+impl ImmichAlbum {
+    pub fn new(immich: Arc<Immich>, id: Uuid) -> Self {
+        ImmichAlbum { immich, id }
     }
+}
 
-    pub async fn get_photo(server_url: String, id: String, api_key: String) -> Result<Vec<u8>> {
-        Ok(Self::create_client()
-            .get(format!(
-                "{server_url}/assets/{id}/original?apiKey={api_key}"
-            ))
-            .send()
-            .await
-            .map_err(|e| anyhow!("Failed to fetch {e:?}"))?
-            .bytes()
-            .await?
-            .to_vec())
+impl PhotoSource for ImmichAlbum {
+    fn name(&self) -> String {
+        self.id.to_string()
+    }
+
+    fn list(&self) -> BoxFuture<'_, Result<Vec<SourcePhoto>>> {
+        Box::pin(self.immich.get_assets(self.id))
+    }
+
+    fn fetch<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<Vec<u8>>> {
+        Box::pin(self.immich.get_asset(id))
     }
 }
@@ -0,0 +1,523 @@
+//! Serves photos from Immich, local directories, WebDAV, S3 or feeds,
+//! processed for the e-paper panel, to the frames.
+
+use std::{net::IpAddr, sync::Arc, time::Duration};
+
+use anyhow::{Result, anyhow};
+use futures::StreamExt;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::Semaphore,
+};
+use tokio_io_timeout::TimeoutStream;
+
+use crate::{
+    app_data::{AppData, Frame, ProccessedImage},
+    codec::Encoding,
+    config::StorageBackend,
+    listener::Listener,
+    pipeline::Pipeline,
+    protocol::{DeviceId, FrameHeader, HELLO_TIMEOUT, Hello, NO_CHALLENGE, RESPONSE_LEN},
+    transfer::{send_buffer, send_windowed},
+};
+
+pub mod admin;
+pub mod app_data;
+mod auth;
+pub mod codec;
+pub mod config;
+mod dashboard;
+pub mod directory;
+pub mod feed;
+pub mod frame_store;
+mod gamut;
+mod http;
+pub mod image_ops;
+pub mod immich;
+mod listener;
+pub mod pipeline;
+mod preview;
+mod protocol;
+mod remote;
+pub mod s3;
+pub mod source;
+pub mod transfer;
+pub mod upload;
+pub mod webdav;
+mod xml;
+
+/// Pause after a failed accept, so running out of file descriptors doesn't spin.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+/// How long the pool is kept when no refresh is requested and no source changes.
+const REFRESH_INTERVAL: Duration = Duration::from_hours(12);
+
+/// Negotiates with the client connecting from `peer` and sends it a frame, or
+/// the rest of the one it was receiving when its last connection dropped.
+pub async fn handle_client<S>(
+    mut socket: S,
+    peer: IpAddr,
+    app_data: &AppData,
+    pipeline: &Pipeline,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut hello = [0u8; Hello::LEN];
+    let auth = &pipeline.config().auth;
+    let Ok(Ok(_)) = tokio::time::timeout(HELLO_TIMEOUT, socket.read_exact(&mut hello)).await else {
+        if !auth::allows_anonymous(auth) {
+            return Err(anyhow!(
+                "Refused frame without a handshake, devices are restricted"
+            ));
+        }
+        // Frames from before the handshake just wait for the raw panels
+        let (_, photo) = next_photo(app_data, pipeline, None).await?;
+        let photo = photo.encoded(Encoding::Raw)?;
+        println!("Sending {} + {} bytes", photo.left.len(), photo.right.len());
+        send_buffer(&mut socket, &photo.left)
+            .await
+            .map_err(|e| anyhow!("Failed to send the left panel: {e}"))?;
+        send_buffer(&mut socket, &photo.right)
+            .await
+            .map_err(|e| anyhow!("Failed to send the right panel: {e}"))?;
+        return Ok(());
+    };
+    let hello = Hello::parse(&hello)?;
+
+    match auth::device_key(auth, &hello.device)? {
+        Some(key) => {
+            let challenge = auth::new_challenge();
+            let mut response = [0u8; RESPONSE_LEN];
+            socket
+                .write_all(&challenge)
+                .await
+                .map_err(|e| anyhow!("Failed to send the challenge: {e}"))?;
+            socket
+                .read_exact(&mut response)
+                .await
+                .map_err(|e| anyhow!("Failed to read the challenge response: {e}"))?;
+            auth::verify(key, &challenge, &hello.device, &response)?;
+        }
+        None => socket
+            .write_all(&NO_CHALLENGE)
+            .await
+            .map_err(|e| anyhow!("Failed to send the challenge: {e}"))?,
+    }
+    app_data.check_in(hello.device, peer);
+
+    let resumed = app_data.pinned_image(&hello.device).filter(|photo| {
+        hello.offset > 0
+            && photo.checksums.image == hello.image
+            && hello.offset as usize <= photo.left.len() + photo.right.len()
+    });
+    let (photo, offset) = match resumed {
+        Some(photo) => {
+            println!("Resuming transfer at byte {}", hello.offset);
+            (photo, hello.offset)
+        }
+        None => {
+            let (id, photo) = next_photo(app_data, pipeline, Some(&hello.device)).await?;
+            app_data.record_image(hello.device, id);
+            let encoding = if hello.supports(photo.encoding) {
+                photo.encoding
+            } else {
+                Encoding::Raw
+            };
+            let photo = photo.encoded(encoding)?;
+            app_data.pin_image(hello.device, photo.clone());
+            (photo, 0)
+        }
+    };
+
+    let header = FrameHeader {
+        encoding: photo.encoding,
+        left_len: photo.left.len() as u32,
+        right_len: photo.right.len() as u32,
+        checksums: photo.checksums,
+        offset,
+    };
+    socket
+        .write_all(&header.to_bytes())
+        .await
+        .map_err(|e| anyhow!("Failed to send the frame header: {e}"))?;
+    let offset = offset as usize;
+    let (left, right) = match photo.left.get(offset..) {
+        Some(left) => (left, &photo.right[..]),
+        None => (&[][..], &photo.right[offset - photo.left.len()..]),
+    };
+    println!("Sending {} + {} bytes", left.len(), right.len());
+    send_windowed(&mut socket, &[left, right], offset, hello.window as usize)
+        .await
+        .map_err(|e| anyhow!("Failed to send the panels: {e}"))?;
+    app_data.unpin_image(&hello.device);
+    app_data.record_delivery(hello.device);
+    Ok(())
+}
+
+pub async fn esp_server(app_data: Arc<AppData>, pipeline: Arc<Pipeline>) -> Result<()> {
+    let config = &pipeline.config().server;
+    let listeners = listener::open(config)?;
+    let http_listeners = match &config.http {
+        Some(http) => listener::bind_port(config, http.port)?,
+        None => Vec::new(),
+    };
+    let admin_listeners = match &config.admin {
+        Some(admin) => {
+            let router = admin::router(
+                Arc::clone(&app_data),
+                Arc::clone(&pipeline),
+                admin.token.clone(),
+            );
+            listener::bind_port(config, admin.port)?
+                .into_iter()
+                .map(|listener| (listener, router.clone()))
+                .collect()
+        }
+        None => Vec::new(),
+    };
+    let connections = Arc::new(Semaphore::new(config.max_connections));
+
+    let accept_loops = listeners.into_iter().map(|listener| {
+        tokio::spawn(accept_connections(
+            listener,
+            Arc::clone(&app_data),
+            Arc::clone(&pipeline),
+            Arc::clone(&connections),
+        ))
+    });
+    let http_servers = http_listeners.into_iter().map(|listener| {
+        tokio::spawn(http::serve(
+            listener,
+            Arc::clone(&app_data),
+            Arc::clone(&pipeline),
+        ))
+    });
+    let admin_servers = admin_listeners
+        .into_iter()
+        .map(|(listener, router)| tokio::spawn(admin::serve(listener, router)));
+    futures::future::join_all(accept_loops.chain(http_servers).chain(admin_servers)).await;
+    Ok(())
+}
+
+/// Serves every frame connecting to the listener, sharing `connections` with
+/// the other listeners.
+async fn accept_connections(
+    listener: Listener,
+    app_data: Arc<AppData>,
+    pipeline: Arc<Pipeline>,
+    connections: Arc<Semaphore>,
+) {
+    let transport = if listener.tls.is_some() { "TLS" } else { "TCP" };
+    match listener.tcp.local_addr() {
+        Ok(address) => println!("Starting {transport} server on {address}..."),
+        Err(_) => println!("Starting {transport} server..."),
+    }
+    let config = pipeline.config();
+    let read_timeout = Duration::from_secs(config.server.read_timeout_secs);
+    let write_timeout = Duration::from_secs(config.server.write_timeout_secs);
+    let connection_timeout = Duration::from_secs(config.server.connection_timeout_secs);
+
+    loop {
+        // Further frames queue up in the listener's backlog until a slot frees up
+        let permit = Arc::clone(&connections).acquire_owned().await.unwrap();
+        let (socket, peer) = match listener.tcp.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                println!("Failed to accept connection: {e}");
+                tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        if let Err(e) = auth::check_peer(&config.auth, peer.ip()) {
+            println!("Refused connection from {peer}: {e}");
+            continue;
+        }
+        println!("Client connected: {peer}");
+
+        let mut socket = TimeoutStream::new(socket);
+        socket.set_read_timeout(Some(read_timeout));
+        socket.set_write_timeout(Some(write_timeout));
+        let socket = Box::pin(socket);
+        let tls = listener.tls.clone();
+        let app_data = Arc::clone(&app_data);
+        let pipeline = Arc::clone(&pipeline);
+        tokio::spawn(async move {
+            let _permit = permit;
+            let client = async {
+                match tls {
+                    Some(acceptor) => {
+                        let socket = acceptor
+                            .accept(socket)
+                            .await
+                            .map_err(|e| anyhow!("TLS handshake failed: {e}"))?;
+                        handle_client(socket, peer.ip(), &app_data, &pipeline).await
+                    }
+                    None => handle_client(socket, peer.ip(), &app_data, &pipeline).await,
+                }
+            };
+            match tokio::time::timeout(connection_timeout, client).await {
+                Ok(Ok(())) => println!("Sent frame to {peer}"),
+                Ok(Err(e)) => println!("Failed to send frame to {peer}: {e:?}"),
+                Err(_) => println!("Connection to {peer} timed out"),
+            }
+        });
+    }
+}
+
+/// Picks the frame or upload queued for the device or a random one, or a
+/// blank one while there are none. Returns the frame's id with its image.
+async fn next_photo(
+    app_data: &AppData,
+    pipeline: &Pipeline,
+    device: Option<&DeviceId>,
+) -> Result<(Option<String>, ProccessedImage)> {
+    let pick = pick_photo(app_data, pipeline, device).await?;
+    use_photo(app_data, device, &pick);
+    Ok((pick.id, pick.photo))
+}
+
+/// A photo picked for a device, see [`pick_photo`].
+struct Pick {
+    id: Option<String>,
+    photo: ProccessedImage,
+    /// Whether it was queued for the device.
+    queued: bool,
+}
+
+/// Picks the photo like [`next_photo`], but leaves the device's queue and the
+/// upload's displays alone until [`use_photo`].
+async fn pick_photo(
+    app_data: &AppData,
+    pipeline: &Pipeline,
+    device: Option<&DeviceId>,
+) -> Result<Pick> {
+    let blank = Pick {
+        id: None,
+        photo: ProccessedImage::default(),
+        queued: false,
+    };
+    let queued = device
+        .and_then(|device| app_data.device(device)?.next)
+        .filter(|id| app_data.contains(id));
+    let is_queued = queued.is_some();
+    let Some(id) = queued.or_else(|| app_data.random_id()) else {
+        return Ok(blank);
+    };
+    if let Some(photo) = app_data.upload_image(&id) {
+        return Ok(Pick {
+            id: Some(id),
+            photo,
+            queued: is_queued,
+        });
+    }
+    // Gone since it was picked, with a refresh or an expiry
+    let Some(frame) = app_data.get_frame(&id) else {
+        return Ok(blank);
+    };
+    let photo = load_frame(app_data, pipeline, &frame)
+        .await
+        .map_err(|e| anyhow!("Failed to process image {}: {e:?}", frame.id))?;
+    Ok(Pick {
+        id: Some(id),
+        photo,
+        queued: is_queued,
+    })
+}
+
+/// Counts the pick as shown, taking it off the device's queue and off the
+/// upload's remaining displays.
+fn use_photo(app_data: &AppData, device: Option<&DeviceId>, pick: &Pick) {
+    if let Some(device) = device.filter(|_| pick.queued) {
+        app_data.take_queued(device);
+    }
+    if let Some(id) = &pick.id {
+        app_data.count_display(id);
+    }
+}
+
+/// Returns the stored frame, processing it first if the store doesn't hold it.
+async fn load_frame(
+    app_data: &AppData,
+    pipeline: &Pipeline,
+    frame: &Frame,
+) -> Result<ProccessedImage> {
+    if let Some(image) = app_data.get_image(&frame.id).await {
+        return Ok(image);
+    }
+
+    let image = pipeline.render(frame).await?;
+    app_data.cache_image(&frame.id, image.clone()).await;
+    Ok(image)
+}
+
+pub async fn refresh_images(app_data: Arc<AppData>, pipeline: Arc<Pipeline>) {
+    loop {
+        println!("Refreshing images...");
+        let plan = pipeline.plan().await;
+        let mut frames = plan.frames;
+        frames.retain(|frame| app_data.allows(frame));
+        // Albums that failed to list keep what they had until the next refresh
+        frames.extend(
+            app_data
+                .frames()
+                .into_iter()
+                .filter(|frame| plan.failed.contains(&frame.album)),
+        );
+        if pipeline.config().storage.backend == StorageBackend::Lazy {
+            app_data.set_frames(frames).await;
+        } else {
+            let ids = frames.iter().map(|frame| frame.id.clone()).collect();
+            let (current, frames): (Vec<_>, Vec<_>) = frames
+                .into_iter()
+                .partition(|frame| app_data.is_current(frame));
+            println!(
+                "{} frames unchanged, processing {}",
+                current.len(),
+                frames.len()
+            );
+
+            // Publish every frame as soon as it's done so the pool fills up gradually
+            futures::stream::iter(frames)
+                .map(|frame| {
+                    let pipeline = Arc::clone(&pipeline);
+                    async move {
+                        let result = pipeline.render(&frame).await;
+                        (frame, result)
+                    }
+                })
+                .buffer_unordered(pipeline.concurrency())
+                .for_each(|(frame, result)| async {
+                    match result {
+                        Ok(image) => app_data.insert_image(frame, image).await,
+                        Err(e) => println!("Failed to process image {}: {e:?}", frame.id),
+                    }
+                })
+                .await;
+            app_data.retain_frames(&ids).await;
+        }
+        println!(
+            "Images refreshed. Next refresh in {} hours or when a source changes.",
+            REFRESH_INTERVAL.as_secs() / 3600
+        );
+        tokio::select! {
+            _ = tokio::time::sleep(REFRESH_INTERVAL) => {}
+            _ = app_data.refresh_requested() => {}
+            _ = pipeline.sources_changed() => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::{
+        app_data::Checksums,
+        config::{Config, ServerConfig},
+        frame_store::MemoryStore,
+    };
+
+    /// Serves a pool of one 20 kB frame on a local port.
+    async fn serve(server: ServerConfig) -> std::net::SocketAddr {
+        let config = Config {
+            server,
+            ..Config::default()
+        };
+        let connections = Arc::new(Semaphore::new(config.server.max_connections));
+        let pipeline = Arc::new(Pipeline::new(Vec::new(), Arc::new(config)));
+        let app_data = Arc::new(AppData::new(Box::new(MemoryStore::default())));
+        let (left, right) = (vec![1u8; 10_000], vec![2u8; 10_000]);
+        let frame = Frame {
+            id: "0:a".to_string(),
+            album: 0,
+            assets: vec!["0:a".to_string()],
+            template: None,
+            version: String::new(),
+        };
+        let image = ProccessedImage {
+            encoding: Encoding::Raw,
+            checksums: Checksums::new(&left, &right),
+            left,
+            right,
+        };
+        app_data.insert_image(frame, image).await;
+
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = tcp.local_addr().unwrap();
+        let listener = Listener { tcp, tls: None };
+        tokio::spawn(accept_connections(
+            listener,
+            app_data,
+            pipeline,
+            connections,
+        ));
+        address
+    }
+
+    /// Connects and says hello, granting a window of 4 kB.
+    async fn connect(address: std::net::SocketAddr, device: u8) -> TcpStream {
+        let mut frame = TcpStream::connect(address).await.unwrap();
+        let mut hello = b"SPF\x06".to_vec();
+        hello.push(Encoding::Raw.mask());
+        hello.extend_from_slice(&[0x02, 0, 0, 0, 0, device]);
+        hello.extend_from_slice(&[0; 8]);
+        hello.extend_from_slice(&4096u32.to_le_bytes());
+        frame.write_all(&hello).await.unwrap();
+        frame
+    }
+
+    #[tokio::test]
+    async fn drops_clients_that_stall() {
+        let address = serve(ServerConfig {
+            read_timeout_secs: 1,
+            write_timeout_secs: 1,
+            connection_timeout_secs: 60,
+            ..ServerConfig::default()
+        })
+        .await;
+
+        let mut frame = connect(address, 1).await;
+        // The challenge, the header and the first window, then no ack
+        let mut start = vec![0u8; 16 + FrameHeader::LEN + 4096];
+        frame.read_exact(&mut start).await.unwrap();
+        let stalled = std::time::Instant::now();
+        let mut rest = Vec::new();
+        tokio::time::timeout(Duration::from_secs(10), frame.read_to_end(&mut rest))
+            .await
+            .expect("still connected")
+            .unwrap();
+        assert!(rest.is_empty());
+        assert!(stalled.elapsed() >= Duration::from_millis(900));
+    }
+
+    #[tokio::test]
+    async fn queues_connections_over_the_limit() {
+        let address = serve(ServerConfig {
+            max_connections: 1,
+            ..ServerConfig::default()
+        })
+        .await;
+
+        let mut first = connect(address, 1).await;
+        let mut challenge = [0u8; 16];
+        first.read_exact(&mut challenge).await.unwrap();
+
+        let mut second = connect(address, 2).await;
+        let waiting = tokio::time::timeout(
+            Duration::from_millis(500),
+            second.read_exact(&mut challenge),
+        )
+        .await;
+        assert!(
+            waiting.is_err(),
+            "served while the first frame holds the only slot"
+        );
+
+        drop(first);
+        tokio::time::timeout(Duration::from_secs(5), second.read_exact(&mut challenge))
+            .await
+            .expect("still waiting after the first frame left")
+            .unwrap();
+    }
+}
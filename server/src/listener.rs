@@ -0,0 +1,170 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use anyhow::{Result, anyhow};
+use rustls_pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::TcpListener;
+use tokio_rustls::{TlsAcceptor, rustls};
+
+use crate::config::{ServerConfig, TlsConfig};
+
+const BACKLOG: i32 = 128;
+
+pub struct Listener {
+    pub tcp: TcpListener,
+    /// Set when connections have to start with a TLS handshake.
+    pub tls: Option<TlsAcceptor>,
+}
+
+/// Opens a listener for every configured address, or takes over `listen_fd`,
+/// plus a TLS listener for every address when TLS is configured.
+pub fn open(config: &ServerConfig) -> Result<Vec<Listener>> {
+    let mut listeners = match config.listen_fd {
+        Some(fd) => vec![Listener {
+            tcp: from_fd(fd)?,
+            tls: None,
+        }],
+        None => bind_all(config, config.port, None)?,
+    };
+    if let Some(tls) = &config.tls {
+        let acceptor = tls_acceptor(tls)?;
+        listeners.extend(bind_all(config, tls.port, Some(acceptor))?);
+    }
+    Ok(listeners)
+}
+
+/// Opens a listener on `port` for every configured address, for the HTTP servers.
+pub fn bind_port(config: &ServerConfig, port: u16) -> Result<Vec<TcpListener>> {
+    let listeners = bind_all(config, port, None)?;
+    Ok(listeners.into_iter().map(|listener| listener.tcp).collect())
+}
+
+fn bind_all(config: &ServerConfig, port: u16, tls: Option<TlsAcceptor>) -> Result<Vec<Listener>> {
+    config
+        .bind
+        .iter()
+        .map(|address| {
+            let address = SocketAddr::new(*address, port);
+            let tcp = bind(address, config.dual_stack)
+                .map_err(|e| anyhow!("Failed to listen on {address}: {e}"))?;
+            Ok(Listener {
+                tcp,
+                tls: tls.clone(),
+            })
+        })
+        .collect()
+}
+
+fn bind(address: SocketAddr, dual_stack: bool) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    if address.is_ipv6() {
+        socket.set_only_v6(!dual_stack)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(BACKLOG)?;
+    TcpListener::from_std(socket.into())
+}
+
+fn tls_acceptor(config: &TlsConfig) -> Result<TlsAcceptor> {
+    let certificates = CertificateDer::pem_file_iter(&config.certificate)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| {
+            anyhow!(
+                "Failed to read certificate {}: {e}",
+                config.certificate.display()
+            )
+        })?;
+    let key = PrivateKeyDer::from_pem_file(&config.key)
+        .map_err(|e| anyhow!("Failed to read key {}: {e}", config.key.display()))?;
+
+    let tls = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certificates, key)
+        .map_err(|e| anyhow!("Invalid TLS certificate or key: {e}"))?;
+    Ok(TlsAcceptor::from(Arc::new(tls)))
+}
+
+#[cfg(unix)]
+fn from_fd(fd: i32) -> Result<TcpListener> {
+    use std::os::fd::FromRawFd;
+
+    // SAFETY: the fd is handed to us to own, nothing else in the process uses it
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    listener
+        .set_nonblocking(true)
+        .and_then(|_| TcpListener::from_std(listener))
+        .map_err(|e| anyhow!("Failed to listen on fd {fd}: {e}"))
+}
+
+#[cfg(not(unix))]
+fn from_fd(_fd: i32) -> Result<TcpListener> {
+    Err(anyhow!("server.listen_fd is only supported on unix"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr, Ipv6Addr},
+        time::Duration,
+    };
+
+    use super::*;
+
+    fn server(toml: &str) -> Result<ServerConfig, toml::de::Error> {
+        toml::from_str(toml)
+    }
+
+    #[test]
+    fn parses_addresses_and_ports() {
+        let config = server("bind = [\"127.0.0.1\", \"::1\"]\nport = 0").unwrap();
+        assert_eq!(
+            config.bind,
+            [
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(Ipv6Addr::LOCALHOST)
+            ]
+        );
+        assert_eq!(config.port, 0);
+
+        assert!(server("bind = [\"localhost\"]").is_err());
+        assert!(server("bind = [\"127.0.0.1:2025\"]").is_err());
+        assert!(server("bind = [\"256.0.0.1\"]").is_err());
+        assert!(server("port = 65536").is_err());
+        assert!(server("port = -1").is_err());
+    }
+
+    #[tokio::test]
+    async fn listens_on_every_address() {
+        let config = server("bind = [\"127.0.0.1\", \"::1\"]").unwrap();
+        let listeners = bind_all(&config, 0, None).unwrap();
+        let addresses: Vec<_> = listeners
+            .iter()
+            .map(|listener| listener.tcp.local_addr().unwrap().ip())
+            .collect();
+        assert_eq!(addresses, config.bind);
+        assert!(listeners.iter().all(|listener| listener.tls.is_none()));
+    }
+
+    /// Whether `listener` accepts a client connecting to its port over IPv4.
+    async fn accepts_ipv4(listener: &TcpListener) -> bool {
+        let port = listener.local_addr().unwrap().port();
+        // Without dual stack nothing listens there, so the connection is refused
+        let _client = tokio::net::TcpStream::connect((Ipv4Addr::LOCALHOST, port)).await;
+        tokio::time::timeout(Duration::from_millis(200), listener.accept())
+            .await
+            .is_ok()
+    }
+
+    #[tokio::test]
+    async fn keeps_ipv6_to_itself_without_dual_stack() {
+        let any = SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0);
+        assert!(!accepts_ipv4(&bind(any, false).unwrap()).await);
+        assert!(accepts_ipv4(&bind(any, true).unwrap()).await);
+    }
+}
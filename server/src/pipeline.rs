@@ -0,0 +1,396 @@
+use std::{
+    collections::HashSet,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, RwLock},
+};
+
+use anyhow::{Result, anyhow};
+use futures::future::{select_all, try_join_all};
+
+use crate::{
+    app_data::{Frame, ProccessedImage},
+    config::{CollageTemplate, Config, ProcessingConfig},
+    image_ops::{process_collage, process_image},
+    preview,
+    source::{PhotoSource, SourcePhoto},
+};
+
+/// The frames of the albums that could be listed.
+#[derive(Default)]
+pub struct Plan {
+    pub frames: Vec<Frame>,
+    /// Albums that couldn't be listed, their frames in the pool are still good.
+    pub failed: HashSet<usize>,
+}
+
+/// Turns album assets into frames, processing them on a dedicated thread pool.
+pub struct Pipeline {
+    /// Where each album's photos come from, in the order of `config.albums`.
+    sources: Vec<Box<dyn PhotoSource>>,
+    config: Arc<Config>,
+    /// Processing for albums without their own, starts out as `[processing]`.
+    processing: RwLock<ProcessingConfig>,
+    pool: rayon::ThreadPool,
+}
+
+impl Pipeline {
+    pub fn new(sources: Vec<Box<dyn PhotoSource>>, config: Arc<Config>) -> Self {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(config.workers.threads)
+            .thread_name(|i| format!("image-worker-{i}"))
+            .build()
+            .unwrap();
+
+        Pipeline {
+            sources,
+            processing: RwLock::new(config.processing.clone()),
+            config,
+            pool,
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Processing for albums without their own.
+    pub fn processing(&self) -> ProcessingConfig {
+        self.processing.read().unwrap().clone()
+    }
+
+    /// Replaces the processing for albums without their own, frames rendered
+    /// from now on use it.
+    pub fn set_processing(&self, processing: ProcessingConfig) {
+        *self.processing.write().unwrap() = processing;
+    }
+
+    /// Number of frames that can usefully be rendered at the same time.
+    pub fn concurrency(&self) -> usize {
+        self.pool.current_num_threads()
+    }
+
+    /// Describes where the album's photos come from.
+    pub fn source_name(&self, album: usize) -> String {
+        self.sources[album].name()
+    }
+
+    /// Lists the frames of every album without downloading or processing them.
+    /// An album that can't be listed is skipped, so the others still refresh.
+    pub async fn plan(&self) -> Plan {
+        let mut plan = Plan::default();
+        for (album, source) in self.sources.iter().enumerate() {
+            match source.list().await {
+                Ok(photos) => {
+                    plan.frames
+                        .extend(plan_album(album, photos, &self.config.collage.templates))
+                }
+                Err(e) => {
+                    println!("Failed to fetch album {}: {e:?}", source.name());
+                    plan.failed.insert(album);
+                }
+            }
+        }
+        plan
+    }
+
+    /// Resolves once an album's source changed since it was last listed.
+    pub async fn sources_changed(&self) {
+        if self.sources.is_empty() {
+            return futures::future::pending().await;
+        }
+        select_all(self.sources.iter().map(|source| source.changed())).await;
+    }
+
+    /// Downloads the frame's assets and processes them on the thread pool.
+    pub async fn render(&self, frame: &Frame) -> Result<ProccessedImage> {
+        let processing = match &self.config.albums[frame.album].processing {
+            Some(processing) => processing.clone(),
+            None => self.processing(),
+        };
+        self.render_with(frame, processing).await
+    }
+
+    /// Renders the frame with the given processing instead of its album's.
+    pub async fn render_with(
+        &self,
+        frame: &Frame,
+        processing: ProcessingConfig,
+    ) -> Result<ProccessedImage> {
+        let source = &self.sources[frame.album];
+        let photos =
+            try_join_all(frame.assets.iter().map(|id| source.fetch(source_id(id)))).await?;
+
+        let config = Arc::clone(&self.config);
+        let frame = frame.clone();
+        self.run(move || process_frame(&frame, &photos, &processing, &config))
+            .await
+    }
+
+    /// Processes an uploaded photo on the thread pool, with the processing for
+    /// albums without their own.
+    pub async fn process_upload(&self, photo: Vec<u8>) -> Result<ProccessedImage> {
+        let config = Arc::clone(&self.config);
+        let processing = self.processing();
+        self.run(move || {
+            process_image(&photo, &config.frame, &processing)
+                .map_err(|e| anyhow!("Failed to process the photo: {e}"))
+                .and_then(|image| ProccessedImage::from(image).encoded(config.storage.encoding))
+        })
+        .await
+    }
+
+    /// Renders a processed frame as a PNG on the thread pool, see [`preview::render_png`].
+    pub async fn preview(
+        &self,
+        image: ProccessedImage,
+        inks: bool,
+        width: Option<u32>,
+    ) -> Result<Vec<u8>> {
+        let config = Arc::clone(&self.config);
+        self.run(move || preview::render_png(image, &config.frame, inks, width))
+            .await
+    }
+
+    /// Runs the job on the thread pool. A panic in it fails the job, rayon
+    /// would abort the whole server.
+    async fn run<T: Send + 'static>(
+        &self,
+        job: impl FnOnce() -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        self.pool.spawn(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(job)).unwrap_or_else(|panic| {
+                let message = panic
+                    .downcast_ref::<&str>()
+                    .copied()
+                    .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                    .unwrap_or("no message");
+                Err(anyhow!("Image processing panicked: {message}"))
+            });
+            let _ = sender.send(result);
+        });
+
+        receiver.await?
+    }
+}
+
+/// The id of an asset is its source's id behind the album, as two sources can
+/// hold photos by the same id.
+fn asset_id(album: usize, id: &str) -> String {
+    format!("{album}:{id}")
+}
+
+/// The id the source knows the asset by.
+fn source_id(asset: &str) -> &str {
+    asset.split_once(':').map_or(asset, |(_, id)| id)
+}
+
+/// Spreads the album over the collage templates in turn, showing leftovers on their own.
+fn plan_album(
+    album: usize,
+    mut photos: Vec<SourcePhoto>,
+    templates: &[CollageTemplate],
+) -> Vec<Frame> {
+    for photo in &mut photos {
+        photo.id = asset_id(album, &photo.id);
+    }
+    let single = |photo: SourcePhoto| Frame {
+        id: photo.id.clone(),
+        album,
+        assets: vec![photo.id],
+        template: None,
+        version: photo.version,
+    };
+    if templates.is_empty() {
+        return photos.into_iter().map(single).collect();
+    }
+
+    // Mixed, but the same way every refresh so unchanged collages stay in the pool
+    photos.sort_by_cached_key(|photo| (crc32fast::hash(photo.id.as_bytes()), photo.id.clone()));
+
+    let mut ret = Vec::new();
+    let mut photos = photos.into_iter().peekable();
+    for (index, template) in templates.iter().enumerate().cycle() {
+        let cell_photos: Vec<_> = photos.by_ref().take(template.cells.len()).collect();
+        if cell_photos.len() < template.cells.len() {
+            ret.extend(cell_photos.into_iter().map(single));
+            break;
+        }
+
+        let (assets, versions): (Vec<_>, Vec<_>) = cell_photos
+            .into_iter()
+            .map(|photo| (photo.id, photo.version))
+            .unzip();
+        ret.push(Frame {
+            id: assets.join("+"),
+            album,
+            assets,
+            template: Some(index),
+            version: versions.join("+"),
+        });
+        if photos.peek().is_none() {
+            break;
+        }
+    }
+    ret
+}
+
+fn process_frame(
+    frame: &Frame,
+    photos: &[Vec<u8>],
+    processing: &ProcessingConfig,
+    config: &Config,
+) -> Result<ProccessedImage> {
+    let image = match frame.template {
+        Some(template) => {
+            let template = &config.collage.templates[template];
+            println!("Composing collage {}...", template.name);
+            process_collage(photos, template, &config.frame, processing)?
+        }
+        None => process_image(&photos[0], &config.frame, processing)?,
+    };
+    ProccessedImage::from(image).encoded(config.storage.encoding)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use futures::future::BoxFuture;
+    use image::{ImageFormat, Rgb, RgbImage};
+
+    use super::*;
+    use crate::config::AlbumConfig;
+
+    /// Photos by name, all at the same version.
+    struct Stub(Vec<(&'static str, Vec<u8>)>);
+
+    impl PhotoSource for Stub {
+        fn name(&self) -> String {
+            "stub".to_string()
+        }
+
+        fn list(&self) -> BoxFuture<'_, Result<Vec<SourcePhoto>>> {
+            let photos = self
+                .0
+                .iter()
+                .map(|(id, _)| SourcePhoto {
+                    id: id.to_string(),
+                    version: "1".to_string(),
+                })
+                .collect();
+            Box::pin(async { Ok(photos) })
+        }
+
+        fn fetch<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<Vec<u8>>> {
+            let photo = self.0.iter().find(|(name, _)| *name == id);
+            let photo = photo
+                .map(|(_, data)| data.clone())
+                .ok_or_else(|| anyhow!("No photo {id}"));
+            Box::pin(async { photo })
+        }
+    }
+
+    /// A source whose server is down.
+    struct Unreachable;
+
+    impl PhotoSource for Unreachable {
+        fn name(&self) -> String {
+            "unreachable".to_string()
+        }
+
+        fn list(&self) -> BoxFuture<'_, Result<Vec<SourcePhoto>>> {
+            Box::pin(async { Err(anyhow!("Connection refused")) })
+        }
+
+        fn fetch<'a>(&'a self, _: &'a str) -> BoxFuture<'a, Result<Vec<u8>>> {
+            Box::pin(async { Err(anyhow!("Connection refused")) })
+        }
+    }
+
+    fn photos(count: usize) -> Vec<SourcePhoto> {
+        (0..count)
+            .map(|i| SourcePhoto {
+                id: format!("{i}.jpg"),
+                version: "1".to_string(),
+            })
+            .collect()
+    }
+
+    fn png(colour: [u8; 3]) -> Vec<u8> {
+        let mut png = Vec::new();
+        RgbImage::from_pixel(4, 3, Rgb(colour))
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        png
+    }
+
+    #[tokio::test]
+    async fn keeps_albums_with_the_same_ids_apart() {
+        let config = Config {
+            albums: vec![AlbumConfig::default(), AlbumConfig::default()],
+            ..Config::default()
+        };
+        let sources: Vec<Box<dyn PhotoSource>> = vec![
+            Box::new(Stub(vec![("2024/img.jpg", png([255, 0, 0]))])),
+            Box::new(Stub(vec![("2024/img.jpg", png([0, 0, 255]))])),
+        ];
+        let pipeline = Pipeline::new(sources, Arc::new(config));
+
+        let frames = pipeline.plan().await.frames;
+        let ids: Vec<_> = frames.iter().map(|frame| frame.id.as_str()).collect();
+        assert_eq!(ids, ["0:2024/img.jpg", "1:2024/img.jpg"]);
+        assert_eq!(frames[1].assets, ["1:2024/img.jpg"]);
+
+        let red = pipeline.render(&frames[0]).await.unwrap();
+        let blue = pipeline.render(&frames[1]).await.unwrap();
+        assert_ne!(red.checksums.image, blue.checksums.image);
+    }
+
+    #[test]
+    fn plans_the_same_collages_every_time() {
+        let templates: Vec<CollageTemplate> = vec![
+            toml::from_str(
+                "name = \"three\"\ncolumns = 3\nrows = 1\n\
+                 cells = [{ x = 0, y = 0 }, { x = 1, y = 0 }, { x = 2, y = 0 }]",
+            )
+            .unwrap(),
+        ];
+        let ids = |frames: Vec<Frame>| -> Vec<String> {
+            frames.into_iter().map(|frame| frame.id).collect()
+        };
+
+        let first = ids(plan_album(0, photos(20), &templates));
+        assert!(first.iter().any(|id| id.contains('+')));
+        let mut reversed = photos(20);
+        reversed.reverse();
+        assert_eq!(ids(plan_album(0, reversed, &templates)), first);
+    }
+
+    #[tokio::test]
+    async fn skips_albums_that_fail_to_list() {
+        let config = Config {
+            albums: vec![AlbumConfig::default(), AlbumConfig::default()],
+            ..Config::default()
+        };
+        let sources: Vec<Box<dyn PhotoSource>> = vec![
+            Box::new(Unreachable),
+            Box::new(Stub(vec![("a.jpg", png([0, 0, 0]))])),
+        ];
+        let plan = Pipeline::new(sources, Arc::new(config)).plan().await;
+        assert_eq!(plan.frames.len(), 1);
+        assert_eq!(plan.frames[0].id, "1:a.jpg");
+        assert_eq!(plan.failed, HashSet::from([0]));
+    }
+
+    #[tokio::test]
+    async fn fails_jobs_that_panic() {
+        let pipeline = Pipeline::new(Vec::new(), Arc::new(Config::default()));
+        let panicked = pipeline.run(|| -> Result<()> { panic!("corrupt photo") });
+        let error = panicked.await.unwrap_err();
+        assert!(error.to_string().contains("corrupt photo"), "{error}");
+
+        // The pool carries on
+        assert_eq!(pipeline.run(|| Ok(1)).await.unwrap(), 1);
+    }
+}
@@ -0,0 +1,89 @@
+//! Turns processed frames back into pictures, to check the dithering without
+//! waiting for the panel.
+
+use std::io::Cursor;
+
+use anyhow::{Result, anyhow};
+use image::{ImageFormat, Rgb, RgbImage};
+
+use crate::{
+    app_data::ProccessedImage,
+    codec::Encoding,
+    config::{FrameConfig, Palette},
+    image_ops::rotate_from_panel,
+};
+
+/// Pixels in a row of panel memory, half of them in each panel.
+const PANEL_WIDTH: usize = 1200;
+/// Bytes of one panel's half of a row, two pixels to a byte.
+const PANEL_ROW_LEN: usize = PANEL_WIDTH / 4;
+
+/// Renders the frame as a PNG the way it's mounted. The colours are the pure
+/// ones the panel is driven with, or with `inks` the frame's palette, which is
+/// closer to what the panel shows. A `width` below the frame's scales it down
+/// for thumbnails.
+pub fn render_png(
+    image: ProccessedImage,
+    frame: &FrameConfig,
+    inks: bool,
+    width: Option<u32>,
+) -> Result<Vec<u8>> {
+    let palette = if inks {
+        frame.palette
+    } else {
+        Palette::default()
+    };
+    let image = image.encoded(Encoding::Raw)?;
+    let panels = unpack_panels(&image.left, &image.right, &palette)?;
+    let mut picture = rotate_from_panel(panels, frame.orientation);
+    if let Some(width) = width.filter(|width| (1..picture.width()).contains(width)) {
+        let height = (picture.height() * width / picture.width()).max(1);
+        picture = image::imageops::thumbnail(&picture, width, height);
+    }
+
+    let mut png = Vec::new();
+    picture
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .map_err(|e| anyhow!("Failed to encode the preview: {e}"))?;
+    Ok(png)
+}
+
+/// Lays both panels out side by side, in the order the panel memory holds them.
+fn unpack_panels(left: &[u8], right: &[u8], palette: &Palette) -> Result<RgbImage> {
+    if left.is_empty() || left.len() != right.len() || !left.len().is_multiple_of(PANEL_ROW_LEN) {
+        return Err(anyhow!(
+            "Panels of {} and {} bytes don't make up an image",
+            left.len(),
+            right.len()
+        ));
+    }
+
+    let rows = left.len() / PANEL_ROW_LEN;
+    let mut image = RgbImage::new(PANEL_WIDTH as u32, rows as u32);
+    for (y, (left, right)) in left
+        .chunks(PANEL_ROW_LEN)
+        .zip(right.chunks(PANEL_ROW_LEN))
+        .enumerate()
+    {
+        for (x, byte) in left.iter().chain(right).enumerate() {
+            let x = 2 * x as u32;
+            image.put_pixel(x, y as u32, ink(byte >> 4, palette)?);
+            image.put_pixel(x + 1, y as u32, ink(byte & 0x0f, palette)?);
+        }
+    }
+    Ok(image)
+}
+
+/// The palette colour of a pixel in panel memory.
+fn ink(value: u8, palette: &Palette) -> Result<Rgb<u8>> {
+    let color = match value {
+        0 => palette.black,
+        1 => palette.white,
+        2 => palette.yellow,
+        3 => palette.red,
+        5 => palette.blue,
+        6 => palette.green,
+        _ => return Err(anyhow!("Unknown panel colour {value}")),
+    };
+    Ok(Rgb(color))
+}
@@ -0,0 +1,104 @@
+//! Handshake between the server and frames that understand more than the raw stream.
+//!
+//! A frame opens with a [`Hello`]. Frames that don't send one within
+//! [`HELLO_TIMEOUT`] are treated as legacy frames and get the raw panel bytes.
+//! Everyone else is sent [`CHALLENGE_LEN`] random bytes and answers with the
+//! [`RESPONSE_LEN`] byte `HMAC-SHA256(key, challenge || device id)` using its
+//! pre-shared key. Frames the server has no key for get [`NO_CHALLENGE`] and
+//! don't answer. Frames that pass get a [`FrameHeader`], followed by both
+//! panels in the chosen encoding. All integers are little endian and checksums
+//! are CRC-32 (IEEE) over the raw panel bytes, so frames can verify what they
+//! decoded before refreshing.
+//!
+//! A frame that lost its connection can reconnect within [`RESUME_WINDOW`] and
+//! ask for the rest of the image it was receiving, identified by the image
+//! checksum, from the offset into the encoded left and right panels it got to.
+//! The server answers with the offset it actually starts from, 0 when it no
+//! longer has that image and sends a new one instead.
+//!
+//! Panel data is flow controlled by the frame: the hello grants a window of
+//! bytes the server may send ahead, and the frame acks with the `u32` offset
+//! it got to as it makes room, see [`crate::transfer::send_windowed`].
+
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+
+use crate::{app_data::Checksums, codec::Encoding};
+
+pub const MAGIC: [u8; 3] = *b"SPF";
+pub const VERSION: u8 = 6;
+pub const HELLO_TIMEOUT: Duration = Duration::from_millis(500);
+pub const CHALLENGE_LEN: usize = 16;
+pub const RESPONSE_LEN: usize = 32;
+/// Sent instead of a challenge when the frame needs no key.
+pub const NO_CHALLENGE: [u8; CHALLENGE_LEN] = [0; CHALLENGE_LEN];
+/// How long the image sent to a device is kept for it to resume.
+pub const RESUME_WINDOW: Duration = Duration::from_secs(120);
+
+/// Identifies a frame across connections, its Wi-Fi MAC address.
+pub type DeviceId = [u8; 6];
+
+/// `MAGIC`, `VERSION`, a bit mask of the encodings the frame can decode, its
+/// device id, the image checksum and offset to resume from, and the window it
+/// grants. An offset of 0 asks for a new image.
+pub struct Hello {
+    pub encodings: u8,
+    pub device: DeviceId,
+    pub image: u32,
+    pub offset: u32,
+    pub window: u32,
+}
+
+impl Hello {
+    pub const LEN: usize = 23;
+
+    pub fn parse(bytes: &[u8; Self::LEN]) -> Result<Self> {
+        if bytes[..3] != MAGIC {
+            return Err(anyhow!("Hello doesn't start with the protocol magic"));
+        }
+        if bytes[3] != VERSION {
+            return Err(anyhow!("Unsupported protocol version {}", bytes[3]));
+        }
+        Ok(Hello {
+            encodings: bytes[4],
+            device: bytes[5..11].try_into().unwrap(),
+            image: u32::from_le_bytes(bytes[11..15].try_into().unwrap()),
+            offset: u32::from_le_bytes(bytes[15..19].try_into().unwrap()),
+            window: u32::from_le_bytes(bytes[19..23].try_into().unwrap()),
+        })
+    }
+
+    pub fn supports(&self, encoding: Encoding) -> bool {
+        self.encodings & encoding.mask() != 0
+    }
+}
+
+/// `MAGIC`, `VERSION`, the encoding, the encoded length of each panel, the
+/// checksums of the left panel, the right panel and the whole image, and the
+/// offset the panel data that follows starts at.
+pub struct FrameHeader {
+    pub encoding: Encoding,
+    pub left_len: u32,
+    pub right_len: u32,
+    pub checksums: Checksums,
+    pub offset: u32,
+}
+
+impl FrameHeader {
+    pub const LEN: usize = 29;
+
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let mut bytes = [0u8; Self::LEN];
+        bytes[..3].copy_from_slice(&MAGIC);
+        bytes[3] = VERSION;
+        bytes[4] = self.encoding as u8;
+        bytes[5..9].copy_from_slice(&self.left_len.to_le_bytes());
+        bytes[9..13].copy_from_slice(&self.right_len.to_le_bytes());
+        bytes[13..17].copy_from_slice(&self.checksums.left.to_le_bytes());
+        bytes[17..21].copy_from_slice(&self.checksums.right.to_le_bytes());
+        bytes[21..25].copy_from_slice(&self.checksums.image.to_le_bytes());
+        bytes[25..29].copy_from_slice(&self.offset.to_le_bytes());
+        bytes
+    }
+}
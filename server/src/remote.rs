@@ -0,0 +1,83 @@
+//! Requests to the servers photos come from, with timeouts, retries and a size
+//! limit.
+
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+use reqwest::{ClientBuilder, RequestBuilder, Response, StatusCode};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// For a whole request, downloading the body included.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(120);
+const ATTEMPTS: u32 = 3;
+/// Doubles after every failed attempt.
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Larger photos are refused rather than read into memory.
+pub const MAX_BODY_LEN: usize = 256 * 1024 * 1024;
+
+/// A client builder with the timeouts set.
+pub fn client() -> ClientBuilder {
+    ClientBuilder::new()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .timeout(REQUEST_TIMEOUT)
+}
+
+/// Sends the request, trying again after a pause when it can't connect, times
+/// out or the server has a temporary problem.
+pub async fn send(request: RequestBuilder) -> reqwest::Result<Response> {
+    let mut delay = FIRST_RETRY_DELAY;
+    for _ in 1..ATTEMPTS {
+        // Requests with a streaming body can't be repeated
+        let Some(attempt) = request.try_clone() else {
+            break;
+        };
+        match attempt.send().await {
+            Ok(response) if !is_temporary(response.status()) => return Ok(response),
+            Ok(response) => println!(
+                "{} answered {}, retrying in {delay:?}",
+                response.url(),
+                response.status()
+            ),
+            Err(e) if e.is_connect() || e.is_timeout() => {
+                println!("Request failed, retrying in {delay:?}: {e}")
+            }
+            Err(e) => return Err(e),
+        }
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+    }
+    request.send().await
+}
+
+/// Reads the body of a response, refusing error pages and bodies larger than
+/// [`MAX_BODY_LEN`].
+pub async fn bytes(mut response: Response) -> Result<Vec<u8>> {
+    let url = response.url().clone();
+    let status = response.status();
+    if !status.is_success() {
+        return Err(anyhow!("Fetching {url} failed with {status}"));
+    }
+    let too_large = || anyhow!("{url} is larger than {MAX_BODY_LEN} bytes");
+    let len = response.content_length().unwrap_or_default() as usize;
+    if len > MAX_BODY_LEN {
+        return Err(too_large());
+    }
+
+    // Straight into one buffer as the chunks arrive
+    let mut body = Vec::with_capacity(len);
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .map_err(|e| anyhow!("Failed to fetch {url}: {e}"))?
+    {
+        if body.len() + chunk.len() > MAX_BODY_LEN {
+            return Err(too_large());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+fn is_temporary(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
@@ -0,0 +1,65 @@
+//! Where the photos in the pool come from. Every album reads from one source.
+
+use std::sync::Arc;
+
+use anyhow::{Result, anyhow};
+use futures::future::BoxFuture;
+
+use crate::{
+    config::Config,
+    directory::DirectorySource,
+    feed::FeedSource,
+    immich::{Immich, ImmichAlbum},
+    s3::S3Source,
+    webdav::WebDavSource,
+};
+
+/// A photo as its source lists it.
+pub struct SourcePhoto {
+    /// Identifies the photo within its source.
+    pub id: String,
+    /// Changes whenever the photo does, so unchanged photos aren't processed again.
+    pub version: String,
+}
+
+pub trait PhotoSource: Send + Sync {
+    /// Describes the source in logs and the admin API.
+    fn name(&self) -> String;
+
+    /// Lists the photos the source has now.
+    fn list(&self) -> BoxFuture<'_, Result<Vec<SourcePhoto>>>;
+
+    /// Downloads a listed photo.
+    fn fetch<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<Vec<u8>>>;
+
+    /// Resolves once the source changed since it was last listed. Sources that
+    /// can't tell never resolve and are listed again on schedule.
+    fn changed(&self) -> BoxFuture<'_, ()> {
+        Box::pin(futures::future::pending())
+    }
+}
+
+/// Opens the source of every album, in the order of `config.albums`.
+pub fn open_all(config: &Config, immich: Immich) -> Result<Vec<Box<dyn PhotoSource>>> {
+    let immich = Arc::new(immich);
+    config
+        .albums
+        .iter()
+        .map(|album| -> Result<Box<dyn PhotoSource>> {
+            if let Some(directory) = &album.directory {
+                return Ok(Box::new(DirectorySource::new(directory)?));
+            }
+            if let Some(webdav) = &album.webdav {
+                return Ok(Box::new(WebDavSource::new(webdav)?));
+            }
+            if let Some(s3) = &album.s3 {
+                return Ok(Box::new(S3Source::new(s3)?));
+            }
+            if let Some(feed) = &album.feed {
+                return Ok(Box::new(FeedSource::new(feed)?));
+            }
+            let id = album.id.ok_or_else(|| anyhow!("Album without a source"))?;
+            Ok(Box::new(ImmichAlbum::new(Arc::clone(&immich), id)))
+        })
+        .collect()
+}
@@ -0,0 +1,173 @@
+//! Sending panel data with the client's acknowledgements as flow control.
+//!
+//! Public so the transfer benchmark can drive it against a simulated frame.
+
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Bytes per write while the legacy client acks every chunk.
+const LEGACY_CHUNK: usize = 500;
+/// Largest single write in the windowed transfer.
+const CHUNK: usize = 1024;
+
+/// Sends the buffer in small chunks, waiting for the client's "Ok" after each.
+///
+/// Only frames from before the handshake need this.
+pub async fn send_buffer<S>(socket: &mut S, buffer: &[u8]) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut read_buff = [0u8; 1024];
+
+    for chunk in buffer.chunks(LEGACY_CHUNK) {
+        socket.write_all(chunk).await?;
+        socket.flush().await?;
+        if socket.read(&mut read_buff).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+    Ok(())
+}
+
+/// Sends `parts` back to back, keeping at most `window` bytes unacknowledged.
+///
+/// Positions count from the start of the whole stream, `start` being where
+/// `parts` begin. The client acks with the little endian `u32` position it
+/// has received up to, and the transfer is done once it acked the end.
+pub async fn send_windowed<S>(
+    socket: &mut S,
+    parts: &[&[u8]],
+    start: usize,
+    window: usize,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if window == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Client granted an empty window",
+        ));
+    }
+
+    let mut sent = start;
+    let mut acked = start;
+    for part in parts {
+        let mut part = *part;
+        while !part.is_empty() {
+            if sent - acked == window {
+                socket.flush().await?;
+                acked = read_ack(socket, acked, sent).await?;
+                continue;
+            }
+
+            let n = part.len().min(window - (sent - acked)).min(CHUNK);
+            socket.write_all(&part[..n]).await?;
+            part = &part[n..];
+            sent += n;
+        }
+    }
+
+    socket.flush().await?;
+    while acked < sent {
+        acked = read_ack(socket, acked, sent).await?;
+    }
+    Ok(())
+}
+
+async fn read_ack<S>(socket: &mut S, acked: usize, sent: usize) -> io::Result<usize>
+where
+    S: AsyncRead + Unpin,
+{
+    let position = socket.read_u32_le().await? as usize;
+    if position < acked || position > sent {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Ack for byte {position} outside of the window {acked}..={sent}"),
+        ));
+    }
+    Ok(position)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::DuplexStream;
+
+    use super::*;
+
+    /// Reads from `start` to `end` like a frame that only acks once the window
+    /// is full, checking the server never sends past it.
+    async fn receive(frame: &mut DuplexStream, start: usize, end: usize, window: usize) -> Vec<u8> {
+        let mut received = Vec::new();
+        let mut acked = start;
+        let mut buffer = [0u8; 300];
+        while acked < end {
+            let n = frame.read(&mut buffer).await.unwrap();
+            assert_ne!(n, 0, "closed after {} bytes", received.len());
+            received.extend_from_slice(&buffer[..n]);
+            let position = start + received.len();
+            assert!(
+                position - acked <= window,
+                "{} bytes outstanding",
+                position - acked
+            );
+            if position - acked == window || position == end {
+                frame.write_u32_le(position as u32).await.unwrap();
+                acked = position;
+            }
+        }
+        received
+    }
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn keeps_to_the_window() {
+        let (left, right) = (data(5000), data(3000));
+        for window in [1, 700, 1024, 4096, 10_000] {
+            let (mut frame, mut socket) = tokio::io::duplex(64 * 1024);
+            let parts: [&[u8]; 2] = [&left, &right];
+            let client = async { receive(&mut frame, 0, 8000, window).await };
+            let (sent, received) =
+                tokio::join!(send_windowed(&mut socket, &parts, 0, window), client);
+            sent.unwrap();
+            assert_eq!(
+                received,
+                [&left[..], &right[..]].concat(),
+                "window {window}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_the_tail_from_an_offset() {
+        let (left, right) = (data(5000), data(3000));
+        let (mut frame, mut socket) = tokio::io::duplex(64 * 1024);
+        let parts: [&[u8]; 2] = [&left[3500..], &right];
+        let client = async { receive(&mut frame, 3500, 8000, 1024).await };
+        let (sent, received) = tokio::join!(send_windowed(&mut socket, &parts, 3500, 1024), client);
+        sent.unwrap();
+        assert_eq!(received, [&left[3500..], &right[..]].concat());
+    }
+
+    #[tokio::test]
+    async fn rejects_acks_outside_the_window() {
+        let data = data(300);
+        // Backwards, past what was sent, and before the start of a resumed transfer
+        for (start, acks) in [(0, [50, 40]), (0, [50, 10_000]), (1000, [1050, 500])] {
+            let (mut frame, mut socket) = tokio::io::duplex(64 * 1024);
+            let client = async {
+                let mut buffer = [0u8; 100];
+                frame.read_exact(&mut buffer).await.unwrap();
+                for ack in acks {
+                    frame.write_u32_le(ack).await.unwrap();
+                }
+            };
+            let parts: [&[u8]; 1] = [&data];
+            let (sent, _) = tokio::join!(send_windowed(&mut socket, &parts, start, 100), client);
+            let error = sent.unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "acks {acks:?}");
+        }
+    }
+}
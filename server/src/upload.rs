@@ -0,0 +1,283 @@
+//! Photos pushed straight to the server instead of coming from an album, as
+//! `multipart/form-data` to `POST /api/uploads` or with `server upload`.
+//!
+//! The form has the file as `photo` and optionally:
+//!
+//! - `device`: MAC address of a frame to show it on at its next wake.
+//! - `expires_after`: seconds after which it leaves the rotation.
+//! - `displays`: how many times it's shown before it leaves the rotation.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+};
+
+use anyhow::{Result, anyhow};
+use reqwest::header;
+use uuid::Uuid;
+
+use crate::{auth, config::Config, protocol::DeviceId};
+
+const USAGE: &str =
+    "usage: server upload <photo> [--device <mac>] [--expires-after <secs>] [--displays <n>]";
+
+/// An uploaded photo with what should happen to it.
+pub struct UploadForm {
+    /// File name it was uploaded as.
+    pub name: String,
+    pub photo: Vec<u8>,
+    pub device: Option<DeviceId>,
+    pub expires_after_secs: Option<u64>,
+    pub displays: Option<u32>,
+}
+
+struct Part<'a> {
+    name: String,
+    filename: Option<String>,
+    data: &'a [u8],
+}
+
+impl UploadForm {
+    /// Reads the form from a request body with the given `Content-Type`.
+    pub fn parse(content_type: &str, body: &[u8]) -> Result<Self> {
+        let mut photo = None;
+        let mut device = None;
+        let mut expires_after_secs = None;
+        let mut displays = None;
+        for part in parse_multipart(content_type, body)? {
+            let text = || {
+                std::str::from_utf8(part.data)
+                    .map(str::trim)
+                    .map_err(|_| anyhow!("Field {} isn't text", part.name))
+            };
+            match part.name.as_str() {
+                "photo" => photo = Some((part.filename.unwrap_or_default(), part.data.to_vec())),
+                "device" => {
+                    let mac = text()?;
+                    device = Some(
+                        auth::parse_mac(mac)
+                            .ok_or_else(|| anyhow!("Device {mac} isn't a MAC address"))?,
+                    );
+                }
+                "expires_after" => {
+                    expires_after_secs = Some(
+                        text()?
+                            .parse()
+                            .map_err(|e| anyhow!("Invalid expires_after: {e}"))?,
+                    );
+                }
+                "displays" => {
+                    displays = Some(
+                        text()?
+                            .parse()
+                            .map_err(|e| anyhow!("Invalid displays: {e}"))?,
+                    );
+                }
+                name => return Err(anyhow!("Unknown field {name}")),
+            }
+        }
+
+        let (name, photo) = photo.ok_or_else(|| anyhow!("The form has no photo"))?;
+        if displays == Some(0) {
+            return Err(anyhow!("displays must be at least 1"));
+        }
+        Ok(UploadForm {
+            name,
+            photo,
+            device,
+            expires_after_secs,
+            displays,
+        })
+    }
+
+    /// Encodes the form as `multipart/form-data`, returning the body and its `Content-Type`.
+    pub fn to_multipart(&self) -> (Vec<u8>, String) {
+        let boundary = format!("upload-{}", Uuid::new_v4().simple());
+        let mut body = Vec::new();
+        let mut field = |disposition: String, data: &[u8]| {
+            body.extend_from_slice(
+                format!("--{boundary}\r\nContent-Disposition: form-data; {disposition}\r\n\r\n")
+                    .as_bytes(),
+            );
+            body.extend_from_slice(data);
+            body.extend_from_slice(b"\r\n");
+        };
+
+        let name = self.name.replace(['"', '\r', '\n'], "_");
+        field(format!("name=\"photo\"; filename=\"{name}\""), &self.photo);
+        if let Some(device) = &self.device {
+            field(
+                "name=\"device\"".to_string(),
+                auth::format_mac(device).as_bytes(),
+            );
+        }
+        if let Some(secs) = self.expires_after_secs {
+            field(
+                "name=\"expires_after\"".to_string(),
+                secs.to_string().as_bytes(),
+            );
+        }
+        if let Some(displays) = self.displays {
+            field(
+                "name=\"displays\"".to_string(),
+                displays.to_string().as_bytes(),
+            );
+        }
+        body.extend_from_slice(format!("--{boundary}--\r\n").as_bytes());
+        (body, format!("multipart/form-data; boundary={boundary}"))
+    }
+}
+
+/// `server upload`: sends a photo to the admin API of the server running with
+/// the same config.
+pub async fn run_cli(config: &Config, mut args: impl Iterator<Item = String>) -> Result<()> {
+    let mut path = None;
+    let mut device = None;
+    let mut expires_after_secs = None;
+    let mut displays = None;
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| anyhow!("{arg} needs a value\n{USAGE}"))
+        };
+        match arg.as_str() {
+            "--device" => {
+                let mac = value()?;
+                device = Some(
+                    auth::parse_mac(&mac)
+                        .ok_or_else(|| anyhow!("Device {mac} isn't a MAC address"))?,
+                );
+            }
+            "--expires-after" => {
+                expires_after_secs = Some(
+                    value()?
+                        .parse()
+                        .map_err(|e| anyhow!("Invalid --expires-after: {e}"))?,
+                );
+            }
+            "--displays" => {
+                displays = Some(
+                    value()?
+                        .parse()
+                        .map_err(|e| anyhow!("Invalid --displays: {e}"))?,
+                );
+            }
+            _ if arg.starts_with('-') || path.is_some() => {
+                return Err(anyhow!("Unexpected argument {arg}\n{USAGE}"));
+            }
+            _ => path = Some(PathBuf::from(arg)),
+        }
+    }
+    let path = path.ok_or_else(|| anyhow!("No photo given\n{USAGE}"))?;
+    let admin = config
+        .server
+        .admin
+        .as_ref()
+        .ok_or_else(|| anyhow!("Uploading needs the admin API, set up [server.admin]"))?;
+
+    let form = UploadForm {
+        name: path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        photo: std::fs::read(&path)
+            .map_err(|e| anyhow!("Failed to read {}: {e}", path.display()))?,
+        device,
+        expires_after_secs,
+        displays,
+    };
+    let (body, content_type) = form.to_multipart();
+
+    // The server listens on every address it binds, the first one will do
+    let address = match config.server.bind.first() {
+        Some(IpAddr::V4(address)) if address.is_unspecified() => Ipv4Addr::LOCALHOST.into(),
+        Some(IpAddr::V6(address)) if address.is_unspecified() => Ipv6Addr::LOCALHOST.into(),
+        Some(address) => *address,
+        None => Ipv4Addr::LOCALHOST.into(),
+    };
+    let url = format!(
+        "http://{}/api/uploads",
+        SocketAddr::new(address, admin.port)
+    );
+    let response = reqwest::Client::new()
+        .post(&url)
+        .bearer_auth(&admin.token)
+        .header(header::CONTENT_TYPE, content_type)
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| anyhow!("Failed to reach the server at {url}: {e}"))?;
+    let status = response.status();
+    let text = response
+        .text()
+        .await
+        .map_err(|e| anyhow!("Failed to read the server's answer: {e}"))?;
+    if !status.is_success() {
+        return Err(anyhow!(
+            "Server refused the upload with {status}: {}",
+            text.trim()
+        ));
+    }
+    println!("Uploaded {}: {}", path.display(), text.trim());
+    Ok(())
+}
+
+/// Splits a `multipart/form-data` body into its parts.
+fn parse_multipart<'a>(content_type: &str, body: &'a [u8]) -> Result<Vec<Part<'a>>> {
+    let mut params = content_type.split(';').map(str::trim);
+    if !params
+        .next()
+        .is_some_and(|mime| mime.eq_ignore_ascii_case("multipart/form-data"))
+    {
+        return Err(anyhow!("Expected multipart/form-data, got {content_type}"));
+    }
+    let boundary = params
+        .find_map(|param| param.strip_prefix("boundary="))
+        .map(|boundary| boundary.trim_matches('"'))
+        .filter(|boundary| !boundary.is_empty())
+        .ok_or_else(|| anyhow!("multipart/form-data without a boundary"))?;
+
+    // Every delimiter but the first follows a line break
+    let delimiter = format!("\r\n--{boundary}");
+    let start = find(body, &delimiter.as_bytes()[2..])
+        .ok_or_else(|| anyhow!("Body doesn't contain the boundary"))?;
+    let mut rest = &body[start + delimiter.len() - 2..];
+    let mut parts = Vec::new();
+    while !rest.starts_with(b"--") {
+        let truncated = || anyhow!("Multipart body ends early");
+        rest = rest.strip_prefix(b"\r\n").ok_or_else(truncated)?;
+        let headers_end = find(rest, b"\r\n\r\n").ok_or_else(truncated)?;
+        let headers = std::str::from_utf8(&rest[..headers_end])
+            .map_err(|_| anyhow!("Part headers aren't text"))?;
+        rest = &rest[headers_end + 4..];
+        let data_end = find(rest, delimiter.as_bytes()).ok_or_else(truncated)?;
+        let data = &rest[..data_end];
+        rest = &rest[data_end + delimiter.len()..];
+
+        let disposition = headers
+            .split("\r\n")
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-disposition"))
+            .map(|(_, value)| value)
+            .ok_or_else(|| anyhow!("Part without a Content-Disposition"))?;
+        let param = |wanted: &str| {
+            disposition
+                .split(';')
+                .filter_map(|param| param.trim().split_once('='))
+                .find(|(name, _)| name.eq_ignore_ascii_case(wanted))
+                .map(|(_, value)| value.trim_matches('"').to_string())
+        };
+        parts.push(Part {
+            name: param("name").ok_or_else(|| anyhow!("Part without a name"))?,
+            filename: param("filename"),
+            data,
+        });
+    }
+    Ok(parts)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
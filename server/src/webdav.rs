@@ -0,0 +1,193 @@
+//! Photos from a WebDAV collection, such as a Nextcloud folder.
+//!
+//! Folders are walked with one `PROPFIND` at `Depth: 1` each, as Nextcloud
+//! refuses infinite depth. Files with an image content type are shown, and
+//! their ETag tells a refresh which ones changed.
+
+use anyhow::{Result, anyhow};
+use futures::future::BoxFuture;
+use percent_encoding::percent_decode_str;
+use reqwest::{Method, RequestBuilder, StatusCode, Url, header};
+
+use crate::{
+    config::WebDavConfig,
+    remote,
+    source::{PhotoSource, SourcePhoto},
+    xml,
+};
+
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:">
+  <d:prop><d:resourcetype/><d:getetag/><d:getcontenttype/></d:prop>
+</d:propfind>
+"#;
+
+pub struct WebDavSource {
+    config: WebDavConfig,
+    /// The collection, ending in a slash.
+    root: Url,
+    client: reqwest::Client,
+}
+
+/// A file or folder in a listing, by its path below the root.
+struct Entry {
+    path: String,
+    folder: bool,
+    etag: String,
+    content_type: String,
+}
+
+impl WebDavSource {
+    pub fn new(config: &WebDavConfig) -> Result<Self> {
+        let mut root = Url::parse(&config.url)
+            .map_err(|e| anyhow!("Invalid WebDAV url {}: {e}", config.url))?;
+        if !matches!(root.scheme(), "http" | "https") {
+            return Err(anyhow!("WebDAV url {} isn't http or https", config.url));
+        }
+        if !root.path().ends_with('/') {
+            root.set_path(&format!("{}/", root.path()));
+        }
+
+        Ok(WebDavSource {
+            config: config.clone(),
+            root,
+            client: remote::client()
+                .build()
+                .map_err(|e| anyhow!("Failed to create the WebDAV client: {e}"))?,
+        })
+    }
+
+    /// The url of a path below the root, a folder's ending in a slash.
+    fn url(&self, path: &str, folder: bool) -> Url {
+        let mut url = self.root.clone();
+        if let Ok(mut segments) = url.path_segments_mut() {
+            segments.pop_if_empty();
+            if !path.is_empty() {
+                segments.extend(path.split('/'));
+            }
+            if folder {
+                segments.push("");
+            }
+        }
+        url
+    }
+
+    /// Where a url from a listing is below the root, none when it's outside.
+    fn path(&self, url: &Url) -> Option<String> {
+        let path = url.path().strip_prefix(self.root.path())?;
+        let segments: Option<Vec<_>> = path
+            .trim_end_matches('/')
+            .split('/')
+            .map(|segment| percent_decode_str(segment).decode_utf8().ok())
+            .collect();
+        Some(segments?.join("/"))
+    }
+
+    fn request(&self, method: Method, url: Url) -> RequestBuilder {
+        let request = self.client.request(method, url);
+        match &self.config.username {
+            Some(username) => request.basic_auth(username, self.config.password.as_ref()),
+            None => request,
+        }
+    }
+
+    /// Lists what's directly inside the folder.
+    async fn propfind(&self, folder: &str) -> Result<Vec<Entry>> {
+        let url = self.url(folder, true);
+        let request = self
+            .request(Method::from_bytes(b"PROPFIND").unwrap(), url.clone())
+            .header("Depth", "1")
+            .header(header::CONTENT_TYPE, "application/xml; charset=utf-8")
+            .body(PROPFIND_BODY);
+        let response = remote::send(request)
+            .await
+            .map_err(|e| anyhow!("Failed to list {url}: {e}"))?;
+        if response.status() != StatusCode::MULTI_STATUS {
+            return Err(anyhow!("Listing {url} failed with {}", response.status()));
+        }
+        let body = response
+            .text()
+            .await
+            .map_err(|e| anyhow!("Failed to read the listing of {url}: {e}"))?;
+        let multistatus =
+            xml::parse(&body).map_err(|e| anyhow!("Invalid listing of {url}: {e}"))?;
+
+        let mut entries = Vec::new();
+        for response in multistatus.children("response") {
+            let Some(path) = response
+                .child("href")
+                .and_then(|href| url.join(href.text.trim()).ok())
+                .and_then(|href| self.path(&href))
+                .filter(|path| path != folder)
+            else {
+                continue;
+            };
+            // Properties the server doesn't have come back in a propstat of their own
+            let Some(prop) = response
+                .children("propstat")
+                .filter(|propstat| {
+                    propstat
+                        .child("status")
+                        .is_none_or(|status| status.text.contains(" 200 "))
+                })
+                .find_map(|propstat| propstat.child("prop"))
+            else {
+                continue;
+            };
+            let text = |name| {
+                prop.child(name)
+                    .map(|property| property.text.trim().to_string())
+                    .unwrap_or_default()
+            };
+            entries.push(Entry {
+                path,
+                folder: prop
+                    .child("resourcetype")
+                    .is_some_and(|resource_type| resource_type.child("collection").is_some()),
+                etag: text("getetag"),
+                content_type: text("getcontenttype"),
+            });
+        }
+        Ok(entries)
+    }
+}
+
+impl PhotoSource for WebDavSource {
+    fn name(&self) -> String {
+        self.root.to_string()
+    }
+
+    fn list(&self) -> BoxFuture<'_, Result<Vec<SourcePhoto>>> {
+        Box::pin(async move {
+            let mut photos = Vec::new();
+            let mut folders = vec![String::new()];
+            while let Some(folder) = folders.pop() {
+                for entry in self.propfind(&folder).await? {
+                    if entry.folder {
+                        folders.push(entry.path);
+                    } else if entry.content_type.starts_with("image/") {
+                        photos.push(SourcePhoto {
+                            id: entry.path,
+                            version: entry.etag,
+                        });
+                    }
+                }
+            }
+            photos.sort_by(|a, b| a.id.cmp(&b.id));
+            Ok(photos)
+        })
+    }
+
+    fn fetch<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<Vec<u8>>> {
+        Box::pin(async move {
+            let url = self.url(id, false);
+            let response = remote::send(self.request(Method::GET, url.clone()))
+                .await
+                .map_err(|e| anyhow!("Failed to fetch {url}: {e}"))?;
+            if !response.status().is_success() {
+                return Err(anyhow!("Fetching {url} failed with {}", response.status()));
+            }
+            remote::bytes(response).await
+        })
+    }
+}
@@ -0,0 +1,207 @@
+//! Just enough XML to read WebDAV and S3 listings and feeds: a tree of elements by local name,
+//! with their attributes and text. Namespaces, DTDs and processing
+//! instructions are ignored.
+
+use anyhow::{Result, anyhow};
+
+/// Deeper documents are refused rather than risking the stack.
+const MAX_DEPTH: usize = 64;
+
+#[derive(Default)]
+pub struct Element {
+    /// Name without its namespace prefix.
+    pub name: String,
+    /// By name without their namespace prefix, values unescaped.
+    pub attributes: Vec<(String, String)>,
+    pub children: Vec<Element>,
+    /// The text directly inside the element, unescaped.
+    pub text: String,
+}
+
+impl Element {
+    /// The first child with the given name.
+    pub fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|child| child.name == name)
+    }
+
+    /// Every child with the given name.
+    pub fn children<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Element> {
+        self.children.iter().filter(move |child| child.name == name)
+    }
+
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(attribute, _)| attribute == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Parses a document into its root element.
+pub fn parse(xml: &str) -> Result<Element> {
+    let mut parser = Parser { rest: xml };
+    parser.skip_misc()?;
+    let root = parser.element(0)?;
+    parser.skip_misc()?;
+    if !parser.rest.is_empty() {
+        return Err(anyhow!("XML continues after the root element"));
+    }
+    Ok(root)
+}
+
+struct Parser<'a> {
+    rest: &'a str,
+}
+
+impl Parser<'_> {
+    /// Skips whitespace, comments, processing instructions and the doctype.
+    fn skip_misc(&mut self) -> Result<()> {
+        loop {
+            self.rest = self.rest.trim_start();
+            if self.rest.starts_with("<!--") {
+                self.skip_past("-->")?;
+            } else if self.rest.starts_with("<?") {
+                self.skip_past("?>")?;
+            } else if self.rest.starts_with("<!") {
+                self.skip_past(">")?;
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn skip_past(&mut self, end: &str) -> Result<&str> {
+        let index = self
+            .rest
+            .find(end)
+            .ok_or_else(|| anyhow!("XML ends before {end}"))?;
+        let skipped = &self.rest[..index];
+        self.rest = &self.rest[index + end.len()..];
+        Ok(skipped)
+    }
+
+    fn name(&mut self) -> Result<String> {
+        let end = self
+            .rest
+            .find(|c: char| c.is_whitespace() || matches!(c, '/' | '>' | '='))
+            .unwrap_or(self.rest.len());
+        let name = &self.rest[..end];
+        if name.is_empty() {
+            return Err(anyhow!("Expected an XML name"));
+        }
+        self.rest = &self.rest[end..];
+        Ok(local_name(name).to_string())
+    }
+
+    fn element(&mut self, depth: usize) -> Result<Element> {
+        if depth > MAX_DEPTH {
+            return Err(anyhow!("XML nests deeper than {MAX_DEPTH} elements"));
+        }
+        self.rest = self
+            .rest
+            .strip_prefix('<')
+            .ok_or_else(|| anyhow!("Expected an XML element"))?;
+        let mut element = Element {
+            name: self.name()?,
+            ..Default::default()
+        };
+
+        loop {
+            self.rest = self.rest.trim_start();
+            if let Some(rest) = self.rest.strip_prefix("/>") {
+                self.rest = rest;
+                return Ok(element);
+            }
+            if let Some(rest) = self.rest.strip_prefix('>') {
+                self.rest = rest;
+                break;
+            }
+            let name = self.name()?;
+            self.rest = self
+                .rest
+                .trim_start()
+                .strip_prefix('=')
+                .ok_or_else(|| anyhow!("Attribute {name} has no value"))?
+                .trim_start();
+            let quote = self
+                .rest
+                .chars()
+                .next()
+                .filter(|c| matches!(c, '"' | '\''))
+                .ok_or_else(|| anyhow!("Attribute {name} isn't quoted"))?;
+            self.rest = &self.rest[1..];
+            let value = unescape(self.skip_past(&quote.to_string())?);
+            element.attributes.push((name, value));
+        }
+
+        loop {
+            let text_end = self.rest.find('<').unwrap_or(self.rest.len());
+            element.text.push_str(&unescape(&self.rest[..text_end]));
+            self.rest = &self.rest[text_end..];
+            if self.rest.is_empty() {
+                return Err(anyhow!("XML ends inside {}", element.name));
+            } else if let Some(rest) = self.rest.strip_prefix("</") {
+                self.rest = rest;
+                let name = self.name()?;
+                if name != element.name {
+                    return Err(anyhow!("{} is closed by {name}", element.name));
+                }
+                self.skip_past(">")?;
+                return Ok(element);
+            } else if let Some(rest) = self.rest.strip_prefix("<![CDATA[") {
+                self.rest = rest;
+                let text = self.skip_past("]]>")?;
+                element.text.push_str(text);
+            } else if self.rest.starts_with("<!--") {
+                self.skip_past("-->")?;
+            } else if self.rest.starts_with("<?") {
+                self.skip_past("?>")?;
+            } else {
+                element.children.push(self.element(depth + 1)?);
+            }
+        }
+    }
+}
+
+fn local_name(name: &str) -> &str {
+    name.rsplit(':').next().unwrap_or(name)
+}
+
+/// Replaces the predefined entities and character references.
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        unescaped.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find(';') else {
+            break;
+        };
+        let entity = &rest[1..end];
+        let character = match entity {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "amp" => Some('&'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => entity
+                .strip_prefix("#x")
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(str::parse))
+                .and_then(Result::ok)
+                .and_then(char::from_u32),
+        };
+        match character {
+            Some(character) => {
+                unescaped.push(character);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                unescaped.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
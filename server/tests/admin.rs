@@ -0,0 +1,484 @@
+//! The admin API against an in-process server with a hand-filled pool.
+
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+
+use reqwest::{Client, Method, StatusCode};
+use serde_json::{Value, json};
+use server::{
+    admin,
+    app_data::{AppData, Checksums, Frame, ProccessedImage},
+    codec::Encoding,
+    config::{AlbumConfig, Config, Dither, Fit},
+    frame_store::MemoryStore,
+    handle_client,
+    immich::Immich,
+    pipeline::Pipeline,
+    source,
+    upload::UploadForm,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+use uuid::Uuid;
+
+const TOKEN: &str = "test-token";
+const DEVICE: [u8; 6] = [0x02, 0, 0, 0, 0, 0x01];
+
+struct TestServer {
+    address: SocketAddr,
+    app_data: Arc<AppData>,
+    pipeline: Arc<Pipeline>,
+    blacklist: PathBuf,
+    processing: PathBuf,
+    client: Client,
+}
+
+impl TestServer {
+    /// Serves the admin API over a pool of the frames `a`, `b` and `c+d`, with
+    /// a second album of its own processing settings and no frames yet.
+    async fn start(name: &str) -> Self {
+        let blacklist =
+            std::env::temp_dir().join(format!("admin-{name}-{}.txt", std::process::id()));
+        let mut config = Config::default();
+        config.albums.push(AlbumConfig::immich(Uuid::nil()));
+        config.albums.push(AlbumConfig {
+            processing: Some(config.processing.clone()),
+            ..AlbumConfig::immich(Uuid::max())
+        });
+        let processing =
+            std::env::temp_dir().join(format!("admin-{name}-{}.toml", std::process::id()));
+        config.storage.blacklist = blacklist.clone();
+        config.storage.processing = processing.clone();
+
+        // Nothing listens there, the tests never reach Immich
+        let immich = Immich::new("http://127.0.0.1:9".to_string(), String::new());
+        let sources = source::open_all(&config, immich).unwrap();
+        let pipeline = Arc::new(Pipeline::new(sources, Arc::new(config)));
+        let app_data = Arc::new(AppData::new(Box::new(MemoryStore::default())));
+        for (seed, assets) in [vec!["a"], vec!["b"], vec!["c", "d"]]
+            .into_iter()
+            .enumerate()
+        {
+            let frame = Frame {
+                id: assets.join("+"),
+                album: 0,
+                assets: assets.into_iter().map(String::from).collect(),
+                template: None,
+                version: String::new(),
+            };
+            app_data.insert_image(frame, image(seed as u8)).await;
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let router = admin::router(Arc::clone(&app_data), Arc::clone(&pipeline), TOKEN.into());
+        tokio::spawn(admin::serve(listener, router));
+
+        TestServer {
+            address,
+            app_data,
+            pipeline,
+            blacklist,
+            processing,
+            client: Client::new(),
+        }
+    }
+
+    async fn request(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<Value>,
+    ) -> (StatusCode, Value) {
+        let mut request = self
+            .client
+            .request(method, format!("http://{}{path}", self.address))
+            .bearer_auth(TOKEN);
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+        let response = request.send().await.unwrap();
+        let status = response.status();
+        let text = response.text().await.unwrap();
+        (status, serde_json::from_str(&text).unwrap_or(Value::Null))
+    }
+
+    async fn image_ids(&self) -> Vec<String> {
+        let (status, images) = self.request(Method::GET, "/api/images", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let mut ids: Vec<String> = images
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|image| image["id"].as_str().unwrap().to_string())
+            .collect();
+        ids.sort();
+        ids
+    }
+}
+
+fn image(seed: u8) -> ProccessedImage {
+    let left = vec![seed; 64];
+    let right = vec![seed.wrapping_add(100); 64];
+    ProccessedImage {
+        encoding: Encoding::Raw,
+        checksums: Checksums::new(&left, &right),
+        left,
+        right,
+    }
+}
+
+/// Connects the way a frame does and returns the panels it was sent.
+async fn wake_frame(server: &TestServer) -> Vec<u8> {
+    let (mut frame, socket) = tokio::io::duplex(4096);
+    let app_data = Arc::clone(&server.app_data);
+    let pipeline = Arc::clone(&server.pipeline);
+    let serving = tokio::spawn(async move {
+        handle_client(socket, [127, 0, 0, 1].into(), &app_data, &pipeline).await
+    });
+
+    let mut hello = b"SPF\x06".to_vec();
+    hello.push(Encoding::Raw.mask() | Encoding::Lz.mask());
+    hello.extend_from_slice(&DEVICE);
+    hello.extend_from_slice(&[0; 8]);
+    hello.extend_from_slice(&8192u32.to_le_bytes());
+    frame.write_all(&hello).await.unwrap();
+
+    // Without keys configured there's nothing to answer
+    let mut challenge = [0xFFu8; 16];
+    frame.read_exact(&mut challenge).await.unwrap();
+    assert_eq!(challenge, [0; 16]);
+
+    let mut header = [0u8; 29];
+    frame.read_exact(&mut header).await.unwrap();
+    let field = |at: usize| u32::from_le_bytes(header[at..at + 4].try_into().unwrap()) as usize;
+    let mut panels = vec![0u8; field(5) + field(9)];
+    // Acknowledged as it arrives, the server waits once it has sent a window
+    for (index, chunk) in panels.chunks_mut(2048).enumerate() {
+        frame.read_exact(chunk).await.unwrap();
+        let received = index * 2048 + chunk.len();
+        frame
+            .write_all(&(received as u32).to_le_bytes())
+            .await
+            .unwrap();
+    }
+
+    serving.await.unwrap().unwrap();
+    panels
+}
+
+#[tokio::test]
+async fn rejects_requests_without_the_token() {
+    let server = TestServer::start("token").await;
+    let url = format!("http://{}/api/images", server.address);
+
+    let response = server.client.get(&url).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = server
+        .client
+        .get(&url)
+        .bearer_auth("wrong")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = server
+        .client
+        .get(&url)
+        .bearer_auth(TOKEN)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn lists_the_pool_with_asset_ids() {
+    let server = TestServer::start("list").await;
+    let (status, images) = server.request(Method::GET, "/api/images", None).await;
+
+    assert_eq!(status, StatusCode::OK);
+    let collage = images
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|image| image["id"] == "c+d")
+        .unwrap();
+    assert_eq!(collage["assets"], json!(["c", "d"]));
+    assert_eq!(collage["album"], json!(Uuid::nil()));
+    assert_eq!(server.image_ids().await, ["a", "b", "c+d"]);
+}
+
+#[tokio::test]
+async fn skips_an_image_until_the_next_refresh() {
+    let server = TestServer::start("skip").await;
+
+    let (status, _) = server
+        .request(Method::POST, "/api/images/b/skip", None)
+        .await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    assert_eq!(server.image_ids().await, ["a", "c+d"]);
+
+    let (status, _) = server
+        .request(Method::POST, "/api/images/b/skip", None)
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn blacklists_assets_across_restarts() {
+    let server = TestServer::start("blacklist").await;
+
+    let (status, _) = server.request(Method::PUT, "/api/blacklist/d", None).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    assert_eq!(server.image_ids().await, ["a", "b"]);
+    let (_, blacklist) = server.request(Method::GET, "/api/blacklist", None).await;
+    assert_eq!(blacklist, json!(["d"]));
+    let saved = admin::load_blacklist(&server.blacklist).unwrap();
+    assert!(saved.contains("d") && saved.len() == 1);
+
+    let (status, _) = server
+        .request(Method::DELETE, "/api/blacklist/d", None)
+        .await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    assert!(admin::load_blacklist(&server.blacklist).unwrap().is_empty());
+    let (status, _) = server
+        .request(Method::DELETE, "/api/blacklist/d", None)
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    std::fs::remove_file(&server.blacklist).unwrap();
+}
+
+#[tokio::test]
+async fn triggers_a_refresh() {
+    let server = TestServer::start("refresh").await;
+
+    let (status, _) = server.request(Method::POST, "/api/refresh", None).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    tokio::time::timeout(Duration::from_secs(1), server.app_data.refresh_requested())
+        .await
+        .expect("refresh loop wasn't woken up");
+}
+
+#[tokio::test]
+async fn sends_the_queued_image_on_the_next_wake() {
+    let server = TestServer::start("queue").await;
+    let path = "/api/devices/02:00:00:00:00:01/next";
+
+    let (status, _) = server
+        .request(Method::PUT, path, Some(json!({ "image": "missing" })))
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = server
+        .request(Method::PUT, path, Some(json!({ "image": "b" })))
+        .await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (_, devices) = server.request(Method::GET, "/api/devices", None).await;
+    assert_eq!(devices[0]["device"], "02:00:00:00:00:01");
+    assert_eq!(devices[0]["next"], "b");
+
+    let expected = image(1);
+    assert_eq!(
+        wake_frame(&server).await,
+        [expected.left, expected.right].concat()
+    );
+
+    let (_, devices) = server.request(Method::GET, "/api/devices", None).await;
+    let device = &devices[0];
+    assert_eq!(device["address"], "127.0.0.1");
+    assert_eq!(device["image"], "b");
+    assert_eq!(device["delivered"], true);
+    assert_eq!(device["next"], Value::Null);
+    assert!(device["last_seen"].as_u64().is_some());
+}
+
+#[tokio::test]
+async fn previews_images_as_mounted() {
+    let server = TestServer::start("preview").await;
+    // Four rows of panel memory, white on the left and red on the right
+    let (left, right) = (vec![0x11; 4 * 300], vec![0x33; 4 * 300]);
+    let frame = Frame {
+        id: "p".to_string(),
+        album: 0,
+        assets: vec!["p".to_string()],
+        template: None,
+        version: String::new(),
+    };
+    let preview = ProccessedImage {
+        encoding: Encoding::Raw,
+        checksums: Checksums::new(&left, &right),
+        left,
+        right,
+    };
+    server
+        .app_data
+        .insert_image(frame, preview.encoded(Encoding::Lz).unwrap())
+        .await;
+
+    let fetch = |path: &'static str| {
+        let request = server
+            .client
+            .get(format!("http://{}{path}", server.address))
+            .bearer_auth(TOKEN);
+        async move {
+            let response = request.send().await.unwrap();
+            (response.status(), response.bytes().await.unwrap())
+        }
+    };
+    let (status, png) = fetch("/api/images/p/preview").await;
+    assert_eq!(status, StatusCode::OK);
+    let picture = image::load_from_memory(&png).unwrap().to_rgb8();
+    // Landscape frames are turned back from the panel's portrait memory
+    assert_eq!(picture.dimensions(), (4, 1200));
+    assert_eq!(picture.get_pixel(0, 0).0, [255, 255, 255]);
+    assert_eq!(picture.get_pixel(3, 1199).0, [255, 0, 0]);
+
+    let path = "/api/devices/02:00:00:00:00:01/preview";
+    assert_eq!(fetch(path).await.0, StatusCode::NOT_FOUND);
+    server.app_data.record_image(DEVICE, Some("p".to_string()));
+    let (status, device_png) = fetch(path).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(device_png, png);
+
+    assert_eq!(
+        fetch("/api/images/missing/preview").await.0,
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn serves_the_dashboard_without_the_token() {
+    let server = TestServer::start("dashboard").await;
+
+    for (path, content_type) in [
+        ("/", "text/html"),
+        ("/dashboard.js", "text/javascript"),
+        ("/dashboard.css", "text/css"),
+    ] {
+        let response = server
+            .client
+            .get(format!("http://{}{path}", server.address))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let header = response.headers()["content-type"].to_str().unwrap();
+        assert!(header.starts_with(content_type), "{path} is {header}");
+    }
+}
+
+#[tokio::test]
+async fn saves_processing_settings_across_restarts() {
+    let server = TestServer::start("processing").await;
+    let own = Frame {
+        id: "e".to_string(),
+        album: 1,
+        assets: vec!["e".to_string()],
+        template: None,
+        version: String::new(),
+    };
+    server.app_data.insert_image(own, image(4)).await;
+
+    let (status, processing) = server.request(Method::GET, "/api/processing", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(processing["fit"], "crop");
+    assert_eq!(processing["dither"], "floyd_steinberg");
+
+    let invalid = json!({ "tone": [{ "op": "gamma", "value": 0.0 }] });
+    let (status, _) = server
+        .request(Method::PUT, "/api/processing", Some(invalid))
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(!server.processing.exists());
+
+    let settings = json!({
+        "fit": "contain",
+        "dither": "none",
+        "tone": [{ "op": "clahe", "tiles": 4, "clip_limit": 1.5 }],
+        "gamut": null,
+    });
+    let (status, _) = server
+        .request(Method::PUT, "/api/processing", Some(settings.clone()))
+        .await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (_, processing) = server.request(Method::GET, "/api/processing", None).await;
+    assert_eq!(processing, settings);
+    tokio::time::timeout(Duration::from_secs(1), server.app_data.refresh_requested())
+        .await
+        .expect("pool wasn't processed again");
+    // Only the album without settings of its own is processed again
+    assert!(server.app_data.get_image("a").await.is_none());
+    assert!(server.app_data.get_image("c+d").await.is_none());
+    assert!(server.app_data.get_image("e").await.is_some());
+
+    let saved = admin::load_processing(&server.processing).unwrap().unwrap();
+    assert_eq!(saved.fit, Fit::Contain);
+    assert_eq!(saved.dither, Dither::None);
+    assert!(saved.gamut.is_none() && saved.tone.len() == 1);
+
+    std::fs::remove_file(&server.processing).unwrap();
+}
+
+#[tokio::test]
+async fn uploads_a_photo_for_a_device() {
+    let server = TestServer::start("upload").await;
+    let mut photo = Vec::new();
+    image::RgbImage::from_pixel(40, 30, image::Rgb([200, 40, 40]))
+        .write_to(
+            &mut std::io::Cursor::new(&mut photo),
+            image::ImageFormat::Png,
+        )
+        .unwrap();
+    let upload = |form: UploadForm| {
+        let (body, content_type) = form.to_multipart();
+        server
+            .client
+            .post(format!("http://{}/api/uploads", server.address))
+            .bearer_auth(TOKEN)
+            .header("content-type", content_type)
+            .body(body)
+            .send()
+    };
+
+    let response = upload(UploadForm {
+        name: "card.txt".to_string(),
+        photo: b"not a photo".to_vec(),
+        device: None,
+        expires_after_secs: None,
+        displays: None,
+    })
+    .await
+    .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = upload(UploadForm {
+        name: "card.png".to_string(),
+        photo,
+        device: Some(DEVICE),
+        expires_after_secs: Some(3600),
+        displays: Some(1),
+    })
+    .await
+    .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let created: Value = response.json().await.unwrap();
+    let id = created["id"].as_str().unwrap();
+    let (_, uploads) = server.request(Method::GET, "/api/uploads", None).await;
+    assert_eq!(uploads[0]["id"], id);
+    assert_eq!(uploads[0]["name"], "card.png");
+    assert_eq!(uploads[0]["displays_left"], 1);
+    assert!(uploads[0]["expires_at"].as_u64().is_some());
+    let (status, _) = server
+        .request(Method::GET, &format!("/api/images/{id}/preview"), None)
+        .await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Shown once on the next wake, then it's used up
+    wake_frame(&server).await;
+    let (_, devices) = server.request(Method::GET, "/api/devices", None).await;
+    assert_eq!(devices[0]["image"], id);
+    let (_, uploads) = server.request(Method::GET, "/api/uploads", None).await;
+    assert_eq!(uploads, json!([]));
+}
@@ -0,0 +1,246 @@
+//! The frame protocol against `handle_client` over in-memory streams.
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Result;
+use hmac_sha256::HMAC;
+use server::{
+    app_data::{AppData, Checksums, Frame, ProccessedImage},
+    codec::Encoding,
+    config::{Config, DeviceConfig},
+    frame_store::MemoryStore,
+    handle_client,
+    pipeline::Pipeline,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, DuplexStream},
+    task::JoinHandle,
+};
+
+const KEY: &str = "frame key";
+const DEVICE: [u8; 6] = [0x02, 0, 0, 0, 0, 0x01];
+const KEYLESS: [u8; 6] = [0x02, 0, 0, 0, 0, 0x02];
+
+struct TestServer {
+    app_data: Arc<AppData>,
+    pipeline: Arc<Pipeline>,
+}
+
+impl TestServer {
+    /// A pool of one frame, for `DEVICE` with a key and `KEYLESS` without.
+    async fn start(config: Config) -> Self {
+        let pipeline = Arc::new(Pipeline::new(Vec::new(), Arc::new(config)));
+        let app_data = Arc::new(AppData::new(Box::new(MemoryStore::default())));
+        let frame = Frame {
+            id: "0:a".to_string(),
+            album: 0,
+            assets: vec!["0:a".to_string()],
+            template: None,
+            version: String::new(),
+        };
+        app_data.insert_image(frame, image()).await;
+        TestServer { app_data, pipeline }
+    }
+
+    /// Opens a connection the way a frame does.
+    fn connect(&self) -> (DuplexStream, JoinHandle<Result<()>>) {
+        let (frame, socket) = tokio::io::duplex(4096);
+        let app_data = Arc::clone(&self.app_data);
+        let pipeline = Arc::clone(&self.pipeline);
+        let serving = tokio::spawn(async move {
+            handle_client(socket, [127, 0, 0, 1].into(), &app_data, &pipeline).await
+        });
+        (frame, serving)
+    }
+}
+
+fn config() -> Config {
+    let mut config = Config::default();
+    config.auth.devices = vec![
+        DeviceConfig {
+            mac: DEVICE,
+            key: Some(KEY.to_string()),
+        },
+        DeviceConfig {
+            mac: KEYLESS,
+            key: None,
+        },
+    ];
+    config
+}
+
+/// Raw panels of 3000 and 2000 bytes, every byte telling where it is.
+fn image() -> ProccessedImage {
+    let left: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+    let right: Vec<u8> = (0..2000).map(|i| (i % 241) as u8).collect();
+    ProccessedImage {
+        encoding: Encoding::Raw,
+        checksums: Checksums::new(&left, &right),
+        left,
+        right,
+    }
+}
+
+fn hello(device: [u8; 6], image: u32, offset: u32) -> Vec<u8> {
+    let mut hello = b"SPF\x06".to_vec();
+    hello.push(Encoding::Raw.mask());
+    hello.extend_from_slice(&device);
+    hello.extend_from_slice(&image.to_le_bytes());
+    hello.extend_from_slice(&offset.to_le_bytes());
+    hello.extend_from_slice(&8192u32.to_le_bytes());
+    hello
+}
+
+fn field(header: &[u8; 29], at: usize) -> u32 {
+    u32::from_le_bytes(header[at..at + 4].try_into().unwrap())
+}
+
+/// Reads the frame header and the panel bytes after its offset, acking them.
+async fn receive(frame: &mut DuplexStream) -> ([u8; 29], Vec<u8>) {
+    let mut header = [0u8; 29];
+    frame.read_exact(&mut header).await.unwrap();
+    let offset = field(&header, 25) as usize;
+    let mut panels = vec![0u8; (field(&header, 5) + field(&header, 9)) as usize - offset];
+    for (index, chunk) in panels.chunks_mut(1024).enumerate() {
+        frame.read_exact(chunk).await.unwrap();
+        let received = offset + index * 1024 + chunk.len();
+        frame
+            .write_all(&(received as u32).to_le_bytes())
+            .await
+            .unwrap();
+    }
+    (header, panels)
+}
+
+#[tokio::test]
+async fn answers_the_challenge() {
+    let server = TestServer::start(config()).await;
+    let (mut frame, serving) = server.connect();
+    frame.write_all(&hello(DEVICE, 0, 0)).await.unwrap();
+
+    let mut challenge = [0u8; 16];
+    frame.read_exact(&mut challenge).await.unwrap();
+    assert_ne!(challenge, [0; 16]);
+    let response = HMAC::mac([&challenge[..], &DEVICE].concat(), KEY);
+    frame.write_all(&response).await.unwrap();
+
+    let (header, panels) = receive(&mut frame).await;
+    let image = image();
+    assert_eq!(field(&header, 21), image.checksums.image);
+    assert_eq!(panels, [image.left, image.right].concat());
+    serving.await.unwrap().unwrap();
+    assert!(server.app_data.device(&DEVICE).unwrap().delivered);
+}
+
+#[tokio::test]
+async fn refuses_wrong_answers() {
+    let server = TestServer::start(config()).await;
+    let (mut frame, serving) = server.connect();
+    frame.write_all(&hello(DEVICE, 0, 0)).await.unwrap();
+
+    let mut challenge = [0u8; 16];
+    frame.read_exact(&mut challenge).await.unwrap();
+    let response = HMAC::mac([&challenge[..], &DEVICE].concat(), "wrong key");
+    frame.write_all(&response).await.unwrap();
+
+    assert!(serving.await.unwrap().is_err());
+    let mut rest = Vec::new();
+    frame.read_to_end(&mut rest).await.unwrap();
+    assert!(rest.is_empty());
+    assert!(server.app_data.device(&DEVICE).is_none());
+}
+
+#[tokio::test]
+async fn lets_keyless_devices_in_without_a_challenge() {
+    let server = TestServer::start(config()).await;
+    let (mut frame, serving) = server.connect();
+    frame.write_all(&hello(KEYLESS, 0, 0)).await.unwrap();
+
+    let mut challenge = [0xFFu8; 16];
+    frame.read_exact(&mut challenge).await.unwrap();
+    assert_eq!(challenge, [0; 16]);
+    let (_, panels) = receive(&mut frame).await;
+    assert_eq!(panels.len(), 5000);
+    serving.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn refuses_unknown_devices() {
+    let server = TestServer::start(config()).await;
+    let (mut frame, serving) = server.connect();
+    frame
+        .write_all(&hello([0x02, 0, 0, 0, 0, 0x03], 0, 0))
+        .await
+        .unwrap();
+
+    assert!(serving.await.unwrap().is_err());
+    let mut rest = Vec::new();
+    frame.read_to_end(&mut rest).await.unwrap();
+    assert!(rest.is_empty());
+}
+
+/// Starts sending the frame to `KEYLESS` and hangs up after `received` bytes
+/// of panels, leaving the image pinned for it.
+async fn interrupt(server: &TestServer, received: usize) -> ProccessedImage {
+    let (mut frame, serving) = server.connect();
+    frame.write_all(&hello(KEYLESS, 0, 0)).await.unwrap();
+    let mut header = [0u8; 16 + 29];
+    frame.read_exact(&mut header).await.unwrap();
+    let mut panels = vec![0u8; received];
+    frame.read_exact(&mut panels).await.unwrap();
+    drop(frame);
+    assert!(serving.await.unwrap().is_err());
+    image()
+}
+
+/// Reconnects as `KEYLESS` asking to resume, returns the offset the server
+/// starts from and the bytes it sends.
+async fn resume(server: &TestServer, image: u32, offset: u32) -> (u32, Vec<u8>) {
+    let (mut frame, serving) = server.connect();
+    frame
+        .write_all(&hello(KEYLESS, image, offset))
+        .await
+        .unwrap();
+    let mut challenge = [0u8; 16];
+    frame.read_exact(&mut challenge).await.unwrap();
+    let (header, panels) = receive(&mut frame).await;
+    serving.await.unwrap().unwrap();
+    (field(&header, 25), panels)
+}
+
+#[tokio::test]
+async fn resumes_interrupted_transfers() {
+    let server = TestServer::start(config()).await;
+    // Inside the left panel, at the start of the right one, inside it and at the very end
+    for offset in [1000, 3000, 3500, 5000] {
+        let image = interrupt(&server, offset as usize).await;
+        let (start, panels) = resume(&server, image.checksums.image, offset).await;
+        assert_eq!(start, offset);
+        let whole = [image.left, image.right].concat();
+        assert_eq!(panels, whole[offset as usize..], "offset {offset}");
+    }
+}
+
+#[tokio::test]
+async fn sends_the_whole_image_when_it_cant_resume() {
+    let server = TestServer::start(config()).await;
+
+    let image = interrupt(&server, 1000).await;
+    let (start, panels) = resume(&server, image.checksums.image ^ 1, 1000).await;
+    assert_eq!(start, 0);
+    assert_eq!(panels.len(), 5000);
+
+    let image = interrupt(&server, 1000).await;
+    let (start, panels) = resume(&server, image.checksums.image, 5001).await;
+    assert_eq!(start, 0);
+    assert_eq!(panels.len(), 5000);
+
+    // The pin lasts RESUME_WINDOW, two minutes
+    let image = interrupt(&server, 1000).await;
+    tokio::time::pause();
+    tokio::time::advance(Duration::from_secs(121)).await;
+    tokio::time::resume();
+    let (start, panels) = resume(&server, image.checksums.image, 1000).await;
+    assert_eq!(start, 0);
+    assert_eq!(panels, [image.left, image.right].concat());
+}
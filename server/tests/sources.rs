@@ -0,0 +1,376 @@
+//! Photo sources against local stand-ins for where photos are kept.
+
+use std::{
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use axum::{
+    Router,
+    body::Body,
+    extract::State,
+    http::{Request, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use server::{
+    config::{DirectoryConfig, FeedConfig, S3Config, WebDavConfig},
+    directory::DirectorySource,
+    feed::FeedSource,
+    immich::Immich,
+    s3::S3Source,
+    source::{PhotoSource, SourcePhoto},
+    webdav::WebDavSource,
+};
+use tokio::net::TcpListener;
+
+/// A fresh directory of photos, notes and sync leftovers.
+fn photo_directory(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("sources-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+    for (file, content) in [
+        ("a.jpg", "a"),
+        ("trips/b.PNG", "b"),
+        ("trips/notes.txt", "notes"),
+        ("trips/.syncthing.c.jpg.tmp", "c"),
+        (".stversions/a.jpg", "old a"),
+    ] {
+        let file = path.join(file);
+        std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+        std::fs::write(file, content).unwrap();
+    }
+    path
+}
+
+fn config(path: PathBuf) -> DirectoryConfig {
+    toml::from_str::<DirectoryConfig>(&format!("path = {:?}\ndebounce_secs = 0", path)).unwrap()
+}
+
+fn ids(photos: &[SourcePhoto]) -> Vec<&str> {
+    photos.iter().map(|photo| photo.id.as_str()).collect()
+}
+
+#[tokio::test]
+async fn lists_photos_in_a_directory() {
+    let path = photo_directory("list");
+    let source = DirectorySource::new(&config(path.clone())).unwrap();
+
+    let photos = source.list().await.unwrap();
+    assert_eq!(ids(&photos), ["a.jpg", "trips/b.PNG"]);
+    assert_eq!(source.fetch("trips/b.PNG").await.unwrap(), b"b");
+    assert!(source.fetch("../list/a.jpg").await.is_err());
+
+    // Only the rewritten photo gets a new fingerprint
+    std::fs::write(path.join("a.jpg"), "a, edited").unwrap();
+    let relisted = source.list().await.unwrap();
+    assert_ne!(relisted[0].version, photos[0].version);
+    assert_eq!(relisted[1].version, photos[1].version);
+
+    let mut narrowed = config(path.clone());
+    narrowed.include = vec!["trips/**/*.png".to_string()];
+    let source = DirectorySource::new(&narrowed).unwrap();
+    assert_eq!(ids(&source.list().await.unwrap()), ["trips/b.PNG"]);
+    std::fs::remove_dir_all(path).unwrap();
+}
+
+#[tokio::test]
+async fn notices_new_photos() {
+    let path = photo_directory("watch");
+    let source = DirectorySource::new(&config(path.clone())).unwrap();
+    source.list().await.unwrap();
+
+    std::fs::write(path.join("trips/c.jpg"), "c").unwrap();
+    tokio::time::timeout(Duration::from_secs(5), source.changed())
+        .await
+        .expect("the new photo went unnoticed");
+    assert_eq!(
+        ids(&source.list().await.unwrap()),
+        ["a.jpg", "trips/b.PNG", "trips/c.jpg"]
+    );
+    std::fs::remove_dir_all(path).unwrap();
+}
+
+/// A Nextcloud-like WebDAV server with a folder of photos for `alice:secret`.
+async fn webdav_standin(request: Request<Body>) -> Response {
+    // base64 of alice:secret
+    let authorized = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .is_some_and(|value| value == "Basic YWxpY2U6c2VjcmV0");
+    if !authorized {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    let entry = |href: &str, props: &str| {
+        format!(
+            "<d:response><d:href>{href}</d:href><d:propstat><d:prop>{props}</d:prop>\
+             <d:status>HTTP/1.1 200 OK</d:status></d:propstat><d:propstat><d:prop>\
+             <d:getcontenttype/></d:prop><d:status>HTTP/1.1 404 Not Found</d:status>\
+             </d:propstat></d:response>"
+        )
+    };
+    let folder =
+        "<d:resourcetype><d:collection/></d:resourcetype><d:getetag>&quot;f&quot;</d:getetag>";
+    let listing = match (request.method().as_str(), request.uri().path()) {
+        ("PROPFIND", "/dav/Photos/") => [
+            entry("/dav/Photos/", folder),
+            entry(
+                "/dav/Photos/a.jpg",
+                "<d:resourcetype/><d:getetag>&quot;1&quot;</d:getetag>\
+                 <d:getcontenttype>image/jpeg</d:getcontenttype>",
+            ),
+            entry(
+                "/dav/Photos/notes.txt",
+                "<d:resourcetype/><d:getetag>&quot;2&quot;</d:getetag>\
+                 <d:getcontenttype>text/plain</d:getcontenttype>",
+            ),
+            entry("http://localhost/dav/Photos/Trips/", folder),
+        ]
+        .concat(),
+        ("PROPFIND", "/dav/Photos/Trips/") => [
+            entry("/dav/Photos/Trips/", folder),
+            entry(
+                "/dav/Photos/Trips/b%20c.png",
+                "<d:resourcetype/><d:getetag>&quot;3&quot;</d:getetag>\
+                 <d:getcontenttype>image/png</d:getcontenttype>",
+            ),
+        ]
+        .concat(),
+        ("GET", "/dav/Photos/Trips/b%20c.png") => return "b c".into_response(),
+        _ => return StatusCode::NOT_FOUND.into_response(),
+    };
+    let body = format!(
+        "<?xml version=\"1.0\"?>\n<d:multistatus xmlns:d=\"DAV:\">{listing}</d:multistatus>"
+    );
+    (StatusCode::MULTI_STATUS, body).into_response()
+}
+
+#[tokio::test]
+async fn lists_photos_over_webdav() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let router = Router::new().fallback(webdav_standin);
+    tokio::spawn(async move { axum::serve(listener, router).await });
+
+    let config = |password: &str| WebDavConfig {
+        url: format!("http://{address}/dav/Photos"),
+        username: Some("alice".to_string()),
+        password: Some(password.to_string()),
+    };
+    let source = WebDavSource::new(&config("secret")).unwrap();
+    let photos = source.list().await.unwrap();
+    assert_eq!(ids(&photos), ["Trips/b c.png", "a.jpg"]);
+    assert_eq!(photos[0].version, "\"3\"");
+    assert_eq!(source.fetch("Trips/b c.png").await.unwrap(), b"b c");
+
+    let source = WebDavSource::new(&config("wrong")).unwrap();
+    assert!(source.list().await.is_err());
+}
+
+/// An S3 bucket `photos` listing two objects per page, for the key `test-key`.
+async fn s3_standin(request: Request<Body>) -> Response {
+    let signed = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            value.starts_with("AWS4-HMAC-SHA256 Credential=test-key/")
+                && value.contains("/garage/s3/aws4_request, SignedHeaders=")
+        });
+    if !signed || !request.headers().contains_key("x-amz-date") {
+        let error = "<Error><Code>AccessDenied</Code><Message>Access Denied</Message></Error>";
+        return (StatusCode::FORBIDDEN, error).into_response();
+    }
+
+    let object = |key: &str, etag: &str| {
+        format!(
+            "<Contents><Key>{key}</Key><ETag>&quot;{etag}&quot;</ETag><Size>1</Size></Contents>"
+        )
+    };
+    let page = match (request.uri().path(), request.uri().query()) {
+        ("/photos", Some("list-type=2&prefix=frame%2F")) => [
+            object("frame/a.jpg", "1"),
+            object("frame/notes.txt", "2"),
+            "<IsTruncated>true</IsTruncated>".to_string(),
+            "<NextContinuationToken>page 2</NextContinuationToken>".to_string(),
+        ]
+        .concat(),
+        ("/photos", Some("continuation-token=page%202&list-type=2&prefix=frame%2F")) => [
+            object("frame/trips/", "3"),
+            object("frame/trips/b c.png", "4"),
+            "<IsTruncated>false</IsTruncated>".to_string(),
+        ]
+        .concat(),
+        ("/photos/frame/trips/b%20c.png", None) => return "b c".into_response(),
+        _ => return StatusCode::NOT_FOUND.into_response(),
+    };
+    let body = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <ListBucketResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">{page}</ListBucketResult>"
+    );
+    body.into_response()
+}
+
+#[tokio::test]
+async fn lists_photos_in_a_bucket() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let router = Router::new().fallback(s3_standin);
+    tokio::spawn(async move { axum::serve(listener, router).await });
+
+    let config = |key: &str| {
+        toml::from_str::<S3Config>(&format!(
+            "endpoint = \"http://{address}\"\nregion = \"garage\"\nbucket = \"photos\"\n\
+             prefix = \"frame/\"\naccess_key_id = \"{key}\"\nsecret_access_key = \"secret\""
+        ))
+        .unwrap()
+    };
+    let source = S3Source::new(&config("test-key")).unwrap();
+    let photos = source.list().await.unwrap();
+    assert_eq!(ids(&photos), ["frame/a.jpg", "frame/trips/b c.png"]);
+    assert_eq!(photos[1].version, "\"4\"");
+    assert_eq!(source.fetch("frame/trips/b c.png").await.unwrap(), b"b c");
+
+    let error = S3Source::new(&config("other-key"))
+        .unwrap()
+        .list()
+        .await
+        .err()
+        .unwrap();
+    assert!(error.to_string().contains("Access Denied"), "{error}");
+}
+
+const ART_FEED: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<rss version="2.0" xmlns:media="http://search.yahoo.com/mrss/">
+  <channel>
+    <title>Art of the day</title>
+    <item><title>Irises</title><enclosure url="/art/1.jpg" type="image/jpeg" length="1"/></item>
+    <item><title>Audio guide</title><enclosure url="/guide.mp3" type="audio/mpeg"/></item>
+    <item><title>Water lilies</title><media:content url="/art/2.jpg" medium="image"/></item>
+    <item>
+      <title>Starry night</title>
+      <description>&lt;p&gt;&lt;img data-src="/lazy.jpg" src="/art/3.jpg?size=large&amp;amp;v=2"&gt;&lt;/p&gt;</description>
+    </item>
+  </channel>
+</rss>"#;
+
+/// Serves the art feed, a url list and their images with ETags, counting the
+/// images it sends in full.
+async fn feed_standin(
+    State(downloads): State<Arc<AtomicUsize>>,
+    request: Request<Body>,
+) -> Response {
+    let (body, etag): (&str, &str) = match request.uri().path() {
+        "/art.rss" => (ART_FEED, "\"feed\""),
+        "/list.txt" => (
+            "# shared by a friend\n/art/2.jpg\n\nhttp://127.0.0.1:9/gone.jpg\n",
+            "\"list\"",
+        ),
+        "/art/1.jpg" => ("one", "\"1\""),
+        "/art/2.jpg" => ("two", "\"2\""),
+        // Without an ETag
+        "/art/3.jpg" => ("three", ""),
+        _ => return StatusCode::NOT_FOUND.into_response(),
+    };
+    let unchanged = !etag.is_empty()
+        && request
+            .headers()
+            .get(header::IF_NONE_MATCH)
+            .is_some_and(|sent| sent == etag);
+    if unchanged {
+        return StatusCode::NOT_MODIFIED.into_response();
+    }
+    if request.uri().path().starts_with("/art/") {
+        downloads.fetch_add(1, Ordering::SeqCst);
+    }
+    match etag {
+        "" => body.into_response(),
+        etag => ([(header::ETAG, etag)], body).into_response(),
+    }
+}
+
+#[tokio::test]
+async fn lists_images_of_feeds() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let downloads = Arc::new(AtomicUsize::new(0));
+    let router = Router::new()
+        .fallback(feed_standin)
+        .with_state(Arc::clone(&downloads));
+    tokio::spawn(async move { axum::serve(listener, router).await });
+
+    let feed = |path: &str| FeedConfig {
+        url: format!("http://{address}{path}"),
+        limit: 50,
+    };
+    let source = FeedSource::new(&feed("/art.rss")).unwrap();
+    let photos = source.list().await.unwrap();
+    assert_eq!(
+        ids(&photos),
+        [
+            format!("http://{address}/art/1.jpg"),
+            format!("http://{address}/art/2.jpg"),
+            format!("http://{address}/art/3.jpg?size=large&v=2"),
+        ]
+    );
+    assert_eq!(photos[0].version, "\"1\"");
+    assert_eq!(downloads.load(Ordering::SeqCst), 3);
+
+    // Processing uses what listing downloaded, the next listing only asks for changes
+    assert_eq!(source.fetch(&photos[0].id).await.unwrap(), b"one");
+    let relisted = source.list().await.unwrap();
+    assert_eq!(downloads.load(Ordering::SeqCst), 4);
+    assert_eq!(relisted[0].version, photos[0].version);
+    assert_eq!(relisted[2].version, photos[2].version);
+    assert_eq!(source.fetch(&photos[0].id).await.unwrap(), b"one");
+    assert_eq!(downloads.load(Ordering::SeqCst), 5);
+
+    let mut list = feed("/list.txt");
+    list.limit = 1;
+    let source = FeedSource::new(&list).unwrap();
+    assert_eq!(
+        ids(&source.list().await.unwrap()),
+        [format!("http://{address}/art/2.jpg")]
+    );
+}
+
+#[tokio::test]
+async fn fails_on_error_pages() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    // An Immich that doesn't take the key, and a feed whose images are gone
+    let router = Router::new().fallback(|request: Request<Body>| async move {
+        match request.uri().path() {
+            "/art.rss" => ART_FEED.into_response(),
+            "/art/1.jpg" => (StatusCode::NOT_FOUND, "<html>gone</html>").into_response(),
+            _ => (
+                StatusCode::UNAUTHORIZED,
+                "{\"message\":\"Invalid API key\"}",
+            )
+                .into_response(),
+        }
+    });
+    tokio::spawn(async move { axum::serve(listener, router).await });
+
+    let immich = Immich::new(format!("http://{address}"), "wrong key".to_string());
+    let Err(error) = immich.get_assets(uuid::Uuid::nil()).await else {
+        panic!("listed an album it couldn't read");
+    };
+    assert!(error.to_string().contains("401"), "{error}");
+    let error = immich.get_asset("a").await.unwrap_err();
+    assert!(error.to_string().contains("401"), "{error}");
+
+    let source = FeedSource::new(&FeedConfig {
+        url: format!("http://{address}/art.rss"),
+        limit: 50,
+    })
+    .unwrap();
+    let error = source
+        .fetch(&format!("http://{address}/art/1.jpg"))
+        .await
+        .unwrap_err();
+    assert!(error.to_string().contains("404"), "{error}");
+}